@@ -0,0 +1,49 @@
+#!/usr/bin/env python3
+"""Opens many idle client connections and reports the resource usage of the server.
+
+Usage: connections.py PID [COUNT] [HOST] [PORT]
+
+PID is the process id of the running server. Every client registers with
+NICK/USER and then stays idle. Memory and thread counts are read from
+/proc/PID/status before and after the clients connected.
+"""
+import resource
+import socket
+import sys
+import time
+
+
+def status(pid):
+    fields = {}
+    with open("/proc/%d/status" % pid) as f:
+        for line in f:
+            key, _, value = line.partition(":")
+            fields[key] = value.strip()
+    return fields["VmRSS"], fields["Threads"]
+
+
+def main():
+    pid = int(sys.argv[1])
+    count = int(sys.argv[2]) if len(sys.argv) > 2 else 10000
+    host = sys.argv[3] if len(sys.argv) > 3 else "127.0.0.1"
+    port = int(sys.argv[4]) if len(sys.argv) > 4 else 6667
+    _, hard = resource.getrlimit(resource.RLIMIT_NOFILE)
+    resource.setrlimit(resource.RLIMIT_NOFILE, (hard, hard))
+
+    print("before: rss %s, threads %s" % status(pid))
+    clients = []
+    start = time.time()
+    for i in range(count):
+        client = socket.create_connection((host, port))
+        client.sendall(b"NICK bench%d\r\nUSER bench 0 * :bench\r\n" % i)
+        clients.append(client)
+    print("connected %d clients in %.1f s" % (count, time.time() - start))
+    # Give the server some time to process the registrations
+    time.sleep(5)
+    print("after: rss %s, threads %s" % status(pid))
+    for client in clients:
+        client.close()
+
+
+if __name__ == "__main__":
+    main()
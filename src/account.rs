@@ -0,0 +1,665 @@
+//! User accounts
+//!
+//! Accounts are provided by an `AccountBackend`. The only backend so far
+//! reads a file with one account per line:
+//!
+//! ```text
+//! # name:scram-sha-256:iterations:salt:stored key:server key[:fingerprints]
+//! alice:scram-sha-256:4096:<salt>:<stored key>:<server key>
+//! # name:sha256:salt:hash[:fingerprints]
+//! bob:sha256:<salt>:<hash>
+//! ```
+//!
+//! `scram-sha-256` entries hold the keys of RFC 5802 derived with
+//! PBKDF2-HMAC-SHA-256, they can be used with PLAIN and SCRAM-SHA-256.
+//! Older `sha256` entries hold the SHA-256 of the salt followed by the
+//! password and only work with PLAIN. All binary values are hex encoded.
+//! `chätd --hash-password <password>` prints new SCRAM credentials.
+//! The optional next field holds the comma separated SHA-256 fingerprints
+//! of the TLS client certificates of the account. The last optional field
+//! holds the comma separated nicks grouped under the account, which are
+//! owned by the account like its name.
+//!
+//! The file is rewritten when the fingerprints or nicks change or an
+//! account is registered, comments are lost.
+
+use std::collections::{HashMap};
+use std::io::{File, BufferedReader, IoResult, IoError, InvalidInput};
+use std::io::fs;
+use std::rand::{task_rng, Rng};
+
+use openssl::crypto::hash::{hash, HashType};
+use openssl::crypto::hmac::{HMAC};
+use serialize::hex::{ToHex, FromHex};
+
+use util;
+use util::{CaseMapping, NameKey};
+use self::Credentials::*;
+
+/// Length of newly generated salts in bytes
+static SALT_LENGTH: uint = 16;
+/// PBKDF2 iterations of newly generated SCRAM credentials
+pub static DEFAULT_ITERATIONS: uint = 4096;
+
+/// Source of account information
+pub trait AccountBackend {
+    /// Checks the password of an account
+    ///
+    /// Returns the name of the account if the password is correct.
+    fn check_password(&self, name: &str, password: &[u8]) -> Option<String>;
+    /// Returns the name and the SCRAM credentials of an account
+    ///
+    /// Accounts with older credentials cannot use SCRAM.
+    fn scram_credentials(&self, name: &str) -> Option<(String, ScramCredentials)>;
+    /// Returns the name of an account as it was registered
+    ///
+    /// Returns `None` if the account does not exist.
+    fn account_name(&self, name: &str) -> Option<String>;
+    /// Creates a new account
+    ///
+    /// Returns false if the account already exists.
+    fn register(&mut self, name: &str, credentials: ScramCredentials) -> IoResult<bool>;
+    /// Returns the name of the account which owns a nick
+    ///
+    /// Accounts own their name and the nicks grouped under them.
+    fn nick_owner(&self, nick: &str) -> Option<String>;
+    /// Nicks grouped under an account
+    fn nicks(&self, name: &str) -> Vec<String>;
+    /// Groups a nick under an account
+    ///
+    /// Returns false if the nick is already owned by an account.
+    fn group_nick(&mut self, name: &str, nick: &str) -> IoResult<bool>;
+    /// Removes a nick from the group of an account
+    ///
+    /// Returns false if the nick is not grouped under the account.
+    fn ungroup_nick(&mut self, name: &str, nick: &str) -> IoResult<bool>;
+    /// Returns the name of the account a certificate fingerprint belongs to
+    fn find_fingerprint(&self, fingerprint: &str) -> Option<String>;
+    /// Certificate fingerprints of an account
+    fn fingerprints(&self, name: &str) -> Vec<String>;
+    /// Adds a certificate fingerprint to an account
+    ///
+    /// Returns false if the fingerprint already belongs to an account.
+    fn add_fingerprint(&mut self, name: &str, fingerprint: &str) -> IoResult<bool>;
+    /// Removes a certificate fingerprint from an account
+    ///
+    /// Returns false if the account does not have the fingerprint.
+    fn remove_fingerprint(&mut self, name: &str, fingerprint: &str) -> IoResult<bool>;
+}
+
+/// Normalizes a SHA-256 certificate fingerprint to lower case hex
+///
+/// Colons are removed, returns `None` if it is not a valid fingerprint.
+pub fn normalize_fingerprint(fingerprint: &str) -> Option<String> {
+    let hex: String = fingerprint.chars().filter(|&c| c != ':').map(
+        |c| c.to_lowercase()
+    ).collect();
+    if hex.len() == 64 && hex.as_slice().chars().all(|c| c.is_digit_radix(16)) {
+        Some(hex)
+    } else {
+        None
+    }
+}
+
+/// SHA-256 of `data`
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    hash(HashType::SHA256, data)
+}
+
+/// HMAC-SHA-256 of `data`
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hmac = HMAC(HashType::SHA256, key);
+    hmac.update(data);
+    hmac.finalize()
+}
+
+/// First block of PBKDF2-HMAC-SHA-256, the `Hi` function of RFC 5802
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: uint) -> Vec<u8> {
+    let mut block = hmac_sha256(password, (salt.to_vec() + &[0u8, 0, 0, 1]).as_slice());
+    let mut result = block.clone();
+    for _ in range(1, iterations) {
+        block = hmac_sha256(password, block.as_slice());
+        for (r, &b) in result.iter_mut().zip(block.iter()) {
+            *r ^= b
+        }
+    }
+    result
+}
+
+/// Compares two byte strings in time which only depends on their length
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (&x, &y)| acc | (x ^ y)) == 0
+}
+
+fn random_salt() -> Vec<u8> {
+    let mut salt = Vec::from_elem(SALT_LENGTH, 0u8);
+    task_rng().fill_bytes(salt.as_mut_slice());
+    salt
+}
+
+/// Keys of an account for SCRAM-SHA-256 (RFC 5802, RFC 7677)
+///
+/// The password itself cannot be recovered from the keys.
+#[deriving(Clone)]
+pub struct ScramCredentials {
+    pub iterations: uint,
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>
+}
+
+impl ScramCredentials {
+    /// Derives the keys from a password with a new random salt
+    pub fn new(password: &[u8]) -> ScramCredentials {
+        ScramCredentials::derive(password, random_salt().as_slice(), DEFAULT_ITERATIONS)
+    }
+
+    /// Credentials offered to SCRAM clients for names without SCRAM keys
+    ///
+    /// The salt is derived from the case folded name and `secret`, such that
+    /// it is the same on every attempt as for an existing account. No proof
+    /// matches.
+    pub fn fake(name: &NameKey, secret: &[u8]) -> ScramCredentials {
+        let mut salt = hmac_sha256(secret, name.as_str().as_bytes());
+        salt.truncate(SALT_LENGTH);
+        ScramCredentials {
+            iterations: DEFAULT_ITERATIONS,
+            salt: salt,
+            stored_key: Vec::new(),
+            server_key: Vec::new()
+        }
+    }
+
+    /// Derives the keys from a password
+    pub fn derive(password: &[u8], salt: &[u8], iterations: uint) -> ScramCredentials {
+        let salted_password = pbkdf2_sha256(password, salt, iterations);
+        let client_key = hmac_sha256(salted_password.as_slice(), b"Client Key");
+        ScramCredentials {
+            iterations: iterations,
+            salt: salt.to_vec(),
+            stored_key: sha256(client_key.as_slice()),
+            server_key: hmac_sha256(salted_password.as_slice(), b"Server Key")
+        }
+    }
+
+    fn matches(&self, password: &[u8]) -> bool {
+        let derived = ScramCredentials::derive(password, self.salt.as_slice(), self.iterations);
+        constant_time_eq(derived.stored_key.as_slice(), self.stored_key.as_slice())
+    }
+}
+
+/// Stored password
+#[deriving(Clone)]
+enum Credentials {
+    /// Salt and SHA-256 of the salt followed by the password
+    Salted(Vec<u8>, Vec<u8>),
+    Scram(ScramCredentials)
+}
+
+impl Credentials {
+    /// Derives SCRAM credentials with a new random salt
+    fn new(password: &[u8]) -> Credentials {
+        Scram(ScramCredentials::new(password))
+    }
+
+    fn matches(&self, password: &[u8]) -> bool {
+        match *self {
+            Salted(ref salt, ref hash) => constant_time_eq(
+                sha256((salt.to_vec() + password).as_slice()).as_slice(), hash.as_slice()
+            ),
+            Scram(ref scram) => scram.matches(password)
+        }
+    }
+
+    /// Formats the credentials like they are stored in the account file
+    fn to_string(&self) -> String {
+        match *self {
+            Salted(ref salt, ref hash) => format!("sha256:{}:{}", salt.to_hex(), hash.to_hex()),
+            Scram(ref scram) => format!("scram-sha-256:{}:{}:{}:{}",
+                scram.iterations, scram.salt.to_hex(),
+                scram.stored_key.to_hex(), scram.server_key.to_hex()
+            )
+        }
+    }
+}
+
+/// Hashes a password for the account file
+pub fn hash_password(password: &str) -> String {
+    Credentials::new(password.as_bytes()).to_string()
+}
+
+/// Account stored in the account file
+#[deriving(Clone)]
+struct Account {
+    name: String,
+    credentials: Credentials,
+    /// Fingerprints of the client certificates
+    fingerprints: Vec<String>,
+    /// Grouped nicks
+    nicks: Vec<String>
+}
+
+impl Account {
+    /// Formats the account like it is stored in the account file
+    fn to_string(&self) -> String {
+        let mut line = format!("{}:{}", self.name, self.credentials.to_string());
+        if !self.fingerprints.is_empty() || !self.nicks.is_empty() {
+            line.push(':');
+            line.push_str(self.fingerprints.connect(",").as_slice())
+        }
+        if !self.nicks.is_empty() {
+            line.push(':');
+            line.push_str(self.nicks.connect(",").as_slice())
+        }
+        line
+    }
+}
+
+/// Accounts read from a local file
+pub struct FileBackend {
+    path: Path,
+    casemapping: CaseMapping,
+    /// Accounts by the key of their name
+    accounts: HashMap<NameKey, Account>
+}
+
+impl FileBackend {
+    /// Loads the accounts stored in the file at `path`
+    ///
+    /// Account names and grouped nicks are compared like nicks under
+    /// `casemapping`.
+    pub fn load(path: &Path, casemapping: CaseMapping) -> IoResult<FileBackend> {
+        let mut file = BufferedReader::new(try!(File::open(path)));
+        let mut accounts = HashMap::new();
+        for (i, line) in file.lines().enumerate() {
+            let line = try!(line);
+            let line = line.as_slice().trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue
+            }
+            let account = match parse_account(line) {
+                Some(account) => account,
+                None => return Err(IoError {
+                    kind: InvalidInput,
+                    desc: "invalid account entry",
+                    detail: Some(format!("line {}", i + 1))
+                })
+            };
+            accounts.insert(casemapping.key(account.name.as_slice()), account);
+        }
+        Ok(FileBackend {
+            path: path.clone(),
+            casemapping: casemapping,
+            accounts: accounts
+        })
+    }
+
+    /// Path of the account file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the accounts back to the file
+    ///
+    /// A temporary file is renamed such that the file is never half written.
+    fn save(&self) -> IoResult<()> {
+        let mut accounts: Vec<&Account> = self.accounts.values().collect();
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            for account in accounts.iter() {
+                try!(file.write_line(account.to_string().as_slice()));
+            }
+            try!(file.fsync());
+        }
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl AccountBackend for FileBackend {
+    fn check_password(&self, name: &str, password: &[u8]) -> Option<String> {
+        match self.accounts.get(&self.casemapping.key(name)) {
+            Some(account) if account.credentials.matches(password) => Some(account.name.clone()),
+            _ => None
+        }
+    }
+
+    fn scram_credentials(&self, name: &str) -> Option<(String, ScramCredentials)> {
+        match self.accounts.get(&self.casemapping.key(name)) {
+            Some(&Account { ref name, credentials: Scram(ref scram), .. }) =>
+                Some((name.clone(), scram.clone())),
+            _ => None
+        }
+    }
+
+    fn account_name(&self, name: &str) -> Option<String> {
+        self.accounts.get(&self.casemapping.key(name)).map(|account| account.name.clone())
+    }
+
+    fn register(&mut self, name: &str, credentials: ScramCredentials) -> IoResult<bool> {
+        let key = self.casemapping.key(name);
+        if self.nick_owner(name).is_some() {
+            return Ok(false)
+        }
+        self.accounts.insert(key.clone(), Account {
+            name: name.to_string(),
+            credentials: Scram(credentials),
+            fingerprints: Vec::new(),
+            nicks: Vec::new()
+        });
+        match self.save() {
+            Ok(()) => Ok(true),
+            Err(err) => {
+                self.accounts.remove(&key);
+                Err(err)
+            }
+        }
+    }
+
+    fn nick_owner(&self, nick: &str) -> Option<String> {
+        let key = self.casemapping.key(nick);
+        match self.accounts.get(&key) {
+            Some(account) => Some(account.name.clone()),
+            None => self.accounts.values().find(|account| account.nicks.iter().any(
+                |v| self.casemapping.key(v.as_slice()) == key
+            )).map(|account| account.name.clone())
+        }
+    }
+
+    fn nicks(&self, name: &str) -> Vec<String> {
+        self.accounts.get(&self.casemapping.key(name)).map_or(
+            Vec::new(), |account| account.nicks.clone()
+        )
+    }
+
+    fn group_nick(&mut self, name: &str, nick: &str) -> IoResult<bool> {
+        if self.nick_owner(nick).is_some() {
+            return Ok(false)
+        }
+        let key = self.casemapping.key(name);
+        match self.accounts.get_mut(&key) {
+            Some(account) => account.nicks.push(nick.to_string()),
+            None => return Ok(false)
+        }
+        match self.save() {
+            Ok(()) => Ok(true),
+            Err(err) => {
+                if let Some(account) = self.accounts.get_mut(&key) {
+                    account.nicks.pop();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn ungroup_nick(&mut self, name: &str, nick: &str) -> IoResult<bool> {
+        let (casemapping, key) = (self.casemapping.clone(), self.casemapping.key(nick));
+        let account_key = casemapping.key(name);
+        let (pos, grouped) = match self.accounts.get_mut(&account_key) {
+            Some(account) => match account.nicks.iter().position(
+                |v| casemapping.key(v.as_slice()) == key
+            ) {
+                Some(pos) => (pos, account.nicks.remove(pos).unwrap()),
+                None => return Ok(false)
+            },
+            None => return Ok(false)
+        };
+        match self.save() {
+            Ok(()) => Ok(true),
+            Err(err) => {
+                if let Some(account) = self.accounts.get_mut(&account_key) {
+                    account.nicks.insert(pos, grouped)
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn find_fingerprint(&self, fingerprint: &str) -> Option<String> {
+        self.accounts.values().find(
+            |account| account.fingerprints.iter().any(|v| v.as_slice() == fingerprint)
+        ).map(|account| account.name.clone())
+    }
+
+    fn fingerprints(&self, name: &str) -> Vec<String> {
+        self.accounts.get(&self.casemapping.key(name)).map_or(
+            Vec::new(), |account| account.fingerprints.clone()
+        )
+    }
+
+    fn add_fingerprint(&mut self, name: &str, fingerprint: &str) -> IoResult<bool> {
+        if self.find_fingerprint(fingerprint).is_some() {
+            return Ok(false)
+        }
+        let key = self.casemapping.key(name);
+        match self.accounts.get_mut(&key) {
+            Some(account) => account.fingerprints.push(fingerprint.to_string()),
+            None => return Ok(false)
+        }
+        match self.save() {
+            Ok(()) => Ok(true),
+            Err(err) => {
+                if let Some(account) = self.accounts.get_mut(&key) {
+                    account.fingerprints.pop();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn remove_fingerprint(&mut self, name: &str, fingerprint: &str) -> IoResult<bool> {
+        let key = self.casemapping.key(name);
+        let pos = match self.accounts.get_mut(&key) {
+            Some(account) => match account.fingerprints.iter().position(
+                |v| v.as_slice() == fingerprint
+            ) {
+                Some(pos) => { account.fingerprints.remove(pos); pos },
+                None => return Ok(false)
+            },
+            None => return Ok(false)
+        };
+        match self.save() {
+            Ok(()) => Ok(true),
+            Err(err) => {
+                if let Some(account) = self.accounts.get_mut(&key) {
+                    account.fingerprints.insert(pos, fingerprint.to_string())
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Parses a line of the account file
+fn parse_account(line: &str) -> Option<Account> {
+    let fields: Vec<&str> = line.split(':').collect();
+    // Number of fields up to the credentials
+    let length = match fields.as_slice().get(1) {
+        Some(&"sha256") => 4,
+        Some(&"scram-sha-256") => 6,
+        _ => return None
+    };
+    if fields.len() < length || fields.len() > length + 2 {
+        return None
+    }
+    let credentials = match fields.slice_to(length) {
+        [_, _, salt, hash] => parse_salted(salt, hash),
+        [_, _, iterations, salt, stored_key, server_key] =>
+            parse_scram(iterations, salt, stored_key, server_key),
+        _ => None
+    };
+    let mut fingerprints = Vec::new();
+    for fingerprint in fields.as_slice().get(length).map_or("", |v| *v).split(',').filter(|v| !v.is_empty()) {
+        match normalize_fingerprint(fingerprint) {
+            Some(fingerprint) => fingerprints.push(fingerprint),
+            None => return None
+        }
+    }
+    let mut nicks = Vec::new();
+    for nick in fields.as_slice().get(length + 1).map_or("", |v| *v).split(',').filter(|v| !v.is_empty()) {
+        if !util::valid_nick_len(nick, util::MAX_NICK_LENGTH) {
+            return None
+        }
+        nicks.push(nick.to_string())
+    }
+    let name = fields[0];
+    match credentials {
+        Some(credentials) if !name.is_empty() => Some(Account {
+            name: name.to_string(),
+            credentials: credentials,
+            fingerprints: fingerprints,
+            nicks: nicks
+        }),
+        _ => None
+    }
+}
+
+fn parse_salted(salt: &str, hash: &str) -> Option<Credentials> {
+    match (salt.from_hex(), hash.from_hex()) {
+        (Ok(salt), Ok(hash)) => Some(Salted(salt, hash)),
+        _ => None
+    }
+}
+
+fn parse_scram(iterations: &str, salt: &str, stored_key: &str,
+               server_key: &str) -> Option<Credentials> {
+    match (from_str::<uint>(iterations), salt.from_hex(),
+           stored_key.from_hex(), server_key.from_hex()) {
+        (Some(iterations), Ok(salt), Ok(stored_key), Ok(server_key)) if iterations > 0 => {
+            Some(Scram(ScramCredentials {
+                iterations: iterations,
+                salt: salt,
+                stored_key: stored_key,
+                server_key: server_key
+            }))
+        },
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Credentials, Scram, parse_account, hash_password, normalize_fingerprint};
+	use super::{pbkdf2_sha256, AccountBackend, FileBackend, ScramCredentials};
+	use serialize::hex::{ToHex};
+	use std::io::{File, TempDir, USER_RWX};
+	use std::io::fs;
+	use util::{Rfc1459};
+
+	fn salt(credentials: &Credentials) -> Vec<u8> {
+		match *credentials {
+			Scram(ref scram) => scram.salt.clone(),
+			_ => panic!("new credentials should be SCRAM credentials")
+		}
+	}
+
+	/// Test hashing and checking of passwords
+	#[test]
+	fn test_credentials() {
+		let credentials = Credentials::new(b"secret");
+		assert!(credentials.matches(b"secret"))
+		assert!(!credentials.matches(b"Secret"))
+		assert!(salt(&Credentials::new(b"secret")) != salt(&credentials))
+		let line = format!("alice:{}", hash_password("pw"));
+		let account = parse_account(line.as_slice()).unwrap();
+		assert_eq!(account.name.as_slice(), "alice")
+		assert!(account.credentials.matches(b"pw"))
+		assert_eq!(account.to_string(), line)
+		assert!(parse_account("alice:md5:00:00").is_none())
+		assert!(parse_account("alice:sha256:xy:00").is_none())
+		assert!(parse_account("alice:scram-sha-256:0:00:00:00").is_none())
+		assert!(parse_account("alice:scram-sha-256:1:00:00").is_none())
+	}
+	/// Test PBKDF2 against known vectors
+	#[test]
+	fn test_pbkdf2() {
+		assert_eq!(pbkdf2_sha256(b"password", b"salt", 1).to_hex().as_slice(),
+			"120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b")
+		assert_eq!(pbkdf2_sha256(b"password", b"salt", 2).to_hex().as_slice(),
+			"ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43")
+	}
+	/// Test the fingerprint field
+	#[test]
+	fn test_fingerprints() {
+		let fingerprint = String::from_char(64, 'a');
+		let upper = String::from_char(32, 'A') + ":" + String::from_char(32, 'A').as_slice();
+		assert_eq!(normalize_fingerprint(upper.as_slice()), Some(fingerprint.clone()))
+		assert_eq!(normalize_fingerprint("abc"), None)
+		let line = format!("alice:sha256:00:00:{},{}", fingerprint, fingerprint);
+		let account = parse_account(line.as_slice()).unwrap();
+		assert_eq!(account.fingerprints.len(), 2)
+		assert!(parse_account("alice:sha256:00:00:abc").is_none())
+		let account = parse_account("alice:sha256:00:00").unwrap();
+		assert_eq!(account.to_string().as_slice(), "alice:sha256:00:00")
+	}
+	/// Test the field of the grouped nicks
+	#[test]
+	fn test_nicks() {
+		let account = parse_account("alice:sha256:00:00::Ally,al").unwrap();
+		assert_eq!(account.fingerprints.len(), 0)
+		assert_eq!(account.nicks, vec!["Ally".to_string(), "al".to_string()])
+		assert_eq!(account.to_string().as_slice(), "alice:sha256:00:00::Ally,al")
+		assert!(parse_account("alice:sha256:00:00::1ally").is_none())
+		assert!(parse_account("alice:sha256:00:00::ally:").is_none())
+	}
+	/// Test that registered accounts are saved
+	#[test]
+	fn test_register() {
+		let dir = TempDir::new("chatd").unwrap();
+		let path = dir.path().join("accounts.txt");
+		File::create(&path).unwrap();
+		let mut backend = FileBackend::load(&path, Rfc1459).unwrap();
+		assert_eq!(backend.account_name("alice"), None)
+		assert!(backend.register("Alice", ScramCredentials::new(b"secret")).unwrap())
+		assert!(!backend.register("alice", ScramCredentials::new(b"other")).unwrap())
+		let backend = FileBackend::load(&path, Rfc1459).unwrap();
+		assert_eq!(backend.account_name("alice"), Some("Alice".to_string()))
+		assert_eq!(backend.check_password("alice", b"secret"), Some("Alice".to_string()))
+		assert!(backend.scram_credentials("ALICE").is_some())
+		let mut backend = backend;
+		assert!(backend.group_nick("alice", "Ally").unwrap())
+		assert!(!backend.group_nick("alice", "ally").unwrap())
+		assert!(!backend.register("ally", ScramCredentials::new(b"secret")).unwrap())
+		let mut backend = FileBackend::load(&path, Rfc1459).unwrap();
+		assert_eq!(backend.nick_owner("ALLY"), Some("Alice".to_string()))
+		assert_eq!(backend.nick_owner("alice"), Some("Alice".to_string()))
+		assert!(backend.ungroup_nick("alice", "ally").unwrap())
+		assert_eq!(backend.nick_owner("ally"), None)
+	}
+	/// Test that names are compared with the case mapping
+	#[test]
+	fn test_casemapping() {
+		let dir = TempDir::new("chatd").unwrap();
+		let path = dir.path().join("accounts.txt");
+		File::create(&path).unwrap();
+		let mut backend = FileBackend::load(&path, Rfc1459).unwrap();
+		assert!(backend.register("alice{", ScramCredentials::new(b"secret")).unwrap())
+		assert!(!backend.register("ALICE[", ScramCredentials::new(b"secret")).unwrap())
+		assert!(backend.group_nick("ALICE[", "al|ce").unwrap())
+		assert_eq!(backend.nick_owner("AL\\CE"), Some("alice{".to_string()))
+		assert!(backend.ungroup_nick("alice{", "AL\\CE").unwrap())
+		assert_eq!(backend.nick_owner("al|ce"), None)
+	}
+	/// Test that changes are undone if the file cannot be written
+	#[test]
+	fn test_failed_save() {
+		let dir = TempDir::new("chatd").unwrap();
+		let path = dir.path().join("accounts.txt");
+		File::create(&path).unwrap();
+		let mut backend = FileBackend::load(&path, Rfc1459).unwrap();
+		assert!(backend.register("alice", ScramCredentials::new(b"secret")).unwrap())
+		assert!(backend.add_fingerprint("alice", "ab").unwrap())
+		assert!(backend.group_nick("alice", "Ally").unwrap())
+		// The temporary file cannot be created
+		fs::mkdir(&path.with_extension("tmp"), USER_RWX).unwrap();
+		assert!(backend.add_fingerprint("alice", "cd").is_err())
+		assert!(backend.remove_fingerprint("alice", "ab").is_err())
+		assert_eq!(backend.fingerprints("alice"), vec!["ab".to_string()])
+		assert_eq!(backend.find_fingerprint("cd"), None)
+		assert!(backend.group_nick("alice", "alison").is_err())
+		assert!(backend.ungroup_nick("alice", "ally").is_err())
+		assert_eq!(backend.nicks("alice"), vec!["Ally".to_string()])
+		assert_eq!(backend.nick_owner("alison"), None)
+	}
+}
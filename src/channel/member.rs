@@ -74,14 +74,15 @@ impl Member {
     }
     
     /// Get flag as string
+    ///
+    /// Returns the prefix of the highest ranked privilege.
     pub fn decoration(&self) -> String {
-        if self.has_privilege(OperatorPrivilege) {
-            "@".to_string()
-        } else if self.has_privilege(VoicePrivilege) {
-            "+".to_string()
-        } else {
-            "".to_string()
+        for mode in [OperatorPrivilege, VoicePrivilege].iter() {
+            if self.has_privilege(*mode) {
+                return mode.prefix().map_or("".to_string(), |p| p.to_string())
+            }
         }
+        "".to_string()
     }
     
     /// Checks whether a member is the operator of the channel
@@ -140,6 +141,11 @@ impl Member {
     /// Setter for nick
     pub fn set_nick(&mut self, nick: String) {
         self.nick = nick;
+        self.mask = HostMask::from_parts(
+            self.nick.as_slice(),
+            self.username.as_slice(),
+            self.hostname.as_slice()
+        );
         self.update_decorated_nick()
     }
     
@@ -1,46 +1,41 @@
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map;
+use std::cell::{RefCell};
 
 use msg::{RawMessage};
-use util::{HostMask};
+use util::{HostMask, CaseMapping, NameKey};
 
 use con::{PeerId, Peer};
+use config::{Config};
 use cmd;
-use server;
 
 pub use self::Event::*;
 
 pub use self::member::{Member};
 pub use self::util::{Flags, ChannelMode, modes_do};
+pub use self::registry::{ChannelState};
 
 pub mod util;
+pub mod registry;
 mod member;
 
 
-/// Forwards the message to a channel
+/// Forwards events to a channel
+///
+/// Channels live in the server thread, events are handled immediately.
 pub struct Proxy {
-    name: String,
-    tx: Sender<Event>,
-    server_tx: Sender<server::Event>
+    channel: RefCell<Channel>
 }
 
 impl Proxy {
-    fn new(name: String,
-           tx: Sender<Event>, 
-           server_tx: Sender<server::Event>) -> Proxy {
+    /// Wraps a channel
+    pub fn new(channel: Channel) -> Proxy {
         Proxy {
-            name: name,
-            tx: tx,
-            server_tx: server_tx
+            channel: RefCell::new(channel)
         }
     }
     pub fn send(&self, event: Event) {
-        match self.tx.send_opt(event) {
-            Ok(_) => {},
-            Err(_) => {
-                let _ = self.server_tx.send_opt(server::ChannelLost(self.name.clone()));
-            }
-        }
+        self.channel.borrow_mut().dispatch(event)
     }
 }
 
@@ -54,27 +49,34 @@ pub enum Event {
 /// Represents an IRC channel
 pub struct Channel {
     name: String,
+    casemapping: CaseMapping,
     server_name: String,
     topic: Vec<u8>,
     password: Option<Vec<u8>>,
     flags: Flags,
     limit: Option<uint>,
-    members: HashMap<String, Member>,
-    nicknames: HashMap<PeerId, String>,
+    topic_length: uint,
+    list_entries: uint,
+    members: HashMap<NameKey, Member>,
+    nicknames: HashMap<PeerId, NameKey>,
     ban_masks: HashSet<HostMask>,
     except_masks: HashSet<HostMask>,
     invite_masks: HashSet<HostMask>,
 }
 
 impl Channel {
-    pub fn new(name: String, server_name: String) -> Channel {
+    /// Creates a new channel with the default modes of the configuration
+    pub fn new(name: String, config: &Config) -> Channel {
         Channel {
             name: name,
-            server_name: server_name,
+            casemapping: config.server.casemapping,
+            server_name: config.server.name.clone(),
             topic: b"".to_vec(),
             password: None,
-            flags: HashSet::new(),
+            flags: config.channels.default_modes.iter().map(|v| *v).collect(),
             limit: None,
+            topic_length: config.limits.topic_length,
+            list_entries: config.limits.list_entries,
             members: HashMap::new(),
             nicknames: HashMap::new(),
             ban_masks: HashSet::new(),
@@ -83,19 +85,6 @@ impl Channel {
         }
     }
     
-    /// Starts listening for events in a separate thread
-    pub fn listen(self, server_tx: Sender<server::Event>) -> Proxy {
-        let (tx, rx) = channel();
-        let name = self.name.clone();
-        spawn(proc() {
-            let mut this = self;
-            for event in rx.iter() {
-                this.dispatch(event)
-            }
-        });
-        Proxy::new(name, tx, server_tx)
-    }
-
     /// Message dispatcher
     fn dispatch(&mut self, event: Event) {
         match event {
@@ -114,6 +103,11 @@ impl Channel {
         self.name.as_slice()
     }
     
+    /// Returns the case folded channel name
+    pub fn key(&self) -> NameKey {
+        self.casemapping.key(self.name.as_slice())
+    }
+    
     /// Getter for server name
     pub fn server_name(&self) -> &str {
         self.server_name.as_slice()
@@ -125,10 +119,18 @@ impl Channel {
     }
     
     /// Setter for topic
-    pub fn set_topic(&mut self, topic: Vec<u8>) {
+    ///
+    /// Topics longer than the configured maximum are truncated.
+    pub fn set_topic(&mut self, mut topic: Vec<u8>) {
+        topic.truncate(self.topic_length);
         self.topic = topic
     }
     
+    /// Getter for the maximum topic length
+    pub fn topic_length(&self) -> uint {
+        self.topic_length
+    }
+    
     /// Getter for the user limit
     pub fn limit(&self) -> Option<uint> {
         self.limit
@@ -153,7 +155,7 @@ impl Channel {
     }
     
     /// Returns a view into the channel members
-    pub fn members<'a>(&'a self) -> hash_map::Values<'a, String, Member> {
+    pub fn members<'a>(&'a self) -> hash_map::Values<'a, NameKey, Member> {
         self.members.values()
     }
     
@@ -177,6 +179,48 @@ impl Channel {
         self.flags.iter().map( |c| *c as u8 as char).collect() 
     }
     
+    /// Topic, modes and masks which are stored for registered channels
+    pub fn state(&self) -> ChannelState {
+        fn sorted(masks: &HashSet<HostMask>) -> Vec<String> {
+            let mut masks: Vec<String> = masks.iter().map(|v| v.as_str().to_string()).collect();
+            masks.sort();
+            masks
+        }
+        let mut modes: Vec<u8> = self.flags.iter().map(|c| *c as u8).collect();
+        modes.sort();
+        ChannelState {
+            topic: self.topic.clone(),
+            modes: String::from_utf8(modes).unwrap(),
+            key: self.password.clone(),
+            limit: self.limit,
+            ban_masks: sorted(&self.ban_masks),
+            except_masks: sorted(&self.except_masks),
+            invite_masks: sorted(&self.invite_masks)
+        }
+    }
+    
+    /// Restores the stored state of a registered channel
+    pub fn restore(&mut self, state: &ChannelState) {
+        self.set_topic(state.topic.clone());
+        self.flags = state.modes.as_slice().chars().filter_map(|c| {
+            let mode: Option<ChannelMode> = if (c as u32) < 128 {
+                FromPrimitive::from_u8(c as u8)
+            } else { None };
+            mode.and_then(|mode| if mode.class() == util::FlagMode { Some(mode) } else { None })
+        }).collect();
+        self.password = state.key.clone();
+        self.limit = state.limit;
+        self.ban_masks = state.ban_masks.iter().map(|v| HostMask::new(v.clone())).collect();
+        self.except_masks = state.except_masks.iter().map(|v| HostMask::new(v.clone())).collect();
+        self.invite_masks = state.invite_masks.iter().map(|v| HostMask::new(v.clone())).collect();
+    }
+    
+    /// Checks if the ban, exception and invitation lists can take another entry
+    pub fn lists_full(&self) -> bool {
+        self.ban_masks.len() + self.except_masks.len() + self.invite_masks.len()
+        >= self.list_entries
+    }
+    
     /// Adds a ban mask to the channel
     pub fn add_ban_mask(&mut self, mask: HostMask) -> bool {
         self.ban_masks.insert(mask)
@@ -227,8 +271,9 @@ impl Channel {
         if self.member_with_id(member.id()).is_some() {
             false // member already in channel
         } else {
-            self.nicknames.insert(member.id(), member.nick().to_string());
-            self.members.insert(member.nick().to_string(), member);
+            let key = self.casemapping.key(member.nick());
+            self.nicknames.insert(member.id(), key.clone());
+            self.members.insert(key, member);
             true
         }
     }
@@ -244,6 +289,24 @@ impl Channel {
         true
     }
     
+    /// Changes the nick of a member
+    pub fn rename_member(&mut self, id: &PeerId, nick: String) -> bool {
+        let old_nick = match self.nicknames.get(id) {
+            Some(nick) => nick.clone(),
+            None => return false
+        };
+        match self.members.remove(&old_nick) {
+            Some(mut member) => {
+                let key = self.casemapping.key(nick.as_slice());
+                member.set_nick(nick);
+                self.nicknames.insert(*id, key.clone());
+                self.members.insert(key, member);
+                true
+            },
+            None => false
+        }
+    }
+    
     pub fn send_response(&self, client: &Peer, command: cmd::ResponseCode, 
                          params: &[&str]) {
         client.send_response(
@@ -269,15 +332,18 @@ impl Channel {
         }
     }
     
-    pub fn member_with_nick(&self, nick: &String) -> Option<&Member> {
-        self.members.get(nick)
+    pub fn member_with_nick(&self, nick: &str) -> Option<&Member> {
+        self.members.get(&self.casemapping.key(nick))
     }
     
-    pub fn mut_member_with_nick(&mut self, nick: &String) -> Option<&mut Member> {
-        self.members.get_mut(nick)
+    pub fn mut_member_with_nick(&mut self, nick: &str) -> Option<&mut Member> {
+        let key = self.casemapping.key(nick);
+        self.members.get_mut(&key)
     }
     
     /// Broadcasts a message to all members
+    ///
+    /// The members share the message, it is not copied.
     #[inline]
     pub fn broadcast(&self, message: RawMessage) {
         for member in self.members() {
@@ -327,4 +393,63 @@ impl<'a> Drop for ListSender<'a> {
     fn drop(&mut self) {
         self.receiver.send_response(self.end_code, &[self.name], self.server_name)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use test::{Bencher};
+	use cmd::{PRIVMSG};
+	use con::{Connection, Outgoing, detached_connection};
+	use config::{Config};
+	use msg::{RawMessage};
+	use super::{Channel, Member};
+
+	static MEMBERS: uint = 5000;
+
+	/// Creates a channel with 5000 members whose connections have no socket
+	fn crowded_channel() -> (Channel, Vec<(Connection, Outgoing)>) {
+		let config = Config::from_str(r#"
+[[listen]]
+address = "127.0.0.1"
+"#).unwrap();
+		let mut channel = Channel::new("#channel".to_string(), &config);
+		let connections = Vec::from_fn(MEMBERS, |i| {
+			let (con, out) = detached_connection("localhost");
+			con.peer().info().write().set_nick(format!("nick{}", i));
+			channel.add_member(Member::new(con.peer()));
+			(con, out)
+		});
+		(channel, connections)
+	}
+
+	fn broadcast(b: &mut Bencher, send: |&Channel, &RawMessage|) {
+		let (channel, connections) = crowded_channel();
+		let m = RawMessage::new(PRIVMSG, &["#channel",
+			"Lorem ipsum dolor sit amet, consectetur adipisici elit, sed eiusmod tempor incidunt"
+		], Some("nick!user@example.com"));
+		b.iter(|| {
+			send(&channel, &m);
+			// Take the messages out of the send queues like the event loop
+			for &(ref con, ref out) in connections.iter() {
+				for msg in out.messages().iter() {
+					con.sendq().pop(msg.wire_len())
+				}
+			}
+		})
+	}
+	/// Broadcast into a channel with 5000 members sharing the message
+	#[bench]
+	fn bench_broadcast_shared(b: &mut Bencher) {
+		broadcast(b, |channel, m| channel.broadcast(m.clone()))
+	}
+	/// Broadcast into a channel with 5000 members copying the message for
+	/// every member like it was done before messages were shared
+	#[bench]
+	fn bench_broadcast_copied(b: &mut Bencher) {
+		broadcast(b, |channel, m| {
+			for member in channel.members() {
+				member.send_msg(RawMessage::parse(m.as_slice()).unwrap())
+			}
+		})
+	}
+}
@@ -0,0 +1,409 @@
+//! Registered channels
+//!
+//! Registered channels have a founder account and an access list, their
+//! topic, modes and masks are restored when they are created again after
+//! a restart. They are stored in a file which is rewritten by the server,
+//! changes of the topic and modes are written out periodically:
+//!
+//! ```text
+//! channel #rust
+//! founder alice
+//! topic Welcome to #rust
+//! modes nt
+//! ban *!*@spam.example.net
+//! access bob auto-op
+//! access carol auto-voice
+//! access mallory ban
+//! ```
+//!
+//! `key`, `limit`, `except` and `invite` lines hold the remaining modes.
+
+use std::collections::{HashMap};
+use std::io::{File, IoResult, IoError, InvalidInput, FileNotFound};
+use std::io::fs;
+
+use util::{CaseMapping, NameKey};
+
+pub use self::AccessLevel::*;
+
+/// Privileges of an account in a registered channel
+#[deriving(Clone, PartialEq, Show)]
+pub enum AccessLevel {
+    /// Members logged in to the account become operators when they join
+    AutoOp,
+    /// Members logged in to the account are voiced when they join
+    AutoVoice,
+    /// Users logged in to the account cannot join
+    Ban
+}
+
+impl AccessLevel {
+    pub fn from_name(name: &str) -> Option<AccessLevel> {
+        match name {
+            "auto-op" => Some(AutoOp),
+            "auto-voice" => Some(AutoVoice),
+            "ban" => Some(Ban),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AutoOp => "auto-op",
+            AutoVoice => "auto-voice",
+            Ban => "ban"
+        }
+    }
+}
+
+/// Topic, modes and masks of a channel
+#[deriving(Clone, PartialEq)]
+pub struct ChannelState {
+    pub topic: Vec<u8>,
+    /// Channel flags
+    pub modes: String,
+    pub key: Option<Vec<u8>>,
+    pub limit: Option<uint>,
+    pub ban_masks: Vec<String>,
+    pub except_masks: Vec<String>,
+    pub invite_masks: Vec<String>
+}
+
+/// Registered channel
+#[deriving(Clone)]
+pub struct Registration {
+    pub name: String,
+    /// Account of the founder
+    pub founder: String,
+    pub state: ChannelState,
+    pub access: Vec<(String, AccessLevel)>
+}
+
+impl Registration {
+    /// Access level of an account, the founder is an operator
+    pub fn access(&self, account: &str, casemapping: CaseMapping) -> Option<AccessLevel> {
+        if self.is_founder(account, casemapping) {
+            return Some(AutoOp)
+        }
+        let key = casemapping.key(account);
+        self.access.iter().find(
+            |&&(ref name, _)| casemapping.key(name.as_slice()) == key
+        ).map(|&(_, ref level)| level.clone())
+    }
+
+    /// Checks whether the account is the founder of the channel
+    pub fn is_founder(&self, account: &str, casemapping: CaseMapping) -> bool {
+        casemapping.key(self.founder.as_slice()) == casemapping.key(account)
+    }
+
+    /// Sets or removes the access level of an account
+    ///
+    /// Returns false if nothing changed.
+    pub fn set_access(&mut self, account: &str, level: Option<AccessLevel>,
+                      casemapping: CaseMapping) -> bool {
+        let key = casemapping.key(account);
+        let position = self.access.iter().position(
+            |&(ref name, _)| casemapping.key(name.as_slice()) == key
+        );
+        match (position, level) {
+            (Some(i), Some(level)) => {
+                let changed = match self.access[i] { (_, ref old) => *old != level };
+                self.access.remove(i);
+                self.access.insert(i, (account.to_string(), level));
+                changed
+            },
+            (Some(i), None) => { self.access.remove(i); true },
+            (None, Some(level)) => { self.access.push((account.to_string(), level)); true },
+            (None, None) => false
+        }
+    }
+
+    /// Formats the registration like it is stored in the file
+    fn write_to(&self, out: &mut Vec<u8>) {
+        fn line(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+            out.push_all(key.as_bytes());
+            out.push(b' ');
+            out.push_all(value);
+            out.push(b'\n');
+        }
+        let state = &self.state;
+        line(out, "channel", self.name.as_bytes());
+        line(out, "founder", self.founder.as_bytes());
+        if !state.topic.is_empty() {
+            line(out, "topic", state.topic.as_slice())
+        }
+        if !state.modes.is_empty() {
+            line(out, "modes", state.modes.as_bytes())
+        }
+        if let Some(ref key) = state.key {
+            line(out, "key", key.as_slice())
+        }
+        if let Some(limit) = state.limit {
+            line(out, "limit", limit.to_string().as_bytes())
+        }
+        for &(key, masks) in [("ban", &state.ban_masks), ("except", &state.except_masks),
+                              ("invite", &state.invite_masks)].iter() {
+            for mask in masks.iter() {
+                line(out, key, mask.as_bytes())
+            }
+        }
+        for &(ref account, ref level) in self.access.iter() {
+            line(out, "access", format!("{} {}", account, level.name()).as_bytes())
+        }
+    }
+}
+
+fn invalid(line: uint, desc: &'static str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: desc,
+        detail: Some(format!("line {}", line))
+    }
+}
+
+/// Parses the registrations, errors carry the line they occured on
+fn parse(data: &[u8]) -> IoResult<Vec<Registration>> {
+    let mut registrations: Vec<Registration> = Vec::new();
+    // Line of the last channel
+    let mut start = 0;
+    for (i, line) in data.split(|&c| c == b'\n').enumerate() {
+        let line_no = i + 1;
+        let line = match line.last() {
+            Some(&b'\r') => line.slice_to(line.len() - 1),
+            _ => line
+        };
+        if line.is_empty() || line[0] == b'#' {
+            continue
+        }
+        let (key, value) = match line.iter().position(|&c| c == b' ') {
+            Some(pos) => (line.slice_to(pos), line.slice_from(pos + 1)),
+            None => (line, b"")
+        };
+        let key = String::from_utf8_lossy(key).into_string();
+        if key.as_slice() == "channel" {
+            if registrations.last().map_or(false, |r| r.founder.is_empty()) {
+                return Err(invalid(start, "channel without founder"))
+            }
+            start = line_no;
+            registrations.push(Registration {
+                name: String::from_utf8_lossy(value).into_string(),
+                founder: String::new(),
+                state: ChannelState {
+                    topic: Vec::new(),
+                    modes: String::new(),
+                    key: None,
+                    limit: None,
+                    ban_masks: Vec::new(),
+                    except_masks: Vec::new(),
+                    invite_masks: Vec::new()
+                },
+                access: Vec::new()
+            });
+            continue
+        }
+        let registration = match registrations.last_mut() {
+            Some(registration) => registration,
+            None => return Err(invalid(line_no, "expected a channel line"))
+        };
+        let text = String::from_utf8_lossy(value).into_string();
+        let state = &mut registration.state;
+        match key.as_slice() {
+            "founder" => registration.founder = text,
+            "topic" => state.topic = value.to_vec(),
+            "modes" => state.modes = text,
+            "key" => state.key = Some(value.to_vec()),
+            "limit" => match from_str::<uint>(text.as_slice()) {
+                Some(limit) => state.limit = Some(limit),
+                None => return Err(invalid(line_no, "invalid user limit"))
+            },
+            "ban" => state.ban_masks.push(text),
+            "except" => state.except_masks.push(text),
+            "invite" => state.invite_masks.push(text),
+            "access" => {
+                let fields: Vec<&str> = text.as_slice().split(' ').collect();
+                match fields.as_slice() {
+                    [account, level] => match AccessLevel::from_name(level) {
+                        Some(level) => registration.access.push((account.to_string(), level)),
+                        None => return Err(invalid(line_no, "unknown access level"))
+                    },
+                    _ => return Err(invalid(line_no, "expected an account and a level"))
+                }
+            },
+            _ => return Err(invalid(line_no, "unknown key"))
+        }
+    }
+    if registrations.last().map_or(false, |r| r.founder.is_empty()) {
+        return Err(invalid(start, "channel without founder"))
+    }
+    Ok(registrations)
+}
+
+/// Registered channels stored in a local file
+pub struct Registry {
+    path: Path,
+    casemapping: CaseMapping,
+    channels: HashMap<NameKey, Registration>,
+    /// Whether a channel state changed since the file was written
+    dirty: bool
+}
+
+impl Registry {
+    /// Loads the registrations, a missing file counts as empty
+    pub fn load(path: &Path, casemapping: CaseMapping) -> IoResult<Registry> {
+        let registrations = match File::open(path).read_to_end() {
+            Ok(data) => try!(parse(data.as_slice())),
+            Err(ref err) if err.kind == FileNotFound => Vec::new(),
+            Err(err) => return Err(err)
+        };
+        Ok(Registry {
+            path: path.clone(),
+            casemapping: casemapping,
+            channels: registrations.into_iter().map(
+                |r| (casemapping.key(r.name.as_slice()), r)
+            ).collect(),
+            dirty: false
+        })
+    }
+
+    /// Writes all registrations to a temporary file and moves it over the
+    /// old one
+    pub fn save(&mut self) -> IoResult<()> {
+        let mut registrations: Vec<&Registration> = self.channels.values().collect();
+        registrations.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut data = Vec::new();
+        for registration in registrations.iter() {
+            registration.write_to(&mut data)
+        }
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write(data.as_slice()));
+            try!(file.fsync());
+        }
+        try!(fs::rename(&tmp, &self.path));
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Saves the registrations if a channel state changed
+    pub fn flush(&mut self) -> IoResult<()> {
+        if self.dirty { self.save() } else { Ok(()) }
+    }
+
+    /// Finds a registered channel
+    pub fn get(&self, key: &NameKey) -> Option<&Registration> {
+        self.channels.get(key)
+    }
+
+    /// Registers a channel, returns false if it is already registered
+    pub fn register(&mut self, registration: Registration) -> IoResult<bool> {
+        let key = self.casemapping.key(registration.name.as_slice());
+        if self.channels.contains_key(&key) {
+            return Ok(false)
+        }
+        self.channels.insert(key, registration);
+        try!(self.save());
+        Ok(true)
+    }
+
+    /// Removes a registration, returns false if the channel is not registered
+    pub fn unregister(&mut self, key: &NameKey) -> IoResult<bool> {
+        if self.channels.remove(key).is_none() {
+            return Ok(false)
+        }
+        try!(self.save());
+        Ok(true)
+    }
+
+    /// Stores the topic, modes and masks of a registered channel
+    ///
+    /// The change is only written by the next `flush`.
+    pub fn set_state(&mut self, key: &NameKey, state: ChannelState) {
+        match self.channels.get_mut(key) {
+            Some(registration) if registration.state != state => registration.state = state,
+            _ => return
+        }
+        self.dirty = true
+    }
+
+    /// Sets or removes the access level of an account
+    ///
+    /// Returns false if the channel is not registered or nothing changed.
+    pub fn set_access(&mut self, key: &NameKey, account: &str,
+                      level: Option<AccessLevel>) -> IoResult<bool> {
+        let changed = match self.channels.get_mut(key) {
+            Some(registration) => registration.set_access(account, level, self.casemapping),
+            None => false
+        };
+        if changed {
+            try!(self.save());
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{parse, Registry, AutoOp, AutoVoice, Ban};
+	use util::{Rfc1459};
+	use std::io::{File, TempDir};
+
+	static DATA: &'static [u8] = b"channel #rust
+founder alice
+topic Welcome to #rust
+modes nt
+limit 20
+ban *!*@spam.example.net
+access bob auto-op
+access carol auto-voice
+access mallory ban
+";
+
+	/// Test parsing and formatting of registrations
+	#[test]
+	fn test_parse() {
+		let registrations = parse(DATA).unwrap();
+		assert_eq!(registrations.len(), 1)
+		let registration = &registrations[0];
+		assert_eq!(registration.state.topic.as_slice(), b"Welcome to #rust")
+		assert_eq!(registration.state.limit, Some(20))
+		assert!(registration.is_founder("ALICE", Rfc1459))
+		assert_eq!(registration.access("ALICE", Rfc1459), Some(AutoOp))
+		assert_eq!(registration.access("bob", Rfc1459), Some(AutoOp))
+		assert_eq!(registration.access("Carol", Rfc1459), Some(AutoVoice))
+		assert_eq!(registration.access("mallory", Rfc1459), Some(Ban))
+		assert_eq!(registration.access("dave", Rfc1459), None)
+		let mut data = Vec::new();
+		registration.write_to(&mut data);
+		assert_eq!(data.as_slice(), DATA)
+		assert!(parse(b"founder alice").is_err())
+		assert!(parse(b"channel #rust").is_err())
+		assert!(parse(b"channel #rust\nfounder alice\naccess bob owner").is_err())
+	}
+
+	/// Test that changes are saved
+	#[test]
+	fn test_registry() {
+		let dir = TempDir::new("chatd").unwrap();
+		let path = dir.path().join("channels.txt");
+		let mut registry = Registry::load(&path, Rfc1459).unwrap();
+		let registration = parse(DATA).unwrap().pop().unwrap();
+		assert!(registry.register(registration.clone()).unwrap())
+		assert!(!registry.register(registration).unwrap())
+		let key = Rfc1459.key("#RUST");
+		assert!(registry.set_access(&key, "dave[", Some(AutoVoice)).unwrap())
+		assert!(!registry.set_access(&key, "DAVE{", Some(AutoVoice)).unwrap())
+		let mut registry = Registry::load(&path, Rfc1459).unwrap();
+		assert_eq!(registry.get(&key).unwrap().access("Dave{", Rfc1459), Some(AutoVoice))
+		let mut state = registry.get(&key).unwrap().state.clone();
+		state.topic = b"New topic".to_vec();
+		registry.set_state(&key, state);
+		assert_eq!(Registry::load(&path, Rfc1459).unwrap().get(&key).unwrap().state.topic.as_slice(),
+			b"Welcome to #rust")
+		registry.flush().unwrap();
+		assert_eq!(Registry::load(&path, Rfc1459).unwrap().get(&key).unwrap().state.topic.as_slice(),
+			b"New topic")
+		assert!(registry.unregister(&key).unwrap())
+		assert_eq!(File::open(&path).read_to_end().unwrap().len(), 0)
+	}
+}
@@ -1,7 +1,10 @@
 use std::collections::{HashSet};
 
+use msg::raw::{Params};
+
 pub use self::ChannelMode::*;
 pub use self::Action::*;
+pub use self::ModeClass::*;
 
 /// Enumeration of possible channel modes
 /// as of http://tools.ietf.org/html/rfc2811#section-4
@@ -45,6 +48,31 @@ pub enum ChannelMode {
     InvitationMask = b'I' as int
 }
 
+/// All channel modes clients can set
+///
+/// `ChannelCreator` is only given by the server when a channel is created,
+/// so it is neither advertised nor settable.
+pub static CHANNEL_MODES: [ChannelMode, ..16] = [
+    OperatorPrivilege, VoicePrivilege, AnonChannel, InviteOnly,
+    Moderated, MemberOnly, Quiet, Private, Secret, ReOpFlag, TopicProtect,
+    ChannelKey, UserLimit, BanMask, ExceptionMask, InvitationMask
+];
+
+/// Classification of the modes as used by RPL_ISUPPORT
+#[deriving(PartialEq, Eq, Show)]
+pub enum ModeClass {
+    /// Mode that adds or removes a nick from a list (PREFIX)
+    MemberMode,
+    /// Mode that adds or removes an address from a list (CHANMODES type A)
+    ListMode,
+    /// Mode that always has a parameter (CHANMODES type B)
+    ParamMode,
+    /// Mode that only has a parameter when set (CHANMODES type C)
+    SetParamMode,
+    /// Mode without a parameter (CHANMODES type D)
+    FlagMode
+}
+
 // Actions which determine what to do with a mode
 #[deriving(PartialEq, Eq, Show)]
 pub enum Action {
@@ -57,14 +85,57 @@ pub enum Action {
 }
 
 impl ChannelMode {
-    fn has_parameter(&self) -> bool {
+    /// Checks whether the mode takes a parameter
+    pub fn has_parameter(&self) -> bool {
+        self.class() != FlagMode
+    }
+    
+    /// Returns the class of the mode
+    pub fn class(&self) -> ModeClass {
         match *self {
-            ChannelKey | UserLimit | BanMask
-            | ExceptionMask | InvitationMask
-            | OperatorPrivilege | VoicePrivilege => true,
-            _ => false
+            ChannelCreator | OperatorPrivilege | VoicePrivilege => MemberMode,
+            BanMask | ExceptionMask | InvitationMask => ListMode,
+            ChannelKey => ParamMode,
+            UserLimit => SetParamMode,
+            _ => FlagMode
         }
     }
+    
+    /// Returns the nick prefix of member modes
+    ///
+    /// The prefixes are ordered by rank.
+    pub fn prefix(&self) -> Option<char> {
+        match *self {
+            OperatorPrivilege => Some('@'),
+            VoicePrivilege => Some('+'),
+            _ => None
+        }
+    }
+}
+
+/// Value of the CHANMODES token of RPL_ISUPPORT
+pub fn isupport_chanmodes() -> String {
+    let class_modes = |class: ModeClass| -> String {
+        CHANNEL_MODES.iter().filter(|m| m.class() == class)
+                     .map(|m| *m as u8 as char).collect()
+    };
+    format!("{},{},{},{}",
+        class_modes(ListMode),
+        class_modes(ParamMode),
+        class_modes(SetParamMode),
+        class_modes(FlagMode)
+    )
+}
+
+/// Value of the PREFIX token of RPL_ISUPPORT
+pub fn isupport_prefix() -> String {
+    let modes: Vec<(ChannelMode, char)> = CHANNEL_MODES.iter().filter_map(|m|
+        m.prefix().map(|p| (*m, p))
+    ).collect();
+    format!("({}){}",
+        modes.iter().map(|&(m, _)| m as u8 as char).collect::<String>(),
+        modes.iter().map(|&(_, p)| p).collect::<String>()
+    )
 }
 
 /// Parses the channel modes
@@ -91,24 +162,26 @@ impl ChannelMode {
 /// ```
 /// 
 /// 
-pub fn modes_do(slice: &[&[u8]], block: |Action, ChannelMode, Option<&[u8]>|) {
-    let mut current = slice;
+pub fn modes_do(params: Params, block: |Action, ChannelMode, Option<&[u8]>|) {
+    let mut current = params;
     loop {
+        let first = match current.get(0) {
+            Some(first) => first,
+            None => break
+        };
         // Bug: no +/- asking for modes
-        let (action, offset) = match current[0][0] {
-            b'+' => (Add, 1),
-            b'-' => (Remove, 1),
+        let (action, offset) = match first.get(0) {
+            Some(&b'+') => (Add, 1),
+            Some(&b'-') => (Remove, 1),
             _ => (Show, 0)
             
         };
-        for mode in current[0].slice_from(offset).iter().filter_map( |&v| {
+        for mode in first.slice_from(offset).iter().filter_map( |&v| {
             let m: Option<ChannelMode> = FromPrimitive::from_u8(v); m
         }) {
             let param = if mode.has_parameter() && action != Show {
-                let param = current.get(1).map(|v| *v);
-                if current.len() > 1 {
-                    current = current.slice_from(1);
-                } else { current = [].as_slice(); }
+                let param = current.get(1);
+                current = current.slice_from(1);
                 param
             } else {
                 None
@@ -128,6 +201,7 @@ pub type Flags = HashSet<ChannelMode>;
 #[cfg(test)]
 mod tests {
 	use super::{modes_do, OperatorPrivilege, BanMask, ExceptionMask, Add, Remove, Show};
+	use super::{isupport_chanmodes, isupport_prefix, CHANNEL_MODES};
     use msg::{RawMessage};
 	/// Tests the mode parser
     
@@ -165,4 +239,16 @@ mod tests {
             })
         }
 	}
-}
\ No newline at end of file
+
+	/// Tests the RPL_ISUPPORT tokens
+	#[test]
+	fn test_isupport() {
+		assert_eq!(isupport_chanmodes().as_slice(), "beI,k,l,aimnqpsrt")
+		assert_eq!(isupport_prefix().as_slice(), "(ov)@+")
+		// Every supported mode is advertised by one of the tokens
+		let advertised = isupport_prefix() + isupport_chanmodes();
+		for mode in CHANNEL_MODES.iter() {
+			assert!(advertised.as_slice().contains_char(*mode as u8 as char))
+		}
+	}
+}
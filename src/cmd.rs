@@ -1,8 +1,36 @@
 /// Module encapsules all command constants.
 
+use std::cmp::{Ordering, Less, Greater, Equal};
+
 pub use self::Command::*;
 pub use self::ResponseCode::*;
 
+/// Converts an ASCII letter to upper case
+#[inline]
+fn to_upper(c: u8) -> u8 {
+    if c >= b'a' && c <= b'z' { c - (b'a' - b'A') } else { c }
+}
+
+/// Compares a command name with bytes, ignoring the case of the bytes
+fn cmp_name(name: &[u8], bytes: &[u8]) -> Ordering {
+    for (&a, &b) in name.iter().zip(bytes.iter()) {
+        match a.cmp(&to_upper(b)) {
+            Equal => {},
+            order => return order
+        }
+    }
+    name.len().cmp(&bytes.len())
+}
+
+/// Parses a three-digit numeric
+fn parse_numeric(bytes: &[u8]) -> Option<u16> {
+    if bytes.len() == 3 && bytes.iter().all(|&c| c >= b'0' && c <= b'9') {
+        Some(bytes.iter().fold(0, |n, &c| n * 10 + (c - b'0') as u16))
+    } else {
+        None
+    }
+}
+
 
 macro_rules! commands {
     {$(
@@ -19,12 +47,32 @@ macro_rules! commands {
             UNKNOWN(&'a[u8]),
         }
         
+        /// Command names for the lookup, they have to be sorted
+        static COMMAND_TABLE: &'static [(&'static str, Command<'static>)] = &[
+            $((stringify!($ident), $ident),)*
+        ];
+        
         impl<'a> Command<'a> {
             /// Converts bytestring to Command 
+            ///
+            /// The lookup is case-insensitive. Three-digit numerics are
+            /// converted to replies.
             pub fn from_bytes(bytes: &'a [u8]) -> Command<'a> {
-                // TODO add REPLY(...)
-                $(if bytes == stringify!($ident).as_bytes() { $ident } else)* {
-                    UNKNOWN(bytes)
+                match parse_numeric(bytes) {
+                    Some(code) => return match FromPrimitive::from_u16(code) {
+                        Some(code) => REPLY(code),
+                        None => UNKNOWN(bytes)
+                    },
+                    None => {}
+                }
+                match COMMAND_TABLE.binary_search(
+                    |&(name, _)| cmp_name(name.as_bytes(), bytes)
+                ).found() {
+                    Some(i) => {
+                        let (_, command) = COMMAND_TABLE[i];
+                        command
+                    },
+                    None => UNKNOWN(bytes)
                 }
             }
 
@@ -45,32 +93,47 @@ macro_rules! commands {
     }
 }
 
+// Keep this sorted, the commands are looked up by binary search
 commands!{
-    PRIVMSG     #[doc = "`PRIVMSG` command"];
-    NOTICE      #[doc = "`NOTICE` command"];
-    MODE        #[doc = "`MODE` command"];
+    AUTHENTICATE #[doc = "`AUTHENTICATE` command"];
+    CAP         #[doc = "`CAP` command"];
+    CERT        #[doc = "`CERT` command"];
+    CHANNEL     #[doc = "`CHANNEL` command"];
+    ERROR       #[doc = "`ERROR` command, see http://tools.ietf.org/html/rfc1459.html#section-4.6.4"];
+    FAIL        #[doc = "`FAIL` standard reply, see http://ircv3.net/specs/extensions/standard-replies"];
     JOIN        #[doc = "`JOIN` command, see http://tools.ietf.org/html/rfc1459.html#section-4.2.1"];
-    PING        #[doc = "`PING` command"];
-    WHO         #[doc = "`WHO` command"];
+    MODE        #[doc = "`MODE` command"];
+    MOTD        #[doc = "`MOTD` command"];
     NAMES       #[doc = "`NAMES` command"];
-    TOPIC       #[doc = "`TOPIC` command"];
+    NICK        #[doc = "`NICK` command"];
+    NICKS       #[doc = "`NICKS` command"];
+    NOTICE      #[doc = "`NOTICE` command"];
+    OPER        #[doc = "`OPER` command"];
     PART        #[doc = "`PART` command"];
-    QUIT        #[doc = "`QUIT` command"];
+    PING        #[doc = "`PING` command"];
     PONG        #[doc = "`PONG` command"];
-    NICK        #[doc = "`NICK` command"];
+    PRIVMSG     #[doc = "`PRIVMSG` command"];
+    QUIT        #[doc = "`QUIT` command"];
+    REGISTER    #[doc = "`REGISTER` command"];
+    REHASH      #[doc = "`REHASH` command"];
+    TAGMSG      #[doc = "`TAGMSG` command"];
+    TOPIC       #[doc = "`TOPIC` command"];
     USER        #[doc = "`USER` command"];
-    CAP         #[doc = "`CAP` command"];
+    VERIFY      #[doc = "`VERIFY` command"];
+    WHO         #[doc = "`WHO` command"];
 }
 
 
 #[allow(non_camel_case_types)] 
-#[deriving(Show, PartialEq)]
+#[deriving(Show, PartialEq, FromPrimitive)]
 pub enum ResponseCode {
     RPL_WELCOME = 001,
     RPL_YOURHOST = 002,
     RPL_CREATED = 003,
     RPL_MYINFO = 004,
-    RPL_BOUNCE = 005,
+    /// Originally RPL_BOUNCE, now used by all servers to announce
+    /// supported features
+    RPL_ISUPPORT = 005,
     RPL_USERHOST = 302,
     RPL_ISON = 303,
     RPL_AWAY = 301,
@@ -159,11 +222,13 @@ pub enum ResponseCode {
     ERR_TOOMANYTARGETS = 407,
     ERR_NOSUCHSERVICE = 408,
     ERR_NOORIGIN = 409,
+    ERR_INVALIDCAPCMD = 410,
     ERR_NORECIPIENT = 411,
     ERR_NOTEXTTOSEND = 412,
     ERR_NOTOPLEVEL = 413,
     ERR_WILDTOPLEVEL = 414,
     ERR_BADMASK = 415,
+    ERR_INPUTTOOLONG = 417,
     ERR_UNKNOWNCOMMAND = 421,
     ERR_NOMOTD = 422,
     ERR_NOADMININFO = 423,
@@ -203,4 +268,41 @@ pub enum ResponseCode {
     ERR_NOOPERHOST = 491,
     ERR_UMODEUNKNOWNFLAG = 501,
     ERR_USERSDONTMATCH = 502,
-}
\ No newline at end of file
+    RPL_LOGGEDIN = 900,
+    RPL_LOGGEDOUT = 901,
+    ERR_NICKLOCKED = 902,
+    RPL_SASLSUCCESS = 903,
+    ERR_SASLFAIL = 904,
+    ERR_SASLTOOLONG = 905,
+    ERR_SASLABORTED = 906,
+    ERR_SASLALREADY = 907,
+    RPL_SASLMECHS = 908,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Command, COMMAND_TABLE, PRIVMSG, NICK, NICKS, REPLY, UNKNOWN};
+	use super::{RPL_WELCOME, ERR_NOSUCHNICK};
+	/// Test the command lookup
+	#[test]
+	fn test_from_bytes() {
+		assert_eq!(Command::from_bytes(b"PRIVMSG"), PRIVMSG)
+		assert_eq!(Command::from_bytes(b"privmsg"), PRIVMSG)
+		assert_eq!(Command::from_bytes(b"NiCk"), NICK)
+		assert_eq!(Command::from_bytes(b"NICKS"), NICKS)
+		assert_eq!(Command::from_bytes(b"NICKX"), UNKNOWN(b"NICKX"))
+		assert_eq!(Command::from_bytes(b"001"), REPLY(RPL_WELCOME))
+		assert_eq!(Command::from_bytes(b"401"), REPLY(ERR_NOSUCHNICK))
+		assert_eq!(Command::from_bytes(b"999"), UNKNOWN(b"999"))
+		assert_eq!(Command::from_bytes(b"0001"), UNKNOWN(b"0001"))
+		assert_eq!(Command::from_bytes(b""), UNKNOWN(b""))
+	}
+	/// The lookup only works if the table is sorted
+	#[test]
+	fn test_table_sorted() {
+		for pair in COMMAND_TABLE.windows(2) {
+			let ((a, _), (b, _)) = (pair[0], pair[1]);
+			assert!(a < b)
+		}
+	}
+}
@@ -1,9 +1,10 @@
 use std::sync::{Arc, RWLock};
 
 use std::collections::{HashSet};
-use util::{HostMask};
+use util::{HostMask, NameKey};
 
 use msg::{RawMessage};
+use msg::raw::{MAX_LENGTH};
 use cmd;
 
 pub mod flag {
@@ -20,7 +21,10 @@ pub mod flag {
     #[deriving(FromPrimitive, PartialEq, Eq, Hash)]
     pub enum Extensions {
         IRCExtensions,
-        SASL
+        SASL,
+        MessageTags,
+        CapNotify,
+        AccountRegistration
     }
 }
 
@@ -36,13 +40,23 @@ pub struct UserInfo {
     realname: String,
     hostname: String,
     hostmask: HostMask,
+    account: Option<String>,
+    certfp: Option<String>,
     status: flag::RegistrationStatus,
-    capabilities: HashSet<flag::Extensions>
+    capabilities: HashSet<flag::Extensions>,
+    cap_version: uint,
+    negotiating: bool,
+    oper: bool,
+    secure: bool,
+    channels: HashSet<NameKey>
 }
 
 impl UserInfo {
     /// Creates the user info struct.
-    pub fn new(id: super::PeerId, server_name: String, hostname: String) -> UserInfo {
+    ///
+    /// `secure` indicates that the peer is connected via TLS.
+    pub fn new(id: super::PeerId, server_name: String, hostname: String,
+               secure: bool) -> UserInfo {
         let mask = HostMask::from_parts("*", "*", hostname.as_slice());
         UserInfo {
             id: id,
@@ -52,8 +66,15 @@ impl UserInfo {
             realname: "John Doe".to_string(),
             hostname: hostname,
             hostmask: mask,
+            account: None,
+            certfp: None,
             status: flag::Connected,
-            capabilities: HashSet::new()
+            capabilities: HashSet::new(),
+            cap_version: 0,
+            negotiating: false,
+            oper: false,
+            secure: secure,
+            channels: HashSet::new()
         }
     }
     
@@ -97,6 +118,28 @@ impl UserInfo {
     pub fn hostname(&self) -> &String {
         &self.hostname
     }
+    /// Setter for the host name, used once the address of the peer was
+    /// looked up
+    pub fn set_hostname(&mut self, hostname: String) {
+        self.hostname = hostname;
+        self.update_mask()
+    }
+    /// Account the user is logged in to
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_ref().map(|v| v.as_slice())
+    }
+    /// Logs the user in to an account or out
+    pub fn set_account(&mut self, account: Option<String>) {
+        self.account = account
+    }
+    /// SHA-256 fingerprint of the TLS client certificate as lower case hex
+    pub fn certfp(&self) -> Option<&str> {
+        self.certfp.as_ref().map(|v| v.as_slice())
+    }
+    /// Records the fingerprint of the TLS client certificate
+    pub fn set_certfp(&mut self, certfp: Option<String>) {
+        self.certfp = certfp
+    }
     /// Getter for the registration status/method
     pub fn registration_status(&self) -> flag::RegistrationStatus {
         self.status
@@ -106,6 +149,68 @@ impl UserInfo {
         &mut self.status
     }
     
+    /// Checks whether the user is an IRC operator
+    pub fn is_oper(&self) -> bool {
+        self.oper
+    }
+    /// Grants or revokes the IRC operator status
+    pub fn set_oper(&mut self, oper: bool) {
+        self.oper = oper
+    }
+    
+    /// Checks whether the client negotiated a capability
+    pub fn has_capability(&self, cap: flag::Extensions) -> bool {
+        self.capabilities.contains(&cap)
+    }
+    /// Enables a capability
+    pub fn add_capability(&mut self, cap: flag::Extensions) {
+        self.capabilities.insert(cap);
+    }
+    /// Disables a capability
+    pub fn remove_capability(&mut self, cap: flag::Extensions) {
+        self.capabilities.remove(&cap);
+    }
+    /// Enabled capabilities
+    pub fn capabilities(&self) -> &HashSet<flag::Extensions> {
+        &self.capabilities
+    }
+    /// Highest CAP version the client announced, 0 if it never sent CAP LS
+    pub fn cap_version(&self) -> uint {
+        self.cap_version
+    }
+    /// Records the CAP version the client announced
+    pub fn set_cap_version(&mut self, version: uint) {
+        if version > self.cap_version {
+            self.cap_version = version
+        }
+    }
+    /// Checks whether the registration is suspended by capability negotiation
+    pub fn is_negotiating(&self) -> bool {
+        self.negotiating
+    }
+    /// Suspends or resumes the registration
+    pub fn set_negotiating(&mut self, negotiating: bool) {
+        self.negotiating = negotiating
+    }
+    
+    /// Checks whether the peer is connected via TLS
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+    
+    /// Keys of the channels the user joined
+    pub fn channels(&self) -> &HashSet<NameKey> {
+        &self.channels
+    }
+    /// Records that the user joined a channel
+    pub fn add_channel(&mut self, key: NameKey) {
+        self.channels.insert(key);
+    }
+    /// Records that the user left a channel
+    pub fn remove_channel(&mut self, key: &NameKey) {
+        self.channels.remove(key);
+    }
+    
     /// Updates the real hostmask
     fn update_mask(&mut self) {
         self.hostmask = HostMask::from_parts(
@@ -134,20 +239,29 @@ impl UserInfo {
 #[deriving(Clone)]
 pub struct Peer {
     info: SharedInfo,
-    tx: Sender<RawMessage>,
+    outbox: super::Outbox,
 }
 impl Peer {
     /// Create a new peer struct.
-    pub fn new(info: UserInfo, tx: Sender<RawMessage>) -> Peer {
+    pub fn new(info: UserInfo, outbox: super::Outbox) -> Peer {
         Peer {
             info: Arc::new(RWLock::new(info)),
-            tx: tx
+            outbox: outbox
         }
     }
 
     /// Sends a message to the peer.
+    ///
+    /// Tags are only sent to clients which negotiated `message-tags`,
+    /// TAGMSG is not sent to other clients at all.
     pub fn send_msg(&self, msg: RawMessage) {
-        let _ = self.tx.send_opt(msg);
+        if msg.has_tags() && !self.info.read().has_capability(flag::MessageTags) {
+            if msg.command() != cmd::TAGMSG {
+                self.outbox.send(msg.without_tags())
+            }
+        } else {
+            self.outbox.send(msg)
+        }
     }
     
     /// Sends a response to the peer. 
@@ -163,7 +277,18 @@ impl Peer {
             (vec![info.nick().as_slice()] + params).as_slice(),
             Some(origin)
         );
-        let _ = self.tx.send_opt(msg);
+        self.outbox.send(msg)
+    }
+    
+    /// Number of bytes left for a trailing parameter in a response which
+    /// already has `params`
+    ///
+    /// Used to split long lists over several responses.
+    pub fn response_room(&self, params: &[&str], origin: &str) -> uint {
+        // ":<origin> 000 <nick>", " <param>" for every parameter and " :"
+        let used = 8 + origin.len() + self.info.read().nick().len()
+                   + params.iter().fold(0, |len, param| len + 1 + param.len());
+        if used < MAX_LENGTH { MAX_LENGTH - used } else { 0 }
     }
     
     /// Getter for the shared user info
@@ -0,0 +1,69 @@
+//! Flood protection
+
+use config::{FloodConfig};
+
+/// Token bucket rate limiter
+///
+/// Every message takes a token, tokens are refilled at a constant rate up
+/// to the burst size.
+pub struct TokenBucket {
+    burst: f64,
+    tokens: f64,
+    /// Seconds it takes to refill one token
+    interval: f64,
+    /// Time of the last refill in seconds
+    last: f64
+}
+
+impl TokenBucket {
+    /// Creates a full bucket
+    pub fn new(config: &FloodConfig, now: f64) -> TokenBucket {
+        TokenBucket {
+            burst: config.burst as f64,
+            tokens: config.burst as f64,
+            interval: config.interval as f64 / 1000.0,
+            last: now
+        }
+    }
+
+    /// Takes a token at time `now`
+    ///
+    /// Returns false if the bucket is empty.
+    pub fn take(&mut self, now: f64) -> bool {
+        if now > self.last {
+            self.tokens = (self.tokens + (now - self.last) / self.interval).min(self.burst);
+        }
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{TokenBucket};
+	use config::{FloodConfig};
+	/// Test the token bucket
+	#[test]
+	fn test_token_bucket() {
+		let config = FloodConfig {
+			burst: 3, interval: 500, exempt_opers: true, exempt_hosts: Vec::new()
+		};
+		let mut bucket = TokenBucket::new(&config, 0.0);
+		assert!(bucket.take(0.0))
+		assert!(bucket.take(0.0))
+		assert!(bucket.take(0.0))
+		assert!(!bucket.take(0.1))
+		assert!(bucket.take(0.6))
+		assert!(!bucket.take(0.6))
+		// Refilling stops at the burst size
+		assert!(bucket.take(100.0))
+		assert!(bucket.take(100.0))
+		assert!(bucket.take(100.0))
+		assert!(!bucket.take(100.0))
+	}
+}
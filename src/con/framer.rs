@@ -0,0 +1,148 @@
+//! Splits the incoming byte stream into IRC lines
+
+use msg::tags::{MAX_TAGS_LENGTH};
+
+pub use self::Frame::{Line, TooLong};
+
+/// Maximum length of a message including the trailing CR-LF
+pub static MAX_LINE_LENGTH: uint = 512;
+
+/// Line read from the stream
+#[deriving(Show, PartialEq)]
+pub enum Frame {
+    /// Complete line without the line terminator
+    Line(Vec<u8>),
+    /// Line that exceeded the length limits and has been dropped
+    TooLong
+}
+
+/// Byte oriented line framer
+///
+/// Lines are terminated by LF or CR-LF. No assumptions about the encoding
+/// are made, the bytes are passed on as they are. A line may not be longer
+/// than 512 bytes, an optional tag section may use an additional 8191 bytes.
+pub struct LineFramer {
+    buffer: Vec<u8>,
+    /// Start of the data in the buffer which was not returned yet
+    start: uint,
+    /// Set when the current line is already known to be too long
+    discarding: bool
+}
+
+impl LineFramer {
+    /// Creates an empty framer
+    pub fn new() -> LineFramer {
+        LineFramer {
+            buffer: Vec::with_capacity(MAX_LINE_LENGTH),
+            start: 0,
+            discarding: false
+        }
+    }
+
+    /// Appends bytes received from the stream
+    ///
+    /// The lines returned so far are removed from the buffer first.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.start > 0 {
+            let len = self.buffer.len() - self.start;
+            for i in range(0, len) {
+                let byte = self.buffer[self.start + i];
+                self.buffer.as_mut_slice()[i] = byte
+            }
+            self.buffer.truncate(len);
+            self.start = 0
+        }
+        self.buffer.push_all(bytes)
+    }
+
+    /// Returns the next complete line or `None` if more data is needed
+    ///
+    /// Empty lines are skipped.
+    pub fn next_line(&mut self) -> Option<Frame> {
+        loop {
+            let end = match self.buffer.slice_from(self.start).position_elem(&b'\n') {
+                Some(end) => self.start + end,
+                None => {
+                    if self.buffer.len() - self.start > MAX_TAGS_LENGTH + MAX_LINE_LENGTH {
+                        // Drop everything up to the next line terminator
+                        self.buffer.clear();
+                        self.start = 0;
+                        if !self.discarding {
+                            self.discarding = true;
+                            return Some(TooLong)
+                        }
+                    }
+                    return None
+                }
+            };
+            let mut line = self.buffer.slice(self.start, end);
+            self.start = end + 1;
+            if line.last() == Some(&b'\r') {
+                line = line.slice_to(line.len() - 1)
+            }
+            if self.discarding {
+                self.discarding = false;
+                continue
+            }
+            if line.is_empty() {
+                continue
+            }
+            return Some(if too_long(line) { TooLong } else { Line(line.to_vec()) })
+        }
+    }
+}
+
+/// Checks the length of a line without its terminator
+fn too_long(line: &[u8]) -> bool {
+    let (tags, message) = if line.starts_with(b"@") {
+        match line.position_elem(&b' ') {
+            Some(pos) => (pos + 1, line.len() - pos - 1),
+            None => (line.len(), 0)
+        }
+    } else {
+        (0, line.len())
+    };
+    tags > MAX_TAGS_LENGTH || message + 2 > MAX_LINE_LENGTH
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{LineFramer, Line, TooLong, MAX_LINE_LENGTH};
+	/// Test splitting of lines
+	#[test]
+	fn test_framing() {
+		let mut framer = LineFramer::new();
+		framer.feed(b"NICK foo\r\nUSER a b c :d\nPRIVMSG #ch");
+		assert_eq!(framer.next_line(), Some(Line(b"NICK foo".to_vec())))
+		assert_eq!(framer.next_line(), Some(Line(b"USER a b c :d".to_vec())))
+		assert_eq!(framer.next_line(), None)
+		framer.feed(b"an :\xff\xfe\r\n\r\n");
+		assert_eq!(framer.next_line(), Some(Line(b"PRIVMSG #chan :\xff\xfe".to_vec())))
+		assert_eq!(framer.next_line(), None)
+	}
+	/// Test the length limits
+	#[test]
+	fn test_length_limit() {
+		let mut framer = LineFramer::new();
+		let max = Vec::from_elem(MAX_LINE_LENGTH - 2, b'a');
+		framer.feed(max.as_slice());
+		framer.feed(b"\r\n");
+		assert_eq!(framer.next_line(), Some(Line(max.clone())))
+		framer.feed(max.as_slice());
+		framer.feed(b"a\r\nPING x\r\n");
+		assert_eq!(framer.next_line(), Some(TooLong))
+		assert_eq!(framer.next_line(), Some(Line(b"PING x".to_vec())))
+		let mut tagged = b"@".to_vec();
+		tagged.push_all(Vec::from_elem(1000, b't').as_slice());
+		tagged.push(b' ');
+		tagged.push_all(max.as_slice());
+		framer.feed(tagged.as_slice());
+		framer.feed(b"\n");
+		assert_eq!(framer.next_line(), Some(Line(tagged)))
+		// Lines without terminator are dropped once they cannot fit anymore
+		framer.feed(Vec::from_elem(10000, b'a').as_slice());
+		assert_eq!(framer.next_line(), Some(TooLong))
+		framer.feed(b"aaaa\r\nPING y\r\n");
+		assert_eq!(framer.next_line(), Some(Line(b"PING y".to_vec())))
+	}
+}
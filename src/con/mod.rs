@@ -1,65 +1,198 @@
-use std::io::{TcpStream, BufferedReader, BufferedWriter};
-use std::io::{IoResult};
-use std::io::net::ip::{Ipv4Addr, Ipv6Addr};
+use std::io::net::ip::{IpAddr, SocketAddr};
 use std::rand::{random};
 use std::fmt::{Show, Formatter, Error};
+use std::sync::{Arc};
 
 pub use self::client::{UserInfo, SharedInfo, Peer};
 pub use self::client::flag as reg;
+pub use self::tls::{TlsContext};
+pub use self::reactor::{Reactor};
+#[cfg(test)]
+pub use self::reactor::{Outgoing, detached_connection};
+pub use self::sendq::{SendQueue, SENDQ_EXCEEDED};
 
 use msg::{RawMessage};
 use msg;
+use config::{FloodConfig};
+use sasl;
+use self::flood::{TokenBucket};
+use self::framer::{LineFramer, Line, TooLong};
 
-//use cmd::{Command, REPLY, ResponseCode};
+use time;
+
+use cmd::{REPLY, ERR_INPUTTOOLONG};
+use msg::tags::{MAX_TAG_DATA_LENGTH};
 use server;
 
 mod client;
+mod flood;
+mod framer;
 mod net;
+mod reactor;
+mod sendq;
+mod tls;
 
 /// Unique client id
-#[deriving(Hash)]
+///
+/// The id consists of the full address of the peer (IPv4 or IPv6) and
+/// a random nonce to distinguish subsequent connections from the same port.
+#[deriving(Hash, PartialEq, Eq, Clone)]
 pub struct PeerId {
-    id: [u64, ..2]
+    ip: IpAddr,
+    port: u16,
+    nonce: u64
 }
 
 impl PeerId {
     /// The client id is losely inspired by SILC but the silc
     /// method of also using the nickname for this is not applicable to IRC
-    fn new(stream: &mut TcpStream) -> PeerId {
+    fn new(peer_name: SocketAddr) -> PeerId {
         PeerId { 
-            id: [
-                match stream.socket_name().unwrap().ip {
-                    Ipv4Addr(a, b, c, d) => a as u32 <<24 | b as u32<<16 | c as u32<<8 | d as u32,
-                    Ipv6Addr(_, _, _, _, _, _, a, b) => a as u32 << 16 | b as u32 
-                } as u64 << 32
-                | match stream.peer_name().unwrap().ip {
-                    Ipv4Addr(a, b, c, d) => a as u32 <<24 | b as u32 <<16 | c as u32 <<8 | d as u32,
-                    Ipv6Addr(_, _, _, _, _, _, a, b) => a as u32  << 16 | b as u32  
-                } as u64, 
-                random()
-            ]
+            ip: peer_name.ip,
+            port: peer_name.port,
+            nonce: random()
         }
     }
+    
+    /// Address of the peer
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr { ip: self.ip, port: self.port }
+    }
 }
 
-impl PartialEq for PeerId {
-    #[inline]
-    fn eq(&self, other: &PeerId) -> bool {
-        self.id == other.id
+impl Show for PeerId {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{}/{:x}", self.address(), self.nonce)
     }
 }
 
-impl Eq for PeerId {}
+/// Delivers messages to a peer
+#[deriving(Clone)]
+pub struct Outbox {
+    handle: reactor::Handle,
+    token: reactor::Token,
+    queue: Arc<SendQueue>
+}
 
-impl Show for PeerId {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        write!(fmt, "{:x}{:x}", self.id[0], self.id[1])
+impl Outbox {
+    /// Queues a message
+    ///
+    /// The message is dropped if the send queue of the peer is full.
+    pub fn send(&self, msg: RawMessage) {
+        if !self.queue.push(msg.wire_len()) {
+            return
+        }
+        self.handle.send_msg(self.token, msg)
     }
+    
+    /// Closes the connection
+    pub fn close(&self) {
+        self.handle.close(self.token)
+    }
+    
+    /// Send queue of the peer
+    pub fn queue(&self) -> &SendQueue {
+        &*self.queue
+    }
+}
+
+/// Turns the bytes received from a peer into messages for the server
+struct Input {
+    id: PeerId,
+    info: SharedInfo,
+    hostname: String,
+    outbox: Outbox,
+    framer: LineFramer,
+    bucket: TokenBucket,
+    flood: FloodConfig,
+    exempt_host: bool,
+    /// Set once the server was asked to disconnect the peer
+    closed: bool,
+    tx: Sender<server::Event>
 }
 
-impl Clone for PeerId {
-    fn clone(&self) -> PeerId {
-        PeerId { id: [ self.id[0], self.id[1] ] }
+impl Input {
+    fn new(id: PeerId, info: SharedInfo, hostname: String, outbox: Outbox,
+           flood: FloodConfig, tx: Sender<server::Event>) -> Input {
+        Input {
+            id: id,
+            info: info,
+            exempt_host: flood.is_exempt_host(hostname.as_slice()),
+            hostname: hostname,
+            outbox: outbox,
+            framer: LineFramer::new(),
+            bucket: TokenBucket::new(&flood, time::precise_time_s()),
+            flood: flood,
+            closed: false,
+            tx: tx
+        }
+    }
+    
+    /// Replaces the host name of the peer
+    fn set_hostname(&mut self, hostname: String) {
+        self.exempt_host = self.flood.is_exempt_host(hostname.as_slice());
+        self.info.write().set_hostname(hostname.clone());
+        self.hostname = hostname
+    }
+    
+    /// Handles received bytes
+    ///
+    /// Flooding peers are disconnected by the server such that they are told
+    /// why, everything they send afterwards is ignored.
+    fn feed(&mut self, bytes: &[u8]) {
+        if self.closed {
+            return
+        }
+        self.framer.feed(bytes);
+        while let Some(frame) = self.framer.next_line() {
+            // Rate limit before the message is handled
+            if !self.exempt_host && !(self.flood.exempt_opers && self.info.read().is_oper())
+               && !self.bucket.take(time::precise_time_s()) {
+                self.closed = true;
+                self.tx.send(server::Disconnected(self.id, "Excess Flood".to_string()));
+                return
+            }
+            let line = match frame {
+                Line(line) => line,
+                TooLong => {
+                    let info = self.info.read();
+                    self.outbox.send(RawMessage::new(REPLY(ERR_INPUTTOOLONG), 
+                        &[info.nick().as_slice(), "Input line was too long"],
+                        Some(info.server_name().as_slice())
+                    ));
+                    continue
+                }
+            };
+            match RawMessage::parse(line.as_slice()) {
+                Ok(raw) if raw.tags_len() > MAX_TAG_DATA_LENGTH => {
+                    let info = self.info.read();
+                    self.outbox.send(RawMessage::new(REPLY(ERR_INPUTTOOLONG), 
+                        &[info.nick().as_slice(), "Input line was too long"],
+                        Some(info.server_name().as_slice())
+                    ));
+                },
+                Ok(mut raw) => {
+                    // Only client-only tags are relayed, and only if the
+                    // client negotiated them
+                    if self.info.read().has_capability(reg::MessageTags) {
+                        raw.retain_client_tags()
+                    } else {
+                        raw = raw.without_tags()
+                    }
+                    debug!("received message {}", raw.to_string());
+                    match msg::get_handler(raw) {
+                        Ok(handler) => self.tx.send(server::MessageReceived(self.id, handler)),
+                        Err(Some(mut err_msg)) => {
+                            err_msg.set_prefix(self.hostname.as_slice());
+                            self.outbox.send(err_msg)
+                        },
+                        Err(None) => {} // Ingore error
+                        
+                    }
+                },
+                Err(_) => {}
+            }
+        }
     }
 }
 
@@ -67,77 +200,73 @@ impl Clone for PeerId {
 pub struct Connection {
     id: PeerId,
     peer: Peer,
-    stream: TcpStream,
+    outbox: Outbox,
+    /// Time of the last message received from the peer
+    last_activity: i64,
+    /// Time the last unanswered PING was sent
+    ping_sent: Option<i64>,
+    /// SASL authentication in progress
+    sasl: Option<sasl::Session>,
+    /// Number of failed SASL authentications
+    sasl_failures: uint,
 }
 
 
 impl Connection {
-    /// Spawns two threads for communication with the client
-    /// Returns a SharedClient instance.
-    /// TODO handle failures
-    pub fn listen(server_host: String, mut stream: TcpStream, 
-                         tx: Sender<server::Event>) -> IoResult<()> {
-        let (msg_tx, rx) = channel();
-        let err_tx = msg_tx.clone();
-        let peer_name = try!(stream.peer_name());
-        let id = PeerId::new(&mut stream);
-        let hostname = self::net::get_nameinfo(peer_name);
-        debug!("hostname of client is {}", hostname.clone())
-        let peer = Peer::new(
-            UserInfo::new(id, server_host, hostname.clone()),
-            msg_tx,
-        );
-        let receiving_stream = stream.clone();
-        let id = peer.id();
-        // this has to be sended first otherwise we have a nice race conditions
-        tx.send(server::Connected(Connection {
-            id: id,
-            peer: peer.clone(),
-            stream: stream.clone(),
-            
-        }));
-        spawn(proc() {
-            // TODO: write a proper 510 char line iterator
-            // as it is now it is probably very slow
-            // TODO handle failures properly, send QUIT
-            for line in BufferedReader::new(receiving_stream).lines() {
-                match RawMessage::parse(line.unwrap().as_slice()
-                .trim_right().as_bytes()) {
-                    Ok(raw) => {
-                        debug!("received message {}", raw.to_string());
-                        match msg::get_handler(raw) {
-                            Ok(handler) => tx.send(server::MessageReceived(id, handler)),
-                            Err(Some(mut err_msg)) => {
-                                err_msg.set_prefix(hostname.as_slice());
-                                err_tx.send(err_msg)
-                            },
-                            Err(None) => {} // Ingore error
-                            
-                        }
-                    },
-                    Err(_) => {}
-                }
-            }
-        });
-        spawn(proc() {
-            // TODO: socket timeout
-            // implement when pings are send out
-            // TODO handle failures properly, send QUIT
-            let mut output_stream = BufferedWriter::new(stream);
-            for message in rx.iter() {
-                debug!("sending message {}", message.to_string());
-                output_stream.write(message.as_slice()).unwrap();
-                output_stream.write(b"\r\n").unwrap();
-                output_stream.flush().unwrap();
-            }
-        });
-        Ok(())
+    fn new(peer: Peer, outbox: Outbox) -> Connection {
+        Connection {
+            id: peer.id(),
+            peer: peer,
+            outbox: outbox,
+            last_activity: time::get_time().sec,
+            ping_sent: None,
+            sasl: None,
+            sasl_failures: 0,
+        }
     }
     
     /// Closes the connection to the client
     pub fn close(&mut self) {
-        let _ = self.stream.close_read();
-        let _ = self.stream.close_write();
+        self.outbox.close()
+    }
+    
+    /// Send queue of the peer
+    pub fn sendq(&self) -> &SendQueue {
+        self.outbox.queue()
+    }
+    
+    /// Records that a message has been received at time `now`
+    ///
+    /// Every message shows that the peer is still alive, this also
+    /// resets a pending PING.
+    pub fn touch(&mut self, now: i64) {
+        self.last_activity = now;
+        self.ping_sent = None;
+    }
+    
+    /// Time of the last message received from the peer
+    pub fn last_activity(&self) -> i64 {
+        self.last_activity
+    }
+    
+    /// Time the pending PING was sent
+    pub fn ping_sent(&self) -> Option<i64> {
+        self.ping_sent
+    }
+    
+    /// Sets the time the pending PING was sent
+    pub fn set_ping_sent(&mut self, now: i64) {
+        self.ping_sent = Some(now)
+    }
+    
+    /// SASL authentication in progress
+    pub fn sasl(&mut self) -> &mut Option<sasl::Session> {
+        &mut self.sasl
+    }
+    
+    /// Number of failed SASL authentications
+    pub fn sasl_failures(&mut self) -> &mut uint {
+        &mut self.sasl_failures
     }
     
     /// Accessor for the client id
@@ -1,6 +1,9 @@
 use std::io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
 use libc::{size_t, malloc, sockaddr, sockaddr_in, sockaddr_in6, in_addr, in6_addr, c_int, c_char, socklen_t, AF_INET, AF_INET6};
+use libc::{sockaddr_storage};
 use std::mem::{size_of, transmute};
+use std::mem;
+use std::num::{Int};
 use std::string;
 
 /*
@@ -76,20 +79,22 @@ pub fn get_nameinfo(peer_socket: SocketAddr) -> String {
         match ip {
             Ipv4Addr(a, b, c, d) => {
                 let addr = in_addr {
-                    s_addr: a as u32 << 24 
-                          | b as u32 << 16 
-                          | c as u32 << 8 
-                          | d as u32
+                    s_addr: (a as u32 << 24 
+                           | b as u32 << 16 
+                           | c as u32 << 8 
+                           | d as u32).to_be()
                 };
-                let sockaddr = new_sockaddr_in(port, addr);
+                let sockaddr = new_sockaddr_in(port.to_be(), addr);
                 getnameinfo(transmute(&sockaddr), size_of::<sockaddr_in>() as socklen_t, 
                             buf, hostlen, transmute(0u), 0, 0)
             },
             Ipv6Addr(a, b, c, d, e, f, g, h) => {
+                // Both the address and the port have to be in network byte order
                 let addr = in6_addr {
-                    s6_addr: [a, b, c, d, e, f, g, h]
+                    s6_addr: [a.to_be(), b.to_be(), c.to_be(), d.to_be(),
+                              e.to_be(), f.to_be(), g.to_be(), h.to_be()]
                 };
-                let sockaddr = new_sockaddr_in6(port, addr);
+                let sockaddr = new_sockaddr_in6(port.to_be(), addr);
                 getnameinfo(transmute(&sockaddr), size_of::<sockaddr_in6>() as socklen_t, 
                             buf, hostlen, transmute(0u), 0, 0)
             },
@@ -99,3 +104,76 @@ pub fn get_nameinfo(peer_socket: SocketAddr) -> String {
     unsafe {string::raw::from_buf(transmute(buf))}
 
 }
+
+/// Converts an address into a socket address usable with the libc functions
+pub fn to_sockaddr(address: SocketAddr) -> (sockaddr_storage, socklen_t) {
+    let SocketAddr { ip, port } = address;
+    let mut storage: sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match ip {
+        Ipv4Addr(a, b, c, d) => {
+            let addr = in_addr {
+                s_addr: (a as u32 << 24 
+                       | b as u32 << 16 
+                       | c as u32 << 8 
+                       | d as u32).to_be()
+            };
+            unsafe {
+                *transmute::<&mut sockaddr_storage, &mut sockaddr_in>(&mut storage) =
+                    new_sockaddr_in(port.to_be(), addr);
+            }
+            size_of::<sockaddr_in>()
+        },
+        Ipv6Addr(a, b, c, d, e, f, g, h) => {
+            let addr = in6_addr {
+                s6_addr: [a.to_be(), b.to_be(), c.to_be(), d.to_be(),
+                          e.to_be(), f.to_be(), g.to_be(), h.to_be()]
+            };
+            unsafe {
+                *transmute::<&mut sockaddr_storage, &mut sockaddr_in6>(&mut storage) =
+                    new_sockaddr_in6(port.to_be(), addr);
+            }
+            size_of::<sockaddr_in6>()
+        }
+    };
+    (storage, len as socklen_t)
+}
+
+/// Converts a socket address returned by libc into an address
+pub fn from_sockaddr(storage: &sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as c_int {
+        AF_INET => {
+            let addr: &sockaddr_in = unsafe { transmute(storage) };
+            let ip: u32 = Int::from_be(addr.sin_addr.s_addr);
+            Some(SocketAddr {
+                ip: Ipv4Addr((ip >> 24) as u8, (ip >> 16) as u8, (ip >> 8) as u8, ip as u8),
+                port: Int::from_be(addr.sin_port)
+            })
+        },
+        AF_INET6 => {
+            let addr: &sockaddr_in6 = unsafe { transmute(storage) };
+            let a: Vec<u16> = addr.sin6_addr.s6_addr.iter().map(|&v| Int::from_be(v)).collect();
+            Some(SocketAddr {
+                ip: Ipv6Addr(a[0], a[1], a[2], a[3], a[4], a[5], a[6], a[7]),
+                port: Int::from_be(addr.sin6_port)
+            })
+        },
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{to_sockaddr, from_sockaddr};
+	use std::io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+	/// Test the conversion from and to socket addresses
+	#[test]
+	fn test_sockaddr() {
+		for &address in [
+			SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 6667 },
+			SocketAddr { ip: Ipv6Addr(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), port: 6697 }
+		].iter() {
+			let (storage, _) = to_sockaddr(address);
+			assert_eq!(from_sockaddr(&storage), Some(address))
+		}
+	}
+}
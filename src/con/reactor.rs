@@ -0,0 +1,810 @@
+//! Event loop serving all connections
+//!
+//! The sockets are non-blocking and polled with epoll from a single thread.
+//! Outgoing messages are passed to the loop through a command queue, an
+//! eventfd is used to wake it up. TLS connections are encrypted by OpenSSL
+//! sessions which the loop passes the data of the socket to, the server
+//! only learns about them once their handshake is complete.
+
+use std::collections::{HashMap};
+use std::io::{IoResult, IoError};
+use std::io::net::ip::{SocketAddr};
+use std::mem::{size_of};
+use std::mem;
+use std::os;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, SeqCst};
+use libc::{c_int, c_uint, c_void, size_t, socklen_t, sockaddr, sockaddr_storage, AF_INET6};
+use libc;
+
+use config::{FloodConfig};
+use msg::{RawMessage};
+use server;
+
+use time;
+
+use super::{Connection, Outbox, Input, PeerId, Peer, UserInfo, SendQueue};
+use super::net;
+use super::tls::{TlsContext, Session, HANDSHAKE_TIMEOUT};
+use super::tls::{Progress, Done, WantRead, WantWrite, Closed};
+
+use self::Command::{Write, Close, Resolved};
+
+/// Identifies a socket registered with the event loop
+///
+/// Tokens are never reused, a stale token simply does not match anymore.
+pub type Token = u64;
+
+static WAKER: Token = 0;
+static MAX_EVENTS: uint = 256;
+static READ_BUFFER_SIZE: uint = 4096;
+static LISTEN_BACKLOG: c_int = 128;
+/// Milliseconds between checks for expired deadlines
+static SWEEP_INTERVAL: c_int = 1000;
+/// Seconds a closed socket has to send its remaining output
+static CLOSE_TIMEOUT: i64 = 10;
+/// Threads resolving host names, a slow lookup only blocks one of them
+static RESOLVER_THREADS: uint = 4;
+
+static EPOLLIN: u32 = 0x001;
+static EPOLLOUT: u32 = 0x004;
+static EPOLLERR: u32 = 0x008;
+static EPOLLHUP: u32 = 0x010;
+static EPOLL_CTL_ADD: c_int = 1;
+static EPOLL_CTL_DEL: c_int = 2;
+static EPOLL_CTL_MOD: c_int = 3;
+static EPOLL_CLOEXEC: c_int = 0o2000000;
+static EFD_NONBLOCK: c_int = 0o4000;
+static EFD_CLOEXEC: c_int = 0o2000000;
+static SOCK_STREAM: c_int = 1;
+static SOCK_NONBLOCK: c_int = 0o4000;
+static SOCK_CLOEXEC: c_int = 0o2000000;
+static SOL_SOCKET: c_int = 1;
+static SO_REUSEADDR: c_int = 2;
+static IPPROTO_IPV6: c_int = 41;
+static IPV6_V6ONLY: c_int = 26;
+static MSG_NOSIGNAL: c_int = 0x4000;
+static EINTR: c_int = 4;
+static EAGAIN: c_int = 11;
+
+#[repr(C, packed)]
+struct EpollEvent {
+    events: u32,
+    data: u64
+}
+
+extern {
+    fn epoll_create1(flags: c_int) -> c_int;
+    fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+    fn epoll_wait(epfd: c_int, events: *mut EpollEvent,
+                  maxevents: c_int, timeout: c_int) -> c_int;
+    fn eventfd(initval: c_uint, flags: c_int) -> c_int;
+    fn accept4(fd: c_int, addr: *mut sockaddr, len: *mut socklen_t, flags: c_int) -> c_int;
+}
+
+/// Turns the return value of a libc call into a result
+fn check(ret: c_int) -> IoResult<c_int> {
+    if ret < 0 { Err(IoError::last_error()) } else { Ok(ret) }
+}
+
+/// Returns the error code of the last failed libc call
+fn errno() -> c_int {
+    os::errno() as c_int
+}
+
+/// Commands for the event loop
+enum Command {
+    /// Sends a message to the socket
+    Write(Token, RawMessage),
+    /// Closes the socket
+    Close(Token),
+    /// Replaces the address of the peer by its host name
+    Resolved(Token, String),
+}
+
+/// Wakes up the event loop
+struct Waker {
+    fd: c_int,
+    /// Set if a wake up is already pending
+    pending: AtomicBool
+}
+
+impl Waker {
+    fn wake(&self) {
+        if !self.pending.swap(true, SeqCst) {
+            let one = 1u64;
+            unsafe { libc::write(self.fd, &one as *const u64 as *const c_void, 8) };
+        }
+    }
+
+    /// Has to be called before the commands are processed
+    fn reset(&self) {
+        let mut count = 0u64;
+        unsafe { libc::read(self.fd, &mut count as *mut u64 as *mut c_void, 8) };
+        self.pending.store(false, SeqCst)
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Handle to pass commands to the event loop
+#[deriving(Clone)]
+pub struct Handle {
+    tx: Sender<Command>,
+    waker: Arc<Waker>
+}
+
+impl Handle {
+    fn send(&self, command: Command) {
+        if self.tx.send_opt(command).is_ok() {
+            self.waker.wake()
+        }
+    }
+
+    /// Queues a message for the socket
+    pub fn send_msg(&self, token: Token, msg: RawMessage) {
+        self.send(Write(token, msg))
+    }
+
+    /// Closes the socket after the queued messages have been sent
+    pub fn close(&self, token: Token) {
+        self.send(Close(token))
+    }
+}
+
+/// Reads from a socket
+fn read_socket(fd: c_int, buf: &mut [u8]) -> IoResult<Progress> {
+    loop {
+        let n = unsafe {
+            libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t)
+        };
+        if n > 0 {
+            return Ok(Done(n as uint))
+        } else if n == 0 {
+            return Ok(Closed)
+        } else if errno() == EAGAIN {
+            return Ok(WantRead)
+        } else if errno() != EINTR {
+            return Err(IoError::last_error())
+        }
+    }
+}
+
+/// Writes to a socket
+fn write_socket(fd: c_int, buf: &[u8]) -> IoResult<Progress> {
+    loop {
+        let n = unsafe {
+            libc::send(fd, buf.as_ptr() as *const c_void, buf.len() as size_t, MSG_NOSIGNAL)
+        };
+        if n >= 0 {
+            return Ok(Done(n as uint))
+        } else if errno() == EAGAIN {
+            return Ok(WantWrite)
+        } else if errno() != EINTR {
+            return Err(IoError::last_error())
+        }
+    }
+}
+
+/// Writes as much of `buf` as the socket accepts and removes it from `buf`
+///
+/// Returns the number of bytes written.
+fn send_buffer(fd: c_int, buf: &mut Vec<u8>) -> IoResult<uint> {
+    let mut written = 0;
+    while written < buf.len() {
+        match try!(write_socket(fd, buf.slice_from(written))) {
+            Done(n) => written += n,
+            // Pending output is polled for anyway
+            _ => break
+        }
+    }
+    if written == buf.len() {
+        buf.clear()
+    } else if written > 0 {
+        *buf = buf.slice_from(written).to_vec()
+    }
+    Ok(written)
+}
+
+/// Client socket served by the event loop
+struct Socket {
+    fd: c_int,
+    id: PeerId,
+    hostname: String,
+    /// Session of TLS connections
+    tls: Option<Session>,
+    /// Set once the connection is established, TLS connections have to
+    /// finish their handshake first
+    input: Option<Input>,
+    outbox: Outbox,
+    /// Data which could not be written yet
+    output: Vec<u8>,
+    /// Encrypted data of TLS connections which could not be written yet
+    sealed: Vec<u8>,
+    queue: Arc<SendQueue>,
+    /// Events the socket is polled for
+    events: u32,
+    /// Time until which the TLS handshake has to finish
+    handshake: Option<i64>,
+    /// Time until which a closed socket may send its remaining output
+    closing: Option<i64>
+}
+
+impl Socket {
+    /// Passes all encrypted data available on the socket to the TLS session
+    ///
+    /// Returns false if the peer closed the connection.
+    fn fill(&mut self, buf: &mut [u8]) -> IoResult<bool> {
+        let Socket { fd, ref mut tls, .. } = *self;
+        if let Some(ref mut session) = *tls {
+            loop {
+                match try!(read_socket(fd, buf)) {
+                    Done(n) => session.receive(buf.slice_to(n)),
+                    Closed => return Ok(false),
+                    _ => break
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads plain text, TLS connections decrypt what was passed to them
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<Progress> {
+        match self.tls {
+            Some(ref mut session) => session.read(buf),
+            None => read_socket(self.fd, buf)
+        }
+    }
+
+    /// Writes as much of the pending output as the socket accepts
+    fn flush(&mut self) -> IoResult<()> {
+        let Socket { fd, ref mut tls, ref mut output, ref mut sealed, ref queue, .. } = *self;
+        match *tls {
+            Some(ref mut session) => {
+                // Handshake messages and alerts go first
+                session.take_output(sealed);
+                try!(send_buffer(fd, sealed));
+                // Output is only encrypted once the data encrypted before is
+                // sent, until then it counts towards the send queue
+                if sealed.is_empty() && !output.is_empty() {
+                    try!(session.write(output.as_slice()));
+                    queue.pop(output.len());
+                    output.clear();
+                    session.take_output(sealed);
+                    try!(send_buffer(fd, sealed));
+                }
+            },
+            None => {
+                let written = try!(send_buffer(fd, output));
+                queue.pop(written)
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether output is waiting for the socket
+    fn has_output(&self) -> bool {
+        !self.output.is_empty() || !self.sealed.is_empty()
+    }
+
+    /// Events the socket has to be polled for
+    ///
+    /// Closed sockets are only polled for their output.
+    fn wanted_events(&self) -> u32 {
+        let mut events = 0;
+        if self.closing.is_none() {
+            events |= EPOLLIN
+        }
+        if self.has_output() {
+            events |= EPOLLOUT
+        }
+        events
+    }
+}
+
+/// Binds a non-blocking listening socket
+fn bind(address: SocketAddr) -> IoResult<c_int> {
+    fn set_option(fd: c_int, level: c_int, name: c_int, value: c_int) -> IoResult<c_int> {
+        check(unsafe { libc::setsockopt(
+            fd, level, name, &value as *const c_int as *const c_void,
+            size_of::<c_int>() as socklen_t
+        ) })
+    }
+    let (storage, len) = net::to_sockaddr(address);
+    let family = storage.ss_family as c_int;
+    let fd = try!(check(unsafe {
+        libc::socket(family, SOCK_STREAM | SOCK_NONBLOCK | SOCK_CLOEXEC, 0)
+    }));
+    let result = set_option(fd, SOL_SOCKET, SO_REUSEADDR, 1).and_then(|_| {
+        // Allows separate listeners for IPv4 and IPv6 on the same port
+        if family == AF_INET6 {
+            set_option(fd, IPPROTO_IPV6, IPV6_V6ONLY, 1)
+        } else {
+            Ok(0)
+        }
+    }).and_then(|_| check(unsafe {
+        libc::bind(fd, &storage as *const sockaddr_storage as *const sockaddr, len)
+    })).and_then(|_| check(unsafe {
+        libc::listen(fd, LISTEN_BACKLOG)
+    }));
+    match result {
+        Ok(_) => Ok(fd),
+        Err(err) => {
+            unsafe { libc::close(fd) };
+            Err(err)
+        }
+    }
+}
+
+/// Registers or modifies the events a file descriptor is polled for
+fn control(epoll: c_int, op: c_int, fd: c_int, token: Token, events: u32) -> IoResult<()> {
+    let mut event = EpollEvent { events: events, data: token };
+    check(unsafe { epoll_ctl(epoll, op, fd, &mut event) }).map(|_| ())
+}
+
+/// Event loop
+pub struct Reactor {
+    epoll: c_int,
+    handle: Handle,
+    commands: Receiver<Command>,
+    /// Listening sockets, the token of a listener is its index plus one
+    listeners: Vec<(c_int, Option<TlsContext>)>,
+    sockets: HashMap<Token, Socket>,
+    next_token: Token,
+    host: String,
+    flood: FloodConfig,
+    sendq: uint,
+    server_tx: Sender<server::Event>,
+    resolver: Sender<(Token, SocketAddr)>
+}
+
+impl Reactor {
+    /// Creates the event loop and binds the listeners for `addresses`
+    ///
+    /// Connections to listeners with a TLS context are encrypted.
+    pub fn new(addresses: &[(SocketAddr, Option<TlsContext>)], host: String,
+               flood: FloodConfig, sendq: uint,
+               server_tx: Sender<server::Event>) -> IoResult<Reactor> {
+        let epoll = try!(check(unsafe { epoll_create1(EPOLL_CLOEXEC) }));
+        let waker = try!(check(unsafe { eventfd(0, EFD_NONBLOCK | EFD_CLOEXEC) }));
+        try!(control(epoll, EPOLL_CTL_ADD, waker, WAKER, EPOLLIN));
+        let (tx, rx) = channel();
+        let handle = Handle {
+            tx: tx,
+            waker: Arc::new(Waker { fd: waker, pending: AtomicBool::new(false) })
+        };
+        let mut listeners = Vec::new();
+        for &(address, ref tls) in addresses.iter() {
+            let fd = try!(bind(address));
+            listeners.push((fd, tls.clone()));
+            try!(control(epoll, EPOLL_CTL_ADD, fd, listeners.len() as Token, EPOLLIN));
+        }
+        // Host names are resolved by other threads to keep the loop responsive
+        let (resolver, requests) = channel();
+        let requests = Arc::new(Mutex::new(requests));
+        for _ in range(0, RESOLVER_THREADS) {
+            let requests = requests.clone();
+            let resolver_handle = handle.clone();
+            spawn(proc() {
+                loop {
+                    let request = requests.lock().recv_opt();
+                    match request {
+                        Ok((token, address)) => resolver_handle.send(
+                            Resolved(token, net::get_nameinfo(address))
+                        ),
+                        Err(_) => break
+                    }
+                }
+            });
+        }
+        Ok(Reactor {
+            epoll: epoll,
+            handle: handle,
+            commands: rx,
+            next_token: listeners.len() as Token + 1,
+            listeners: listeners,
+            sockets: HashMap::new(),
+            host: host,
+            flood: flood,
+            sendq: sendq,
+            server_tx: server_tx,
+            resolver: resolver
+        })
+    }
+
+    /// Runs the event loop
+    pub fn run(mut self) {
+        let mut events = Vec::from_fn(MAX_EVENTS, |_| EpollEvent { events: 0, data: 0 });
+        let mut last_sweep = time::get_time().sec;
+        loop {
+            let n = unsafe {
+                epoll_wait(self.epoll, events.as_mut_ptr(), MAX_EVENTS as c_int, SWEEP_INTERVAL)
+            };
+            if n < 0 {
+                if errno() == EINTR {
+                    continue
+                }
+                error!("event loop failed: {}", IoError::last_error());
+                break
+            }
+            for event in events.slice_to(n as uint).iter() {
+                let (token, flags) = (event.data, event.events);
+                if token == WAKER {
+                    self.handle.waker.reset()
+                } else if token <= self.listeners.len() as Token {
+                    self.accept(token)
+                } else {
+                    self.ready(token, flags)
+                }
+            }
+            self.process_commands();
+            let now = time::get_time().sec;
+            if now != last_sweep {
+                self.sweep(now);
+                last_sweep = now
+            }
+        }
+    }
+
+    /// Removes closed sockets which could not send their output in time and
+    /// TLS connections which did not finish their handshake
+    fn sweep(&mut self, now: i64) {
+        let expired: Vec<(Token, bool)> = self.sockets.iter().filter_map(
+            |(&token, socket)| match (socket.closing, socket.handshake) {
+                (Some(deadline), _) if deadline <= now => Some((token, false)),
+                (None, Some(deadline)) if deadline <= now => Some((token, true)),
+                _ => None
+            }
+        ).collect();
+        for (token, handshake) in expired.into_iter() {
+            let reason = if handshake {
+                Some("TLS handshake timed out".to_string())
+            } else {
+                None
+            };
+            self.remove(token, reason)
+        }
+    }
+
+    /// Accepts all pending connections of a listener
+    fn accept(&mut self, token: Token) {
+        let (listener, tls) = match self.listeners[token as uint - 1] {
+            (fd, ref tls) => (fd, tls.clone())
+        };
+        loop {
+            let mut storage: sockaddr_storage = unsafe { mem::zeroed() };
+            let mut len = size_of::<sockaddr_storage>() as socklen_t;
+            let fd = unsafe { accept4(
+                listener, &mut storage as *mut sockaddr_storage as *mut sockaddr,
+                &mut len, SOCK_NONBLOCK | SOCK_CLOEXEC
+            ) };
+            if fd < 0 {
+                if errno() == EINTR {
+                    continue
+                } else if errno() != EAGAIN {
+                    error!("{}", IoError::last_error())
+                }
+                break
+            }
+            match net::from_sockaddr(&storage) {
+                Some(address) => self.attach(fd, address, tls.clone()),
+                None => unsafe { libc::close(fd); }
+            }
+        }
+    }
+
+    /// Starts serving a socket
+    ///
+    /// The address is used as host name until the lookup finished. TLS
+    /// connections are established once their handshake is complete.
+    fn attach(&mut self, fd: c_int, address: SocketAddr, tls: Option<TlsContext>) {
+        let session = match tls {
+            Some(ctx) => match Session::new(&ctx) {
+                Ok(session) => Some(session),
+                Err(err) => {
+                    error!("cannot start TLS for {}: {}", address, err);
+                    unsafe { libc::close(fd) };
+                    return
+                }
+            },
+            None => None
+        };
+        let token = self.next_token;
+        self.next_token += 1;
+        if let Err(err) = control(self.epoll, EPOLL_CTL_ADD, fd, token, EPOLLIN) {
+            error!("cannot poll connection from {}: {}", address, err);
+            drop(session);
+            unsafe { libc::close(fd) };
+            return
+        }
+        let id = PeerId::new(address);
+        let queue = Arc::new(SendQueue::new(id, self.sendq, self.server_tx.clone()));
+        let secure = session.is_some();
+        self.sockets.insert(token, Socket {
+            fd: fd,
+            id: id,
+            hostname: address.ip.to_string(),
+            tls: session,
+            input: None,
+            outbox: Outbox {
+                handle: self.handle.clone(),
+                token: token,
+                queue: queue.clone()
+            },
+            output: Vec::new(),
+            sealed: Vec::new(),
+            queue: queue,
+            events: EPOLLIN,
+            handshake: if secure {
+                Some(time::get_time().sec + HANDSHAKE_TIMEOUT)
+            } else {
+                None
+            },
+            closing: None
+        });
+        if !secure {
+            self.establish(token, None)
+        }
+        let _ = self.resolver.send_opt((token, address));
+    }
+
+    /// Creates the peer of a socket and tells the server about it
+    ///
+    /// `certfp` is the fingerprint of the TLS client certificate.
+    fn establish(&mut self, token: Token, certfp: Option<String>) {
+        let socket = match self.sockets.get_mut(&token) {
+            Some(socket) => socket,
+            None => return
+        };
+        let mut info = UserInfo::new(
+            socket.id, self.host.clone(), socket.hostname.clone(), socket.tls.is_some()
+        );
+        info.set_certfp(certfp);
+        let peer = Peer::new(info, socket.outbox.clone());
+        socket.input = Some(Input::new(
+            socket.id, peer.info().clone(), socket.hostname.clone(), socket.outbox.clone(),
+            self.flood.clone(), self.server_tx.clone()
+        ));
+        socket.handshake = None;
+        let _ = self.server_tx.send_opt(server::Connected(
+            Connection::new(peer, socket.outbox.clone())
+        ));
+    }
+
+    /// Updates the host name of a socket once it was looked up
+    fn resolved(&mut self, token: Token, hostname: String) {
+        if let Some(socket) = self.sockets.get_mut(&token) {
+            debug!("hostname of client is {}", hostname)
+            if let Some(ref mut input) = socket.input {
+                input.set_hostname(hostname.clone())
+            }
+            socket.hostname = hostname
+        }
+    }
+
+    /// Handles the readiness of a socket
+    ///
+    /// TLS sessions may have to answer what they received, so they are
+    /// always flushed.
+    fn ready(&mut self, token: Token, flags: u32) {
+        let (closing, tls) = match self.sockets.get(&token) {
+            Some(socket) => (socket.closing.is_some(), socket.tls.is_some()),
+            None => return
+        };
+        if closing {
+            // Input of closed sockets is ignored, only their output matters
+            if flags & (EPOLLHUP | EPOLLERR) != 0 {
+                return self.remove(token, None)
+            }
+        } else if flags & (EPOLLIN | EPOLLHUP | EPOLLERR) != 0 {
+            match self.receive(token) {
+                Some(reason) => return self.remove(token, Some(reason)),
+                None => {}
+            }
+        }
+        if tls || flags & EPOLLOUT != 0 {
+            self.flush(token)
+        }
+    }
+
+    /// Continues the TLS handshake of a socket
+    ///
+    /// Returns true once the connection is established and the reason if
+    /// the handshake failed.
+    fn handshake(&mut self, token: Token) -> Result<bool, String> {
+        let fingerprint = {
+            let socket = match self.sockets.get_mut(&token) {
+                Some(socket) => socket,
+                None => return Ok(false)
+            };
+            let session = match socket.tls {
+                Some(ref mut session) => session,
+                None => return Ok(true)
+            };
+            match session.handshake() {
+                Ok(true) => session.fingerprint(),
+                Ok(false) => return Ok(false),
+                Err(err) => return Err(format!("TLS handshake failed: {}", err))
+            }
+        };
+        self.establish(token, fingerprint);
+        Ok(true)
+    }
+
+    /// Reads everything available from a socket
+    ///
+    /// Returns the reason if the connection has to be closed.
+    fn receive(&mut self, token: Token) -> Option<String> {
+        let mut buf = [0u8, ..READ_BUFFER_SIZE];
+        let (open, established) = match self.sockets.get_mut(&token) {
+            Some(socket) => match socket.fill(&mut buf) {
+                Ok(open) => (open, socket.input.is_some()),
+                Err(err) => return Some(format!("Read error: {}", err.desc))
+            },
+            None => return None
+        };
+        if !established {
+            match self.handshake(token) {
+                Ok(true) => {},
+                Ok(false) if open => return None,
+                Ok(false) => return Some("Connection closed".to_string()),
+                Err(reason) => return Some(reason)
+            }
+        }
+        let socket = match self.sockets.get_mut(&token) {
+            Some(socket) => socket,
+            None => return None
+        };
+        loop {
+            match socket.read(&mut buf) {
+                Ok(Done(n)) => match socket.input {
+                    Some(ref mut input) => input.feed(buf.slice_to(n)),
+                    None => return None
+                },
+                Ok(Closed) => return Some("Connection closed".to_string()),
+                // Everything was read, TLS sockets may have seen the end before
+                Ok(_) if open => return None,
+                Ok(_) => return Some("Connection closed".to_string()),
+                Err(err) => return Some(format!("Read error: {}", err.desc))
+            }
+        }
+    }
+
+    /// Writes the pending output of a socket
+    ///
+    /// The socket is polled for writability as long as output is pending.
+    /// A closed socket is removed as soon as its output is sent.
+    fn flush(&mut self, token: Token) {
+        let epoll = self.epoll;
+        let result = match self.sockets.get_mut(&token) {
+            Some(socket) => socket.flush().and_then(|()| {
+                if socket.closing.is_some() && !socket.has_output() {
+                    return Ok(true)
+                }
+                let events = socket.wanted_events();
+                if events != socket.events {
+                    socket.events = events;
+                    try!(control(epoll, EPOLL_CTL_MOD, socket.fd, token, events))
+                }
+                Ok(false)
+            }),
+            None => return
+        };
+        match result {
+            Ok(false) => {},
+            Ok(true) => self.remove(token, None),
+            Err(err) => self.remove(token, Some(format!("Write error: {}", err.desc)))
+        }
+    }
+
+    /// Stops reading from a socket and removes it once its output is sent
+    fn close(&mut self, token: Token) {
+        match self.sockets.get_mut(&token) {
+            Some(socket) => if socket.closing.is_none() {
+                socket.closing = Some(time::get_time().sec + CLOSE_TIMEOUT)
+            },
+            None => return
+        }
+        self.flush(token)
+    }
+
+    /// Closes a socket
+    ///
+    /// If a reason is given the server is told about the disconnect, unless
+    /// the server closed the socket itself or the connection was never
+    /// established.
+    fn remove(&mut self, token: Token, reason: Option<String>) {
+        if let Some(socket) = self.sockets.remove(&token) {
+            unsafe {
+                epoll_ctl(self.epoll, EPOLL_CTL_DEL, socket.fd, ptr::null_mut());
+                libc::close(socket.fd);
+            }
+            if socket.closing.is_some() {
+                return
+            }
+            match (socket.input, reason) {
+                (Some(_), Some(reason)) => {
+                    let _ = self.server_tx.send_opt(server::Disconnected(socket.id, reason));
+                },
+                (None, Some(reason)) => info!(
+                    "connection from {} failed: {}", socket.hostname, reason
+                ),
+                (_, None) => {}
+            }
+        }
+    }
+
+    /// Processes all queued commands
+    ///
+    /// Messages are collected first such that each socket is flushed once.
+    fn process_commands(&mut self) {
+        let mut dirty = Vec::new();
+        loop {
+            match self.commands.try_recv() {
+                Ok(Write(token, msg)) => match self.sockets.get_mut(&token) {
+                    // Nothing is queued after the socket was closed
+                    Some(socket) if socket.closing.is_none() => {
+                        if socket.output.is_empty() {
+                            dirty.push(token)
+                        }
+                        let _ = msg.write_to(&mut socket.output);
+                    },
+                    _ => {}
+                },
+                Ok(Close(token)) => self.close(token),
+                Ok(Resolved(token, hostname)) => self.resolved(token, hostname),
+                Err(_) => break
+            }
+        }
+        for token in dirty.into_iter() {
+            self.flush(token)
+        }
+    }
+}
+
+/// Collects the messages sent to a connection without socket
+#[cfg(test)]
+pub struct Outgoing {
+    rx: Receiver<Command>
+}
+
+#[cfg(test)]
+impl Outgoing {
+    /// Messages sent since the last call
+    pub fn messages(&self) -> Vec<RawMessage> {
+        let mut messages = Vec::new();
+        while let Ok(command) = self.rx.try_recv() {
+            if let Write(_, msg) = command {
+                messages.push(msg)
+            }
+        }
+        messages
+    }
+}
+
+/// Creates a connection without socket and event loop for the tests of
+/// the message handlers
+#[cfg(test)]
+pub fn detached_connection(server_name: &str) -> (Connection, Outgoing) {
+    use std::io::net::ip::{Ipv4Addr};
+    let id = PeerId::new(SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 1 });
+    let (tx, rx) = channel();
+    let (server_tx, _) = channel();
+    let outbox = Outbox {
+        handle: Handle {
+            tx: tx,
+            // Nobody has to be woken up
+            waker: Arc::new(Waker { fd: -1, pending: AtomicBool::new(true) })
+        },
+        token: 0,
+        queue: Arc::new(SendQueue::new(id, 1 << 20, server_tx))
+    };
+    let info = UserInfo::new(id, server_name.to_string(), "127.0.0.1".to_string(), false);
+    let peer = Peer::new(info, outbox.clone());
+    (Connection::new(peer, outbox), Outgoing { rx: rx })
+}
@@ -0,0 +1,143 @@
+//! Accounting of the data queued for a peer
+
+use std::sync::{Mutex};
+use std::sync::atomic::{AtomicUint, AtomicBool, SeqCst};
+
+use server;
+
+use super::{PeerId};
+
+/// Disconnect reason for peers which do not read fast enough
+pub static SENDQ_EXCEEDED: &'static str = "SendQ exceeded";
+
+/// Percentage of the limit above which operators are warned
+pub static HIGH_WATER_MARK: uint = 75;
+
+/// Counts the bytes waiting to be sent to a peer
+///
+/// Producers add the size of each message, the writer removes the bytes
+/// it has written. If the limit is exceeded further messages are dropped
+/// and the server is asked to disconnect the peer.
+pub struct SendQueue {
+    id: PeerId,
+    max: uint,
+    bytes: AtomicUint,
+    exceeded: AtomicBool,
+    /// Set while the queue is above the high-water mark
+    high: AtomicBool,
+    server_tx: Mutex<Sender<server::Event>>
+}
+
+impl SendQueue {
+    /// Creates an empty queue which holds at most `max` bytes
+    pub fn new(id: PeerId, max: uint, server_tx: Sender<server::Event>) -> SendQueue {
+        SendQueue {
+            id: id,
+            max: max,
+            bytes: AtomicUint::new(0),
+            exceeded: AtomicBool::new(false),
+            high: AtomicBool::new(false),
+            server_tx: Mutex::new(server_tx)
+        }
+    }
+
+    /// Accounts for `len` more bytes
+    ///
+    /// Returns false if the message has to be dropped.
+    pub fn push(&self, len: uint) -> bool {
+        if self.exceeded.load(SeqCst) {
+            return false
+        }
+        if self.bytes.fetch_add(len, SeqCst) + len > self.max {
+            self.bytes.fetch_sub(len, SeqCst);
+            if !self.exceeded.swap(true, SeqCst) {
+                let _ = self.server_tx.lock().send_opt(
+                    server::Disconnected(self.id, SENDQ_EXCEEDED.to_string())
+                );
+            }
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Removes `len` bytes which have been written
+    pub fn pop(&self, len: uint) {
+        self.bytes.fetch_sub(len, SeqCst);
+    }
+
+    /// Number of bytes currently queued
+    pub fn len(&self) -> uint {
+        self.bytes.load(SeqCst)
+    }
+
+    /// Maximum number of bytes
+    pub fn max(&self) -> uint {
+        self.max
+    }
+
+    /// Checks whether the queue just went above the high-water mark
+    ///
+    /// Returns true only once until the queue drained below half of its
+    /// limit again.
+    pub fn reached_high_water(&self) -> bool {
+        let len = self.len();
+        if len * 100 >= self.max * HIGH_WATER_MARK {
+            !self.high.swap(true, SeqCst)
+        } else {
+            if len * 2 < self.max {
+                self.high.store(false, SeqCst)
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{SendQueue};
+	use super::super::{PeerId};
+	use server;
+	use std::io::net::ip::{SocketAddr, Ipv4Addr};
+	/// Test the byte accounting
+	#[test]
+	fn test_sendq() {
+		let (tx, rx) = channel();
+		let id = PeerId::new(SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 1 });
+		let queue = SendQueue::new(id, 10, tx);
+		assert!(queue.push(6))
+		assert!(queue.push(4))
+		assert_eq!(queue.len(), 10)
+		assert!(!queue.push(1))
+		assert!(match rx.try_recv() {
+			Ok(server::Disconnected(_, ref reason)) => reason.as_slice() == "SendQ exceeded",
+			_ => false
+		})
+		// Everything is dropped after the limit was hit once
+		queue.pop(10);
+		assert!(!queue.push(1))
+		assert!(rx.try_recv().is_err())
+	}
+	/// Test the high-water mark
+	#[test]
+	fn test_high_water() {
+		let (tx, _rx) = channel();
+		let id = PeerId::new(SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 1 });
+		let queue = SendQueue::new(id, 100, tx);
+		assert!(queue.push(70))
+		assert!(!queue.reached_high_water())
+		assert!(queue.push(10))
+		assert!(queue.reached_high_water())
+		assert!(!queue.reached_high_water())
+		// Warned again only after the queue drained
+		queue.pop(20);
+		assert!(!queue.reached_high_water())
+		queue.pop(20);
+		assert!(queue.push(40))
+		assert!(!queue.reached_high_water())
+		queue.pop(50);
+		assert!(!queue.reached_high_water())
+		assert!(queue.push(50))
+		assert!(queue.reached_high_water())
+	}
+}
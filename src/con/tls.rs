@@ -0,0 +1,333 @@
+//! TLS sessions for the event loop
+//!
+//! `SslStream` of the openssl crate performs the handshake in a blocking
+//! call, so the sessions use the bindings of the crate directly. Like the
+//! crate they keep the encrypted data in memory buffers: the event loop
+//! passes what it read from the socket to the session and writes out what
+//! the session produced. The session itself never touches the socket.
+
+use std::io::{IoResult, IoError, OtherIoError};
+use std::sync::{Arc};
+use libc::{c_int, c_uint, c_void};
+
+use openssl::ffi;
+use openssl::ssl::{SslContext, Sslv23};
+use openssl::ssl::error::{SslError};
+use serialize::hex::{ToHex};
+
+pub use self::Progress::*;
+
+/// Timeout in seconds for the TLS handshake
+pub static HANDSHAKE_TIMEOUT: i64 = 10;
+
+static SHA256_LENGTH: uint = 32;
+static BUFFER_SIZE: uint = 4096;
+
+/// Takes the errors from the OpenSSL error queue
+fn ssl_error(desc: &'static str) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: Some(format!("{}", SslError::get()))
+    }
+}
+
+/// Removes errors left by earlier operations from the error queue
+fn clear_errors() {
+    let _ = SslError::get();
+}
+
+/// Accepts every client certificate
+///
+/// Certificates are only used to identify users by their fingerprint, they
+/// do not have to be signed by a trusted authority.
+extern "C" fn accept_any_certificate(_: c_int, _: *mut ffi::X509_STORE_CTX) -> c_int {
+    1
+}
+
+/// Owns an OpenSSL context
+struct Context {
+    ctx: *mut ffi::SSL_CTX
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe { ffi::SSL_CTX_free(self.ctx) }
+    }
+}
+
+/// Server side TLS context, shared by all connections of a listener
+#[deriving(Clone)]
+pub struct TlsContext {
+    ctx: Arc<Context>
+}
+
+impl TlsContext {
+    /// Creates a new context from PEM encoded certificate and key files
+    pub fn new(certificate: &Path, key: &Path) -> IoResult<TlsContext> {
+        // The crate initializes the library including the locking callbacks
+        // OpenSSL needs to be used from several threads
+        if let Err(_) = SslContext::new(Sslv23) {
+            return Err(ssl_error("cannot initialize OpenSSL"))
+        }
+        let ctx = unsafe { ffi::SSL_CTX_new(ffi::SSLv23_method()) };
+        if ctx.is_null() {
+            return Err(ssl_error("cannot create TLS context"))
+        }
+        let context = Context { ctx: ctx };
+        let (certificate, key) = (certificate.to_c_str(), key.to_c_str());
+        unsafe {
+            if ffi::SSL_CTX_use_certificate_file(ctx, certificate.as_ptr(),
+                                                 ffi::X509_FILETYPE_PEM) != 1 {
+                return Err(ssl_error("cannot load TLS certificate"))
+            }
+            if ffi::SSL_CTX_use_PrivateKey_file(ctx, key.as_ptr(),
+                                                ffi::X509_FILETYPE_PEM) != 1 {
+                return Err(ssl_error("cannot load TLS key"))
+            }
+            // Ask for a client certificate, connections without one are accepted
+            ffi::SSL_CTX_set_verify(ctx, ffi::SSL_VERIFY_PEER, Some(accept_any_certificate));
+        }
+        Ok(TlsContext { ctx: Arc::new(context) })
+    }
+}
+
+/// Outcome of an operation on a non-blocking socket
+#[deriving(PartialEq, Show)]
+pub enum Progress {
+    /// The operation finished, holds the number of bytes read or written
+    Done(uint),
+    /// The operation has to be repeated once more data was received
+    WantRead,
+    /// The operation has to be repeated once the socket is writable
+    WantWrite,
+    /// The peer closed the connection
+    Closed
+}
+
+/// Server side of a TLS connection
+pub struct Session {
+    ssl: *mut ffi::SSL,
+    /// Encrypted data received from the peer, owned by `ssl`
+    rbio: *mut ffi::BIO,
+    /// Encrypted data to be sent to the peer, owned by `ssl`
+    wbio: *mut ffi::BIO,
+    /// Keeps the context alive as long as the session uses it
+    _ctx: TlsContext
+}
+
+impl Session {
+    /// Starts a session which waits for the handshake of the client
+    pub fn new(ctx: &TlsContext) -> IoResult<Session> {
+        let ssl = unsafe { ffi::SSL_new(ctx.ctx.ctx) };
+        if ssl.is_null() {
+            return Err(ssl_error("cannot create TLS session"))
+        }
+        let (rbio, wbio) = unsafe {
+            (ffi::BIO_new(ffi::BIO_s_mem()), ffi::BIO_new(ffi::BIO_s_mem()))
+        };
+        if rbio.is_null() || wbio.is_null() {
+            unsafe {
+                if !rbio.is_null() { ffi::BIO_free_all(rbio) }
+                if !wbio.is_null() { ffi::BIO_free_all(wbio) }
+                ffi::SSL_free(ssl)
+            }
+            return Err(ssl_error("cannot create TLS session"))
+        }
+        unsafe { ffi::SSL_set_bio(ssl, rbio, wbio) };
+        Ok(Session { ssl: ssl, rbio: rbio, wbio: wbio, _ctx: ctx.clone() })
+    }
+
+    /// Interprets the return value of a failed operation
+    fn progress(&self, ret: c_int) -> IoResult<Progress> {
+        match unsafe { ffi::SSL_get_error(self.ssl, ret) } {
+            ffi::SSL_ERROR_WANT_READ => Ok(WantRead),
+            ffi::SSL_ERROR_ZERO_RETURN => Ok(Closed),
+            _ => Err(ssl_error("TLS error"))
+        }
+    }
+
+    /// Passes encrypted data read from the socket to the session
+    pub fn receive(&mut self, data: &[u8]) {
+        // Memory buffers grow as needed, a write never fails short
+        unsafe {
+            ffi::BIO_write(self.rbio, data.as_ptr() as *const c_void, data.len() as c_int)
+        };
+    }
+
+    /// Continues the handshake, returns true once it is complete
+    pub fn handshake(&mut self) -> IoResult<bool> {
+        clear_errors();
+        let ret = unsafe { ffi::SSL_accept(self.ssl) };
+        if ret == 1 {
+            return Ok(true)
+        }
+        match try!(self.progress(ret)) {
+            WantRead => Ok(false),
+            _ => Err(IoError {
+                kind: OtherIoError,
+                desc: "connection closed",
+                detail: None
+            })
+        }
+    }
+
+    /// Decrypts received data
+    ///
+    /// Returns `WantRead` once everything received so far was read.
+    pub fn read(&mut self, buf: &mut [u8]) -> IoResult<Progress> {
+        clear_errors();
+        let ret = unsafe {
+            ffi::SSL_read(self.ssl, buf.as_mut_ptr() as *mut c_void, buf.len() as c_int)
+        };
+        if ret > 0 { Ok(Done(ret as uint)) } else { self.progress(ret) }
+    }
+
+    /// Encrypts data, the result is collected with `take_output`
+    pub fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut written = 0;
+        while written < buf.len() {
+            clear_errors();
+            let rest = buf.slice_from(written);
+            let ret = unsafe {
+                ffi::SSL_write(self.ssl, rest.as_ptr() as *const c_void, rest.len() as c_int)
+            };
+            if ret <= 0 {
+                return Err(ssl_error("TLS error"))
+            }
+            written += ret as uint
+        }
+        Ok(())
+    }
+
+    /// Moves the encrypted data which has to be sent to the socket to `output`
+    pub fn take_output(&mut self, output: &mut Vec<u8>) {
+        let mut buf = [0u8, ..BUFFER_SIZE];
+        loop {
+            let n = unsafe {
+                ffi::BIO_read(self.wbio, buf.as_mut_ptr() as *mut c_void, buf.len() as c_int)
+            };
+            if n <= 0 {
+                break
+            }
+            output.push_all(buf.slice_to(n as uint))
+        }
+    }
+
+    /// SHA-256 fingerprint of the client certificate as lower case hex
+    pub fn fingerprint(&self) -> Option<String> {
+        let cert = unsafe { ffi::SSL_get_peer_certificate(self.ssl) };
+        if cert.is_null() {
+            return None
+        }
+        let mut md = [0u8, ..SHA256_LENGTH];
+        let mut len = 0 as c_uint;
+        let ret = unsafe {
+            let ret = ffi::X509_digest(cert, ffi::EVP_sha256(), md.as_mut_ptr(), &mut len);
+            ffi::X509_free(cert);
+            ret
+        };
+        if ret == 1 {
+            Some(md.slice_to(len as uint).to_hex())
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Frees the memory buffers as well
+        unsafe { ffi::SSL_free(self.ssl) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{IoResult, IoError, EndOfFile, standard_error};
+	use libc::{c_int, c_void, size_t};
+	use libc;
+	use openssl::ssl::{SslContext, SslStream, Sslv23};
+	use super::{TlsContext, Session, Done};
+
+	extern {
+		fn socketpair(domain: c_int, kind: c_int, protocol: c_int, sv: *mut c_int) -> c_int;
+	}
+
+	/// Blocking end of a socket pair
+	struct Fd(c_int);
+
+	impl Reader for Fd {
+		fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+			let Fd(fd) = *self;
+			match unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t) } {
+				0 => Err(standard_error(EndOfFile)),
+				n if n > 0 => Ok(n as uint),
+				_ => Err(IoError::last_error())
+			}
+		}
+	}
+
+	impl Writer for Fd {
+		fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+			let Fd(fd) = *self;
+			let mut written = 0;
+			while written < buf.len() {
+				let rest = buf.slice_from(written);
+				let n = unsafe { libc::write(fd, rest.as_ptr() as *const c_void, rest.len() as size_t) };
+				if n < 0 {
+					return Err(IoError::last_error())
+				}
+				written += n as uint
+			}
+			Ok(())
+		}
+	}
+
+	/// Test a handshake with the self-signed test certificate
+	#[test]
+	fn test_tls_session() {
+		let ctx = TlsContext::new(
+			&Path::new("tests/tls/cert.pem"),
+			&Path::new("tests/tls/key.pem")
+		).unwrap();
+		let mut fds = [0 as c_int, ..2];
+		assert_eq!(unsafe { socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) }, 0)
+		let client = fds[1];
+		spawn(proc() {
+			let client_ctx = SslContext::new(Sslv23).unwrap();
+			let mut ssl = SslStream::new(&client_ctx, Fd(client)).unwrap();
+			ssl.write(b"PING :test\r\n").unwrap();
+			ssl.flush().unwrap();
+		});
+		// The session only sees the data the loop passes to it
+		let mut session = Session::new(&ctx).unwrap();
+		let mut socket = Fd(fds[0]);
+		let mut buf = [0u8, ..4096];
+		let mut received = Vec::new();
+		let mut established = false;
+		while received.len() < 12 {
+			let n = socket.read(&mut buf).unwrap();
+			session.receive(buf.slice_to(n));
+			if !established {
+				established = session.handshake().unwrap();
+			}
+			if established {
+				while let Done(n) = session.read(&mut buf).unwrap() {
+					received.push_all(buf.slice_to(n))
+				}
+			}
+			let mut output = Vec::new();
+			session.take_output(&mut output);
+			socket.write(output.as_slice()).unwrap();
+		}
+		assert_eq!(received.as_slice(), b"PING :test\r\n")
+		// The client did not send a certificate
+		assert!(session.fingerprint().is_none())
+		drop(session);
+		unsafe {
+			libc::close(fds[0]);
+			libc::close(fds[1]);
+		}
+	}
+}
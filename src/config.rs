@@ -0,0 +1,861 @@
+//! Configuration of the daemon
+//!
+//! The configuration is read from a small subset of TOML at startup:
+//!
+//! ```
+//! [server]
+//! name = "irc.example.net"
+//! network = "ExampleNet"
+//! motd = "motd.txt"
+//!
+//! [[listen]]
+//! address = "localhost"
+//! port = 6667
+//!
+//! [[listen]]
+//! address = "localhost"
+//! port = 6697
+//! tls_certificate = "cert.pem"
+//! tls_key = "key.pem"
+//!
+//! [limits]
+//! nick_length = 9
+//!
+//! [channels]
+//! default_modes = "nt"
+//! registry = "channels.txt"
+//!
+//! [[oper]]
+//! name = "admin"
+//! password = "secret"
+//! hosts = ["*!*@localhost"]
+//! fingerprint = "<sha-256 fingerprint of the client certificate>"
+//!
+//! [accounts]
+//! file = "accounts.txt"
+//! registration = "verify"
+//! verification_file = "codes.txt"
+//! nick_grace_period = 60
+//! ```
+//!
+//! Supported are tables, arrays of tables, strings, integers, booleans and
+//! arrays of those values. Every error carries the line it occured on.
+
+use std::collections::{HashMap};
+use std::fmt::{Show, Formatter, Error};
+use std::io::{File};
+
+use channel::util::{ChannelMode};
+use util::{HostMask, CaseMapping};
+use util;
+use account::{normalize_fingerprint};
+
+use self::Value::{Str, Int, Bool, Array};
+use self::Target::{Root, Single, InArray};
+
+/// Error which occured while loading the configuration
+pub struct ConfigError {
+    line: Option<uint>,
+    desc: String
+}
+
+impl ConfigError {
+    fn new(line: uint, desc: String) -> ConfigError {
+        ConfigError { line: Some(line), desc: desc }
+    }
+
+    /// Line the error occured on
+    pub fn line(&self) -> Option<uint> {
+        self.line
+    }
+}
+
+impl Show for ConfigError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        match self.line {
+            Some(line) => write!(fmt, "line {}: {}", line, self.desc),
+            None => write!(fmt, "{}", self.desc)
+        }
+    }
+}
+
+pub type ConfigResult<T> = Result<T, ConfigError>;
+
+/// Server identity
+#[deriving(Clone)]
+pub struct ServerConfig {
+    /// Name of the server, used as the prefix of server messages
+    pub name: String,
+    /// Name of the IRC network
+    pub network: String,
+    /// Short description of the server
+    pub description: String,
+    /// Path to the message of the day
+    pub motd: Option<Path>,
+    /// Case mapping used to compare nick and channel names
+    pub casemapping: CaseMapping,
+}
+
+/// Address the server listens on
+#[deriving(Clone)]
+pub struct ListenConfig {
+    /// Host name or ip address
+    pub address: String,
+    pub port: u16,
+    /// Certificate and key if the listener uses TLS
+    pub tls: Option<TlsConfig>,
+}
+
+/// PEM encoded certificate and private key of a TLS listener
+#[deriving(Clone)]
+pub struct TlsConfig {
+    pub certificate: Path,
+    pub key: Path,
+}
+
+/// Various limits the server enforces
+#[deriving(Clone)]
+pub struct Limits {
+    /// Maximum length of a nick name
+    pub nick_length: uint,
+    /// Maximum length of a channel name
+    pub channel_length: uint,
+    /// Maximum length of a channel topic
+    pub topic_length: uint,
+    /// Maximum number of entries in the ban, exception and invitation lists
+    pub list_entries: uint,
+    /// Seconds of inactivity after which a peer is pinged
+    pub ping_interval: uint,
+    /// Seconds a peer has to answer a ping
+    pub ping_timeout: uint,
+    /// Maximum number of bytes queued for a peer
+    pub sendq: uint,
+}
+
+/// Defaults for newly created channels
+#[deriving(Clone)]
+pub struct ChannelConfig {
+    /// Modes set on channel creation
+    pub default_modes: Vec<ChannelMode>,
+    /// File the registered channels are stored in, channels can only be
+    /// registered if it is set
+    pub registry: Option<Path>,
+}
+
+/// Flood protection
+#[deriving(Clone)]
+pub struct FloodConfig {
+    /// Number of messages a client can send at once
+    pub burst: uint,
+    /// Milliseconds after which a client can send another message
+    pub interval: uint,
+    /// Opers are not rate limited
+    pub exempt_opers: bool,
+    /// Host names which are not rate limited
+    pub exempt_hosts: Vec<HostMask>,
+}
+
+impl FloodConfig {
+    /// Checks whether peers connecting from `hostname` are rate limited
+    pub fn is_exempt_host(&self, hostname: &str) -> bool {
+        self.exempt_hosts.iter().any(|mask| mask.matches(hostname))
+    }
+}
+
+/// Operator block
+#[deriving(Clone)]
+pub struct OperConfig {
+    pub name: String,
+    pub password: String,
+    /// Host masks the operator is allowed to connect from
+    pub hosts: Vec<HostMask>,
+    /// Fingerprint of the TLS client certificate the operator has to use
+    pub fingerprint: Option<String>,
+}
+
+/// Who may create accounts with REGISTER
+#[deriving(Clone, PartialEq, Show)]
+pub enum RegistrationPolicy {
+    /// REGISTER is not available
+    Closed,
+    /// Accounts are created immediately
+    Open,
+    /// Accounts are created once the code sent to their e-mail address
+    /// is verified
+    Verify,
+    /// Only operators can create accounts
+    OpersOnly,
+}
+
+/// Account storage
+#[deriving(Clone)]
+pub struct AccountsConfig {
+    /// File the accounts are read from, SASL is only offered if it is set
+    pub file: Option<Path>,
+    pub registration: RegistrationPolicy,
+    /// File the verification codes are written to
+    pub verification_file: Option<Path>,
+    /// Seconds a user of a nick owned by an account has to log in
+    pub nick_grace_period: uint,
+}
+
+/// Configuration of the daemon
+#[deriving(Clone)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub listen: Vec<ListenConfig>,
+    pub limits: Limits,
+    pub channels: ChannelConfig,
+    pub flood: FloodConfig,
+    pub opers: Vec<OperConfig>,
+    pub accounts: AccountsConfig,
+}
+
+impl Config {
+    /// Loads the configuration file at `path`
+    pub fn load(path: &Path) -> ConfigResult<Config> {
+        match File::open(path).read_to_string() {
+            Ok(source) => Config::from_str(source.as_slice()),
+            Err(err) => Err(ConfigError {
+                line: None,
+                desc: format!("cannot read {}: {}", path.display(), err)
+            })
+        }
+    }
+
+    /// Parses the configuration
+    pub fn from_str(source: &str) -> ConfigResult<Config> {
+        let doc = try!(Document::parse(source));
+        try!(doc.check_known(&["server", "limits", "channels", "flood", "accounts"],
+                             &["listen", "oper"]));
+        let server = {
+            let table = doc.table("server");
+            try!(table.check_known(&["name", "network", "description", "motd", "casemapping"]));
+            let (casemapping, line) = try!(table.str_line_or("casemapping", "rfc1459"));
+            ServerConfig {
+                name: try!(table.str_or("name", "localhost")),
+                network: try!(table.str_or("network", "chätIRC")),
+                description: try!(table.str_or("description", "chätIRC server")),
+                motd: try!(table.opt_str("motd")).map(|v| Path::new(v)),
+                casemapping: match CaseMapping::from_name(casemapping.as_slice()) {
+                    Some(casemapping) => casemapping,
+                    None => return Err(ConfigError::new(line, format!(
+                        "unknown casemapping `{}`, use ascii, rfc1459 or strict-rfc1459",
+                        casemapping
+                    )))
+                },
+            }
+        };
+        let mut listen = Vec::new();
+        for table in doc.array("listen").iter() {
+            try!(table.check_known(&["address", "port", "tls_certificate", "tls_key"]));
+            let tls = match (try!(table.opt_str("tls_certificate")),
+                             try!(table.opt_str("tls_key"))) {
+                (Some(certificate), Some(key)) => Some(TlsConfig {
+                    certificate: Path::new(certificate),
+                    key: Path::new(key)
+                }),
+                (None, None) => None,
+                _ => return Err(ConfigError::new(table.line,
+                    "TLS listeners need both `tls_certificate` and `tls_key`".to_string()
+                ))
+            };
+            let default_port = if tls.is_some() { 6697 } else { 6667 };
+            listen.push(ListenConfig {
+                address: try!(table.str_or("address", "localhost")),
+                port: try!(table.int_in("port", default_port, 1, 65535)) as u16,
+                tls: tls
+            })
+        }
+        if listen.len() == 0 {
+            listen.push(ListenConfig {
+                address: "localhost".to_string(),
+                port: 6667,
+                tls: None
+            })
+        }
+        let limits = {
+            let table = doc.table("limits");
+            try!(table.check_known(&["nick_length", "channel_length",
+                                     "topic_length", "list_entries",
+                                     "ping_interval", "ping_timeout", "sendq"]));
+            Limits {
+                nick_length: try!(table.int_in(
+                    "nick_length", util::RFC_NICK_LENGTH as i64, 1, util::MAX_NICK_LENGTH as i64
+                )) as uint,
+                channel_length: try!(table.int_in("channel_length", 50, 2, 200)) as uint,
+                topic_length: try!(table.int_in("topic_length", 390, 0, 400)) as uint,
+                list_entries: try!(table.int_in("list_entries", 60, 0, 1000)) as uint,
+                ping_interval: try!(table.int_in("ping_interval", 120, 10, 3600)) as uint,
+                ping_timeout: try!(table.int_in("ping_timeout", 60, 10, 3600)) as uint,
+                sendq: try!(table.int_in("sendq", 262144, 4096, 67108864)) as uint,
+            }
+        };
+        let channels = {
+            let table = doc.table("channels");
+            try!(table.check_known(&["default_modes", "registry"]));
+            let (modes, line) = try!(table.str_line_or("default_modes", "nt"));
+            let mut default_modes = Vec::new();
+            for c in modes.as_slice().chars() {
+                let mode: Option<ChannelMode> = if (c as u32) < 128 {
+                    FromPrimitive::from_u8(c as u8)
+                } else { None };
+                match mode {
+                    Some(mode) if !mode.has_parameter() => default_modes.push(mode),
+                    _ => return Err(ConfigError::new(line, format!(
+                        "`{}` is not a valid default channel mode", c
+                    )))
+                }
+            }
+            ChannelConfig {
+                default_modes: default_modes,
+                registry: try!(table.opt_str("registry")).map(|v| Path::new(v))
+            }
+        };
+        let flood = {
+            let table = doc.table("flood");
+            try!(table.check_known(&["burst", "interval", "exempt_opers", "exempt_hosts"]));
+            FloodConfig {
+                burst: try!(table.int_in("burst", 10, 1, 1000)) as uint,
+                interval: try!(table.int_in("interval", 1000, 1, 60000)) as uint,
+                exempt_opers: try!(table.bool_or("exempt_opers", true)),
+                exempt_hosts: try!(table.str_array_or("exempt_hosts", &[]))
+                              .into_iter().map(|v| HostMask::new(v)).collect()
+            }
+        };
+        let mut opers = Vec::new();
+        for table in doc.array("oper").iter() {
+            try!(table.check_known(&["name", "password", "hosts", "fingerprint"]));
+            let fingerprint = match try!(table.opt_str("fingerprint")) {
+                Some(_) => {
+                    let (fingerprint, line) = try!(table.str_line_or("fingerprint", ""));
+                    match normalize_fingerprint(fingerprint.as_slice()) {
+                        Some(fingerprint) => Some(fingerprint),
+                        None => return Err(ConfigError::new(line, format!(
+                            "`{}` is not a SHA-256 fingerprint", fingerprint
+                        )))
+                    }
+                },
+                None => None
+            };
+            opers.push(OperConfig {
+                name: try!(table.str("name")),
+                password: try!(table.str("password")),
+                hosts: try!(table.str_array_or("hosts", &["*!*@*"]))
+                       .into_iter().map(|v| HostMask::new(v)).collect(),
+                fingerprint: fingerprint
+            })
+        }
+        let accounts = {
+            let table = doc.table("accounts");
+            try!(table.check_known(&["file", "registration", "verification_file",
+                                     "nick_grace_period"]));
+            let file = try!(table.opt_str("file")).map(|v| Path::new(v));
+            let verification_file = try!(table.opt_str("verification_file")).map(|v| Path::new(v));
+            let (registration, line) = try!(table.str_line_or("registration", "closed"));
+            let registration = match registration.as_slice() {
+                "closed" => RegistrationPolicy::Closed,
+                "open" => RegistrationPolicy::Open,
+                "verify" => RegistrationPolicy::Verify,
+                "opers" => RegistrationPolicy::OpersOnly,
+                _ => return Err(ConfigError::new(line, format!(
+                    "unknown registration policy `{}`, use closed, open, verify or opers",
+                    registration
+                )))
+            };
+            if registration != RegistrationPolicy::Closed && file.is_none() {
+                return Err(ConfigError::new(line,
+                    "account registration needs an account `file`".to_string()
+                ))
+            }
+            if registration == RegistrationPolicy::Verify && verification_file.is_none() {
+                return Err(ConfigError::new(line,
+                    "verified registration needs a `verification_file`".to_string()
+                ))
+            }
+            AccountsConfig {
+                file: file,
+                registration: registration,
+                verification_file: verification_file,
+                nick_grace_period: try!(table.int_in("nick_grace_period", 60, 0, 3600)) as uint
+            }
+        };
+        Ok(Config {
+            server: server,
+            listen: listen,
+            limits: limits,
+            channels: channels,
+            flood: flood,
+            opers: opers,
+            accounts: accounts
+        })
+    }
+}
+
+/// Value of a key
+#[deriving(Show, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<Value>)
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match *self {
+            Str(_) => "string",
+            Int(_) => "integer",
+            Bool(_) => "boolean",
+            Array(_) => "array",
+        }
+    }
+}
+
+/// Key-value pairs of a table with the line they were defined on
+struct Table {
+    line: uint,
+    name: String,
+    entries: HashMap<String, (Value, uint)>
+}
+
+impl Table {
+    fn new(name: String, line: uint) -> Table {
+        Table { line: line, name: name, entries: HashMap::new() }
+    }
+
+    /// Rejects unknown keys, they are most likely typos
+    fn check_known(&self, keys: &[&str]) -> ConfigResult<()> {
+        for (key, &(_, line)) in self.entries.iter() {
+            if !keys.iter().any(|k| *k == key.as_slice()) {
+                return Err(ConfigError::new(line, format!(
+                    "unknown key `{}` in [{}]", key, self.name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn opt_str(&self, key: &str) -> ConfigResult<Option<String>> {
+        match self.entries.get(&key.to_string()) {
+            Some(&(Str(ref v), _)) => Ok(Some(v.clone())),
+            Some(&(ref v, line)) => Err(ConfigError::new(line, format!(
+                "`{}` has to be a string, found {}", key, v.type_name()
+            ))),
+            None => Ok(None)
+        }
+    }
+
+    fn str(&self, key: &str) -> ConfigResult<String> {
+        match try!(self.opt_str(key)) {
+            Some(v) => Ok(v),
+            None => Err(ConfigError::new(self.line, format!(
+                "missing key `{}` in [{}]", key, self.name
+            )))
+        }
+    }
+
+    fn str_or(&self, key: &str, default: &str) -> ConfigResult<String> {
+        self.str_line_or(key, default).map(|(v, _)| v)
+    }
+
+    fn str_line_or(&self, key: &str, default: &str) -> ConfigResult<(String, uint)> {
+        let line = self.entries.get(&key.to_string()).map_or(self.line, |&(_, line)| line);
+        try!(self.opt_str(key)).map_or(Ok((default.to_string(), line)), |v| Ok((v, line)))
+    }
+
+    fn str_array_or(&self, key: &str, default: &[&str]) -> ConfigResult<Vec<String>> {
+        match self.entries.get(&key.to_string()) {
+            Some(&(Array(ref values), line)) => {
+                let mut strings = Vec::new();
+                for value in values.iter() {
+                    match *value {
+                        Str(ref v) => strings.push(v.clone()),
+                        ref v => return Err(ConfigError::new(line, format!(
+                            "`{}` has to be an array of strings, found {}", key, v.type_name()
+                        )))
+                    }
+                }
+                Ok(strings)
+            },
+            Some(&(ref v, line)) => Err(ConfigError::new(line, format!(
+                "`{}` has to be an array, found {}", key, v.type_name()
+            ))),
+            None => Ok(default.iter().map(|v| v.to_string()).collect())
+        }
+    }
+
+    fn int_in(&self, key: &str, default: i64, min: i64, max: i64) -> ConfigResult<i64> {
+        match self.entries.get(&key.to_string()) {
+            Some(&(Int(v), line)) => if v < min || v > max {
+                Err(ConfigError::new(line, format!(
+                    "`{}` has to be between {} and {}", key, min, max
+                )))
+            } else {
+                Ok(v)
+            },
+            Some(&(ref v, line)) => Err(ConfigError::new(line, format!(
+                "`{}` has to be an integer, found {}", key, v.type_name()
+            ))),
+            None => Ok(default)
+        }
+    }
+
+    fn bool_or(&self, key: &str, default: bool) -> ConfigResult<bool> {
+        match self.entries.get(&key.to_string()) {
+            Some(&(Bool(v), _)) => Ok(v),
+            Some(&(ref v, line)) => Err(ConfigError::new(line, format!(
+                "`{}` has to be a boolean, found {}", key, v.type_name()
+            ))),
+            None => Ok(default)
+        }
+    }
+}
+
+/// Parsed but not yet interpreted configuration file
+struct Document {
+    tables: HashMap<String, Table>,
+    arrays: HashMap<String, Vec<Table>>,
+    /// Empty table returned for missing sections
+    empty: Vec<Table>,
+}
+
+/// Current table while parsing
+enum Target {
+    Root,
+    Single(String),
+    InArray(String)
+}
+
+impl Document {
+    fn parse(source: &str) -> ConfigResult<Document> {
+        let mut doc = Document {
+            tables: HashMap::new(),
+            arrays: HashMap::new(),
+            empty: Vec::new(),
+        };
+        let mut target = Root;
+        for (i, raw_line) in source.lines().enumerate() {
+            let line_no = i + 1;
+            let line = strip_comment(raw_line).trim();
+            if line.len() == 0 {
+                continue
+            }
+            if line.starts_with("[[") {
+                if !line.ends_with("]]") {
+                    return Err(ConfigError::new(line_no, "missing `]]`".to_string()))
+                }
+                let name = try!(table_name(line.slice(2, line.len() - 2), line_no));
+                if doc.tables.contains_key(&name) {
+                    return Err(ConfigError::new(line_no, format!(
+                        "[{}] is already defined as a table", name
+                    )))
+                }
+                let table = Table::new(name.clone(), line_no);
+                if doc.arrays.contains_key(&name) {
+                    doc.arrays.get_mut(&name).unwrap().push(table);
+                } else {
+                    doc.arrays.insert(name.clone(), vec![table]);
+                }
+                target = InArray(name);
+            } else if line.starts_with("[") {
+                if !line.ends_with("]") {
+                    return Err(ConfigError::new(line_no, "missing `]`".to_string()))
+                }
+                let name = try!(table_name(line.slice(1, line.len() - 1), line_no));
+                if doc.tables.contains_key(&name) || doc.arrays.contains_key(&name) {
+                    return Err(ConfigError::new(line_no, format!(
+                        "duplicate table [{}]", name
+                    )))
+                }
+                doc.tables.insert(name.clone(), Table::new(name.clone(), line_no));
+                target = Single(name);
+            } else {
+                let (key, value) = try!(parse_pair(line, line_no));
+                let table = match target {
+                    Root => return Err(ConfigError::new(line_no, format!(
+                        "`{}` has to be inside of a table", key
+                    ))),
+                    Single(ref name) => doc.tables.get_mut(name).unwrap(),
+                    InArray(ref name) => doc.arrays.get_mut(name).unwrap().last_mut().unwrap()
+                };
+                if table.entries.contains_key(&key) {
+                    return Err(ConfigError::new(line_no, format!(
+                        "duplicate key `{}`", key
+                    )))
+                }
+                table.entries.insert(key, (value, line_no));
+            }
+        }
+        doc.empty.push(Table::new("".to_string(), 0));
+        Ok(doc)
+    }
+
+    /// Rejects unknown tables
+    fn check_known(&self, tables: &[&str], arrays: &[&str]) -> ConfigResult<()> {
+        for (name, table) in self.tables.iter() {
+            if !tables.iter().any(|k| *k == name.as_slice()) {
+                return Err(ConfigError::new(table.line, format!("unknown table [{}]", name)))
+            }
+        }
+        for (name, tables) in self.arrays.iter() {
+            if !arrays.iter().any(|k| *k == name.as_slice()) {
+                return Err(ConfigError::new(tables[0].line, format!("unknown table [[{}]]", name)))
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the table `name` or an empty one if it does not exist
+    fn table(&self, name: &str) -> &Table {
+        self.tables.get(&name.to_string()).unwrap_or(&self.empty[0])
+    }
+
+    /// Returns the array of tables `name`
+    fn array(&self, name: &str) -> &[Table] {
+        self.arrays.get(&name.to_string()).map_or([].as_slice(), |v| v.as_slice())
+    }
+}
+
+/// Removes comments which are not inside of a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if in_string && !escaped => { escaped = true; continue },
+            '"' if !escaped => in_string = !in_string,
+            '#' if !in_string => return line.slice_to(i),
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn is_digit(c: char) -> bool {
+    c >= '0' && c <= '9'
+}
+
+fn is_bare_key(key: &str) -> bool {
+    key.len() > 0 && key.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+fn table_name(name: &str, line: uint) -> ConfigResult<String> {
+    let name = name.trim();
+    if is_bare_key(name) {
+        Ok(name.to_string())
+    } else {
+        Err(ConfigError::new(line, format!("invalid table name `{}`", name)))
+    }
+}
+
+fn parse_pair(line: &str, line_no: uint) -> ConfigResult<(String, Value)> {
+    let (key, rest) = match line.find('=') {
+        Some(pos) => (line.slice_to(pos).trim(), line.slice_from(pos + 1).trim()),
+        None => return Err(ConfigError::new(line_no, "expected `key = value`".to_string()))
+    };
+    if !is_bare_key(key) {
+        return Err(ConfigError::new(line_no, format!("invalid key `{}`", key)))
+    }
+    let mut parser = ValueParser { chars: rest.chars().peekable(), line: line_no };
+    let value = try!(parser.value());
+    parser.skip_whitespace();
+    match parser.chars.next() {
+        Some(c) => Err(ConfigError::new(line_no, format!(
+            "unexpected `{}` after value of `{}`", c, key
+        ))),
+        None => Ok((key.to_string(), value))
+    }
+}
+
+struct ValueParser<'a> {
+    chars: ::std::iter::Peekable<char, ::std::str::Chars<'a>>,
+    line: uint
+}
+
+impl<'a> ValueParser<'a> {
+    fn error<T>(&self, desc: &str) -> ConfigResult<T> {
+        Err(ConfigError::new(self.line, desc.to_string()))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().map_or(false, |c| c.is_whitespace()) {
+            let _ = self.chars.next();
+        }
+    }
+
+    fn value(&mut self) -> ConfigResult<Value> {
+        self.skip_whitespace();
+        match self.chars.peek().map(|c| *c) {
+            Some('"') => self.string(),
+            Some('[') => self.array(),
+            Some(c) if c == '-' || is_digit(c) => self.integer(),
+            Some(_) => self.boolean(),
+            None => self.error("missing value")
+        }
+    }
+
+    fn string(&mut self) -> ConfigResult<Value> {
+        let _ = self.chars.next();
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(Str(string)),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some(c) => return self.error(format!(
+                        "invalid escape sequence `\\{}`", c
+                    ).as_slice()),
+                    None => return self.error("unterminated string")
+                },
+                Some(c) => string.push(c),
+                None => return self.error("unterminated string")
+            }
+        }
+    }
+
+    fn integer(&mut self) -> ConfigResult<Value> {
+        let mut digits = String::new();
+        while self.chars.peek().map_or(false, |&c| c == '-' || is_digit(c)) {
+            digits.push(self.chars.next().unwrap())
+        }
+        match from_str::<i64>(digits.as_slice()) {
+            Some(v) => Ok(Int(v)),
+            None => self.error(format!("invalid integer `{}`", digits).as_slice())
+        }
+    }
+
+    fn boolean(&mut self) -> ConfigResult<Value> {
+        let mut word = String::new();
+        while self.chars.peek().map_or(false, |&c| c.is_alphanumeric()) {
+            word.push(self.chars.next().unwrap())
+        }
+        match word.as_slice() {
+            "true" => Ok(Bool(true)),
+            "false" => Ok(Bool(false)),
+            "" => self.error("invalid value"),
+            _ => self.error(format!("invalid value `{}`, strings have to be quoted", word).as_slice())
+        }
+    }
+
+    fn array(&mut self) -> ConfigResult<Value> {
+        let _ = self.chars.next();
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek().map(|c| *c) {
+                Some(']') => { let _ = self.chars.next(); return Ok(Array(values)) },
+                None => return self.error("missing `]`, arrays have to be on one line"),
+                _ => {}
+            }
+            values.push(try!(self.value()));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => {},
+                Some(']') => return Ok(Array(values)),
+                _ => return self.error("expected `,` or `]` in array")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Config, RegistrationPolicy};
+	use channel::util::{TopicProtect, MemberOnly};
+
+	#[test]
+	/// Test that defaults are used for an empty configuration
+	fn test_defaults() {
+		let config = Config::from_str("").unwrap();
+		assert_eq!(config.server.name.as_slice(), "localhost")
+		assert_eq!(config.listen.len(), 1)
+		assert_eq!(config.listen[0].port, 6667)
+		assert_eq!(config.limits.nick_length, 9)
+		assert_eq!(config.limits.sendq, 262144)
+		assert_eq!(config.channels.default_modes, vec![MemberOnly, TopicProtect])
+		assert!(config.channels.registry.is_none())
+		assert!(config.accounts.file.is_none())
+		assert_eq!(config.accounts.registration, RegistrationPolicy::Closed)
+		assert_eq!(config.accounts.nick_grace_period, 60)
+	}
+
+	#[test]
+	/// Test a complete configuration
+	fn test_parse() {
+		let config = Config::from_str(r#"
+# comment
+[server]
+name = "irc.example.net" # trailing comment
+network = "Example#Net"
+
+[[listen]]
+address = "127.0.0.1"
+port = 6667
+
+[[listen]]
+address = "::1"
+tls_certificate = "cert.pem"
+tls_key = "key.pem"
+
+[limits]
+nick_length = 16
+
+[[oper]]
+name = "admin"
+password = "secret"
+hosts = ["*!*@localhost", "*!*@127.0.0.1"]
+
+[channels]
+registry = "channels.txt"
+
+[accounts]
+file = "accounts.txt"
+registration = "verify"
+verification_file = "codes.txt"
+"#).unwrap();
+		assert_eq!(config.server.name.as_slice(), "irc.example.net")
+		assert_eq!(config.server.network.as_slice(), "Example#Net")
+		assert_eq!(config.listen.len(), 2)
+		assert_eq!(config.listen[1].address.as_slice(), "::1")
+		assert_eq!(config.listen[1].port, 6697)
+		assert!(config.listen[0].tls.is_none())
+		assert!(config.listen[1].tls.is_some())
+		assert_eq!(config.limits.nick_length, 16)
+		assert_eq!(config.opers[0].hosts.len(), 2)
+		assert!(config.opers[0].hosts[1].matches("foo!bar@127.0.0.1"))
+		assert!(config.channels.registry == Some(Path::new("channels.txt")))
+		assert!(config.accounts.file == Some(Path::new("accounts.txt")))
+		assert_eq!(config.accounts.registration, RegistrationPolicy::Verify)
+		assert!(config.accounts.verification_file == Some(Path::new("codes.txt")))
+	}
+
+	#[test]
+	/// Test that errors report the correct line
+	fn test_errors() {
+		let error = |source: &str| Config::from_str(source).err().unwrap().line();
+		assert_eq!(error("[server]\nname = localhost"), Some(2))
+		assert_eq!(error("[server]\nname = \"a\"\nname = \"b\""), Some(3))
+		assert_eq!(error("[server]\n\nnaem = \"a\""), Some(3))
+		assert_eq!(error("[[listen]]\nport = 123456"), Some(2))
+		assert_eq!(error("[[listen]]\nport = \"6667\""), Some(2))
+		assert_eq!(error("\n[[listen]]\ntls_key = \"key.pem\""), Some(2))
+		assert_eq!(error("[limits]\nnick_length = 100"), Some(2))
+		assert_eq!(error("\n[[oper]]\nname = \"admin\""), Some(2))
+		assert_eq!(error("\n[[oper]]\nname = \"a\"\npassword = \"b\"\nfingerprint = \"ab\""), Some(5))
+		assert_eq!(error("[channels]\ndefault_modes = \"nk\""), Some(2))
+		assert_eq!(error("[accounts]\nfile = \"a\"\nregistration = \"all\""), Some(3))
+		assert_eq!(error("[accounts]\nregistration = \"open\""), Some(2))
+		assert_eq!(error("[accounts]\nfile = \"a\"\nregistration = \"verify\""), Some(3))
+		assert_eq!(error("[foo]"), Some(1))
+		assert_eq!(error("[flood]\nexempt_opers = 1"), Some(2))
+		assert_eq!(error("[flood]\nexempt_hosts = [\"a\", 1]"), Some(2))
+		assert_eq!(error("[server]\ncasemapping = \"utf8\""), Some(2))
+		assert_eq!(error("key = \"value\""), Some(1))
+		assert_eq!(error("[server]\nname = \"unterminated"), Some(2))
+	}
+}
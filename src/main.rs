@@ -8,12 +8,23 @@
 #![feature(macro_rules)]
 #![feature(unsafe_destructor)]
 #![feature(if_let)]
+#![feature(while_let)]
 #[phase(plugin, link)] extern crate log;
 extern crate collections;
 extern crate libc;
+extern crate openssl;
+extern crate serialize;
+extern crate time;
+#[cfg(test)] extern crate test;
 
 #[cfg(not(test))]
 use server::{run_server};
+#[cfg(not(test))]
+use config::{Config};
+#[cfg(not(test))]
+use std::os;
+#[cfg(not(test))]
+use account::{hash_password};
 
 // pub only for documentation purposes
 pub mod con;
@@ -22,11 +33,34 @@ pub mod channel;
 pub mod msg;
 pub mod cmd;
 pub mod util;
+pub mod config;
+pub mod motd;
+pub mod account;
+pub mod sasl;
+pub mod verification;
 
 
+/// Configuration file used if no path is given on the command line
+#[cfg(not(test))]
+static DEFAULT_CONFIG: &'static str = "chätd.toml";
+
 #[cfg(not(test))]
 fn main() {
-    match run_server("localhost") {
+    let args = os::args();
+    if args.len() == 3 && args[1].as_slice() == "--hash-password" {
+        println!("{}", hash_password(args[2].as_slice()));
+        return
+    }
+    let path = Path::new(args.as_slice().get(1).map_or(DEFAULT_CONFIG, |v| v.as_slice()));
+    let config = match Config::load(&path) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("invalid configuration {}: {}", path.display(), err);
+            os::set_exit_status(1);
+            return
+        }
+    };
+    match run_server(config) {
         Ok(_) => {},
         Err(err) => error!("{}", err)
     }
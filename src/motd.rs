@@ -0,0 +1,85 @@
+//! Message of the day
+
+use std::io::{File, BufferedReader, IoResult};
+
+/// Maximum length of a message including the trailing CR-LF
+static MAX_LINE_LENGTH: uint = 512;
+
+/// Message of the day, wrapped to fit into RPL_MOTD replies
+pub struct Motd {
+    path: Path,
+    lines: Vec<String>
+}
+
+impl Motd {
+    /// Loads the message of the day from `path`
+    ///
+    /// Lines are wrapped such that a RPL_MOTD reply from `server_name`
+    /// to a user with a nick name of `nick_length` chars fits into one message.
+    pub fn load(path: &Path, server_name: &str, nick_length: uint) -> IoResult<Motd> {
+        let mut file = BufferedReader::new(try!(File::open(path)));
+        // :<server> 372 <nick> :- <text>\r\n
+        let overhead = 1 + server_name.len() + 5 + nick_length + 4 + 2;
+        let width = MAX_LINE_LENGTH - overhead;
+        let mut lines = Vec::new();
+        for line in file.lines() {
+            let line = try!(line);
+            wrap(line.as_slice().trim_right(), width, &mut lines)
+        }
+        Ok(Motd {
+            path: path.clone(),
+            lines: lines
+        })
+    }
+
+    /// Path of the file the message was loaded from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Lines of the message
+    pub fn lines(&self) -> &[String] {
+        self.lines.as_slice()
+    }
+}
+
+/// Wraps `line` such that no part is longer than `width` bytes
+///
+/// Lines are preferably broken at white space.
+fn wrap(mut line: &str, width: uint, lines: &mut Vec<String>) {
+    while line.len() > width {
+        // Last char boundary which fits into the line
+        let mut end = width;
+        while !line.is_char_boundary(end) {
+            end -= 1
+        }
+        let split = match line.slice_to(end).rfind(' ') {
+            Some(pos) if pos > 0 => pos,
+            _ => end
+        };
+        lines.push(line.slice_to(split).to_string());
+        line = line.slice_from(split).trim_left();
+    }
+    lines.push(line.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{wrap};
+	/// Test line wrapping
+	#[test]
+	fn test_wrap() {
+		let mut lines = Vec::new();
+		wrap("foo bar baz", 7, &mut lines);
+		assert_eq!(lines, vec!["foo bar".to_string(), "baz".to_string()])
+		let mut lines = Vec::new();
+		wrap("foobarbaz", 4, &mut lines);
+		assert_eq!(lines, vec!["foob".to_string(), "arba".to_string(), "z".to_string()])
+		let mut lines = Vec::new();
+		wrap("äöü", 3, &mut lines);
+		assert_eq!(lines, vec!["ä".to_string(), "ö".to_string(), "ü".to_string()])
+		let mut lines = Vec::new();
+		wrap("", 3, &mut lines);
+		assert_eq!(lines, vec!["".to_string()])
+	}
+}
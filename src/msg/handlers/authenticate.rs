@@ -0,0 +1,194 @@
+use std::ascii::{AsciiExt};
+use std::cmp;
+
+use serialize::base64::{ToBase64, STANDARD};
+
+use cmd;
+use msg::RawMessage;
+use sasl;
+use sasl::{Session, CHUNK_LENGTH};
+
+use server::{Server};
+use con::{Peer, PeerId, Connection};
+use con::reg;
+
+/// Handles the AUTHENTICATE command
+///
+///    Command: AUTHENTICATE
+/// Parameters: <mechanism> | <data> | *
+///
+/// The first message selects the mechanism, the following ones carry the
+/// base64 encoded responses. `*` aborts the authentication.
+pub struct Authenticate {
+    raw: RawMessage,
+    data: Vec<u8>
+}
+
+impl Authenticate {
+    fn handle(&self, server: &mut Server, peer: Peer) {
+        let host = server.host().to_string();
+        let host = host.as_slice();
+        let (enabled, logged_in, certfp) = {
+            let info = peer.info().read();
+            (info.has_capability(reg::SASL), info.account().is_some(),
+             info.certfp().map(|v| v.to_string()))
+        };
+        if !enabled {
+            peer.send_response(cmd::ERR_SASLFAIL, &["SASL authentication failed"], host);
+            return
+        } else if logged_in {
+            peer.send_response(cmd::ERR_SASLALREADY,
+                &["You have already authenticated using SASL"], host
+            );
+            return
+        }
+        let id = peer.id();
+        if self.data.as_slice() == b"*" {
+            server.connection_mut(id).map(|con| con.sasl().take());
+            peer.send_response(cmd::ERR_SASLABORTED, &["SASL authentication aborted"], host);
+            return
+        }
+        // The session is put back if the authentication continues
+        let session = match server.connection_mut(id) {
+            Some(con) => con.sasl().take(),
+            None => return
+        };
+        let mut session = match session {
+            Some(session) => session,
+            None => {
+                let failures = server.connection_mut(id).map_or(0, |con| *con.sasl_failures());
+                if failures >= sasl::MAX_FAILURES {
+                    peer.send_response(cmd::ERR_SASLFAIL,
+                        &["Too many failed SASL authentications"], host
+                    );
+                    return
+                }
+                let mechanism = String::from_utf8_lossy(self.data.as_slice()).into_string();
+                let session = Session::new(mechanism.as_slice().to_ascii_upper().as_slice(),
+                                           certfp.as_ref().map(|v| v.as_slice()),
+                                           server.secret(),
+                                           server.config().server.casemapping);
+                match session {
+                    Some(session) => {
+                        store(server, id, session);
+                        peer.send_msg(RawMessage::new(cmd::AUTHENTICATE, &["+"], None))
+                    },
+                    None => {
+                        peer.send_response(cmd::RPL_SASLMECHS, &[
+                            sasl::MECHANISMS.connect(",").as_slice(),
+                            "are available SASL mechanisms"
+                        ], host);
+                        peer.send_response(cmd::ERR_SASLFAIL,
+                            &["SASL authentication failed"], host
+                        )
+                    }
+                }
+                return
+            }
+        };
+        if self.data.len() > CHUNK_LENGTH {
+            peer.send_response(cmd::ERR_SASLTOOLONG, &["SASL message too long"], host);
+            return
+        }
+        let response = match session.feed(self.data.as_slice()) {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                store(server, id, session);
+                return
+            },
+            Err(sasl::TooLong) => {
+                peer.send_response(cmd::ERR_SASLTOOLONG, &["SASL message too long"], host);
+                return
+            },
+            Err(sasl::Invalid) => {
+                peer.send_response(cmd::ERR_SASLFAIL, &["SASL authentication failed"], host);
+                return
+            }
+        };
+        let step = match server.accounts() {
+            Some(accounts) => session.step(response.as_slice(), accounts),
+            None => sasl::Failure
+        };
+        match step {
+            sasl::Challenge(challenge) => {
+                send_challenge(&peer, challenge.as_slice());
+                store(server, id, session)
+            },
+            sasl::Success(account) => {
+                log_in(server, &peer, account);
+                peer.send_response(cmd::RPL_SASLSUCCESS,
+                    &["SASL authentication successful"], host
+                )
+            },
+            sasl::Failure => {
+                server.connection_mut(id).map(|con| *con.sasl_failures() += 1);
+                peer.send_response(cmd::ERR_SASLFAIL,
+                    &["SASL authentication failed"], host
+                )
+            }
+        }
+    }
+}
+
+/// Logs the peer in to an account
+pub fn log_in(server: &Server, peer: &Peer, account: String) {
+    let mask = {
+        let mut info = peer.info().write();
+        info.set_account(Some(account.clone()));
+        info.public_hostmask().as_str().to_string()
+    };
+    peer.send_response(cmd::RPL_LOGGEDIN, &[
+        mask.as_slice(), account.as_slice(),
+        format!("You are now logged in as {}", account).as_slice()
+    ], server.host())
+}
+
+/// Keeps the session of an unfinished authentication
+fn store(server: &mut Server, id: PeerId, session: Session) {
+    if let Some(con) = server.connection_mut(id) {
+        *con.sasl() = Some(session)
+    }
+}
+
+/// Sends a challenge base64 encoded and split into chunks
+fn send_challenge(peer: &Peer, challenge: &[u8]) {
+    let encoded = challenge.to_base64(STANDARD);
+    let encoded = encoded.as_slice();
+    let mut start = 0;
+    loop {
+        let end = cmp::min(start + CHUNK_LENGTH, encoded.len());
+        let chunk = encoded.slice(start, end);
+        peer.send_msg(RawMessage::new(cmd::AUTHENTICATE,
+            &[if chunk.is_empty() { "+" } else { chunk }], None
+        ));
+        // A chunk of full length is followed by another one
+        if chunk.len() < CHUNK_LENGTH {
+            break
+        }
+        start = end
+    }
+}
+
+impl super::MessageHandler for Authenticate {
+    fn from_message(message: RawMessage) -> Result<Box<Authenticate>, Option<RawMessage>> {
+        match message.param(0) {
+            Some(data) => Ok(box Authenticate {
+                raw: message.clone(),
+                data: data.to_vec()
+            }),
+            None => Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NEEDMOREPARAMS), &[
+                "*", message.command().to_string().as_slice(),
+                "not enought params given"
+            ], None)))
+        }
+    }
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        self.handle(server, origin)
+    }
+    fn invoke_con(&self, server: &mut Server, origin: Connection) {
+        self.handle(server, origin.peer())
+    }
+    fn raw_message(&self) -> &RawMessage {
+        &self.raw
+    }
+}
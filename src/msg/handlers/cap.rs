@@ -1,10 +1,134 @@
+use std::ascii::{AsciiExt};
+
 use cmd;
 use msg::RawMessage;
+use msg::raw::{MAX_LENGTH};
 
 use server::{Server};
 use con::{Peer, Connection};
+use con::reg;
+use config::{RegistrationPolicy};
+use sasl;
+
+/// Capability supported by the server
+struct Capability {
+    name: &'static str,
+    flag: reg::Extensions
+}
+
+impl Capability {
+    /// Checks whether the capability is currently offered
+    fn is_available(&self, server: &Server) -> bool {
+        match self.flag {
+            reg::SASL => server.accounts().is_some(),
+            reg::AccountRegistration => server.accounts().is_some() &&
+                server.config().accounts.registration != RegistrationPolicy::Closed,
+            _ => true
+        }
+    }
+
+    /// Value advertised to clients which support CAP LS 302
+    fn value(&self, server: &Server) -> Option<String> {
+        match self.flag {
+            reg::SASL => Some(sasl::MECHANISMS.connect(",")),
+            reg::AccountRegistration => match server.config().accounts.registration {
+                RegistrationPolicy::Open => Some("before-connect".to_string()),
+                RegistrationPolicy::Verify => Some("before-connect,email-required".to_string()),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    /// Name and value for a client which announced `version`
+    fn token(&self, server: &Server, version: uint) -> String {
+        match self.value(server) {
+            Some(ref value) if version >= 302 => format!("{}={}", self.name, value),
+            _ => self.name.to_string()
+        }
+    }
+}
+
+/// Registry of the capabilities supported by the server
+static CAPABILITIES: &'static [Capability] = &[
+    Capability { name: "cap-notify", flag: reg::CapNotify },
+    Capability { name: "draft/account-registration", flag: reg::AccountRegistration },
+    Capability { name: "message-tags", flag: reg::MessageTags },
+    Capability { name: "sasl", flag: reg::SASL },
+];
+
+/// Looks up a capability by name
+fn capability(name: &str) -> Option<&'static Capability> {
+    CAPABILITIES.iter().find(|cap| cap.name == name)
+}
+
+/// Looks up the name of a capability
+fn name(flag: reg::Extensions) -> Option<&'static str> {
+    CAPABILITIES.iter().find(|cap| cap.flag == flag).map(|cap| cap.name)
+}
+
+/// Sends a list of capabilities
+///
+/// Clients which support CAP LS 302 receive the list split over several
+/// lines, all but the last one are marked with `*`. Other clients get a
+/// single line.
+fn send_list(server: &Server, peer: &Peer, nick: &str, subcmd: &str,
+             tokens: &[String], multiline: bool) {
+    let host = server.host();
+    let overhead = format!(":{} CAP {} {} * :", host, nick, subcmd).len();
+    let mut lines = vec![String::new()];
+    for token in tokens.iter() {
+        let full = {
+            let line = lines.last().unwrap();
+            multiline && !line.is_empty() && line.len() + token.len() + 1 + overhead > MAX_LENGTH
+        };
+        if full {
+            lines.push(String::new())
+        }
+        let line = lines.last_mut().unwrap();
+        if !line.is_empty() {
+            line.push(' ')
+        }
+        line.push_str(token.as_slice())
+    }
+    let last = lines.len() - 1;
+    for (i, line) in lines.iter().enumerate() {
+        peer.send_msg(if i < last {
+            RawMessage::new(cmd::CAP, &[nick, subcmd, "*", line.as_slice()], Some(host))
+        } else {
+            RawMessage::new(cmd::CAP, &[nick, subcmd, line.as_slice()], Some(host))
+        })
+    }
+}
+
+/// Tells all clients which enabled `cap-notify` that capabilities
+/// became available (NEW) or were removed (DEL)
+pub fn notify(server: &Server, subcmd: &str, flags: &[reg::Extensions]) {
+    let caps: Vec<&Capability> = CAPABILITIES.iter().filter(
+        |cap| flags.contains(&cap.flag)
+    ).collect();
+    if caps.is_empty() {
+        return
+    }
+    for user in server.users.values() {
+        let info = user.info().read();
+        if info.has_capability(reg::CapNotify) {
+            let tokens: Vec<String> = caps.iter().map(
+                |cap| cap.token(server, info.cap_version())
+            ).collect();
+            user.send_msg(RawMessage::new(cmd::CAP, &[
+                info.nick().as_slice(), subcmd, tokens.connect(" ").as_slice()
+            ], Some(server.host())))
+        }
+    }
+}
 
 /// Handles the CAP command.
+///
+///    Command: CAP
+/// Parameters: <subcommand> [ <version> | <capabilities> ]
+///
+/// Registration is suspended from the first LS or REQ until END.
 #[deriving(Clone)]
 pub struct Cap {
     raw: RawMessage,
@@ -12,48 +136,145 @@ pub struct Cap {
     params: Vec<String>,
 }
 
+impl Cap {
+    /// Replies to LS with all capabilities
+    fn list_supported(&self, server: &Server, peer: &Peer, nick: &str) {
+        let version = self.params.get(0).and_then(|v| from_str::<uint>(v.as_slice()))
+                                        .unwrap_or(0);
+        let version = {
+            let mut info = peer.info().write();
+            info.set_cap_version(version);
+            // CAP LS 302 implicitly enables cap-notify
+            if info.cap_version() >= 302 {
+                info.add_capability(reg::CapNotify)
+            }
+            info.cap_version()
+        };
+        let tokens: Vec<String> = CAPABILITIES.iter().filter(
+            |cap| cap.is_available(server)
+        ).map(|cap| cap.token(server, version)).collect();
+        send_list(server, peer, nick, "LS", tokens.as_slice(), version >= 302)
+    }
+
+    /// Replies to LIST with the enabled capabilities
+    fn list_enabled(&self, server: &Server, peer: &Peer, nick: &str) {
+        let (tokens, version) = {
+            let info = peer.info().read();
+            let tokens: Vec<String> = info.capabilities().iter().filter_map(
+                |&cap| name(cap).map(|v| v.to_string())
+            ).collect();
+            (tokens, info.cap_version())
+        };
+        send_list(server, peer, nick, "LIST", tokens.as_slice(), version >= 302)
+    }
+
+    /// Enables or disables the requested capabilities
+    ///
+    /// A request is either accepted or rejected as a whole.
+    fn request(&self, server: &Server, peer: &Peer, nick: &str) {
+        let requested = self.params.connect(" ");
+        let mut changes = Vec::new();
+        let sticky_notify = peer.info().read().cap_version() >= 302;
+        for name in requested.as_slice().words() {
+            let (enable, name) = if name.starts_with("-") {
+                (false, name.slice_from(1))
+            } else {
+                (true, name)
+            };
+            match capability(name) {
+                // cap-notify cannot be disabled after CAP LS 302
+                Some(cap) if !enable && sticky_notify && cap.flag == reg::CapNotify => {
+                    changes.clear();
+                    break
+                },
+                Some(cap) if cap.is_available(server) => changes.push((enable, cap.flag)),
+                _ => {
+                    changes.clear();
+                    break
+                }
+            }
+        }
+        let reply = if changes.is_empty() {
+            "NAK"
+        } else {
+            let mut info = peer.info().write();
+            for &(enable, cap) in changes.iter() {
+                if enable {
+                    info.add_capability(cap)
+                } else {
+                    info.remove_capability(cap)
+                }
+            }
+            "ACK"
+        };
+        peer.send_msg(RawMessage::new(cmd::CAP, &[
+            nick, reply, requested.as_slice()
+        ], Some(server.host())))
+    }
+
+    fn handle(&self, server: &mut Server, peer: Peer, registered: bool) {
+        let nick = peer.info().read().nick().clone();
+        let nick = nick.as_slice();
+        match self.subcmd.as_slice() {
+            "LS" => {
+                if !registered {
+                    peer.info().write().set_negotiating(true)
+                }
+                self.list_supported(server, &peer, nick)
+            },
+            "LIST" => self.list_enabled(server, &peer, nick),
+            "REQ" => {
+                if !registered {
+                    peer.info().write().set_negotiating(true)
+                }
+                self.request(server, &peer, nick)
+            },
+            "END" => if !registered {
+                // Registration aborts an unfinished authentication
+                let aborted = server.connection_mut(peer.id()).map_or(
+                    false, |con| con.sasl().take().is_some()
+                );
+                if aborted {
+                    peer.send_response(cmd::ERR_SASLABORTED,
+                        &["SASL authentication aborted"], server.host()
+                    )
+                }
+                peer.info().write().set_negotiating(false);
+                super::registration::try_register(server, peer)
+            },
+            subcmd => peer.send_response(cmd::ERR_INVALIDCAPCMD,
+                &[subcmd, "Invalid CAP command"], server.host()
+            )
+        }
+    }
+}
+
 impl super::MessageHandler for Cap {
-    fn from_message(message: RawMessage) -> Result<Box<Cap>, Option<RawMessage>> { 
-        let params = message.params();
-        let mut params = params.iter().map(|&p| 
+    fn from_message(message: RawMessage) -> Result<Box<Cap>, Option<RawMessage>> {
+        let mut params = message.params().map(|p|
             String::from_utf8_lossy(p).to_string()
         );
-        let subcmd = if params.len() > 0 {
-            params.nth(0).unwrap()
-        } else { return Err(None) };
+        let subcmd = match params.next() {
+            Some(subcmd) => subcmd.as_slice().to_ascii_upper(),
+            None => return Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NEEDMOREPARAMS), &[
+                "*", message.command().to_string().as_slice(),
+                "not enought params given"
+            ], None)))
+        };
         Ok(box Cap {
             raw: message.clone(), subcmd: subcmd, params: params.collect()
         })
     }
-    
+
     fn invoke(&self, server: &mut Server, peer: Peer) {
-        let server_name = server.host().to_string();
-        info!("cap:invoke")
-        let this = (*self).clone();
-        spawn(proc() {
-            let info = peer.info().read();
-            let nick = info.nick().as_slice();
-            match this.subcmd.as_slice() {
-                "LS" => {
-                    peer.send_msg(RawMessage::new(cmd::CAP, &[
-                        nick, "LS", ""//, "multi-prefix sasl"
-                    ], Some(server_name.as_slice())))
-                },
-                "REQ" => {
-                    peer.send_msg(RawMessage::new(cmd::CAP, &[
-                        nick, "NAQ", this.params.connect(" ").as_slice()
-                    ], Some(server_name.as_slice())))
-                },
-                _ => {}
-            }
-        })
+        self.handle(server, peer, true)
     }
-    
+
     fn invoke_con(&self, server: &mut Server, origin: Connection) {
-        self.invoke(server, origin.peer())
+        self.handle(server, origin.peer(), false)
     }
-    
+
     fn raw_message(&self) -> &RawMessage {
         &self.raw
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,122 @@
+use std::ascii::{AsciiExt};
+
+use cmd;
+use msg::RawMessage;
+use account::{normalize_fingerprint};
+
+use server::{Server};
+use con::{Peer};
+
+/// Handles the CERT command
+///
+///    Command: CERT
+/// Parameters: ADD [ <fingerprint> ] | DEL <fingerprint> | LIST
+///
+/// Manages the TLS client certificates which can be used to log in to the
+/// account with SASL EXTERNAL. ADD without a fingerprint adds the
+/// certificate of the current connection. Only operators may add other
+/// fingerprints, everybody else has to prove that they own the certificate.
+pub struct Cert {
+    raw: RawMessage,
+    subcmd: String,
+    fingerprint: Option<String>
+}
+
+impl Cert {
+    fn notice(server: &Server, origin: &Peer, nick: &str, text: &str) {
+        origin.send_msg(RawMessage::new(cmd::NOTICE, &[nick, text], Some(server.host())))
+    }
+}
+
+impl super::MessageHandler for Cert {
+    fn from_message(message: RawMessage) -> Result<Box<Cert>, Option<RawMessage>> {
+        match message.param(0) {
+            Some(subcmd) => Ok(box Cert {
+                raw: message.clone(),
+                subcmd: String::from_utf8_lossy(subcmd).as_slice().to_ascii_upper(),
+                fingerprint: message.param(1).map(
+                    |v| String::from_utf8_lossy(v).into_string()
+                )
+            }),
+            None => Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NEEDMOREPARAMS), &[
+                "*", message.command().to_string().as_slice(),
+                "not enought params given"
+            ], None)))
+        }
+    }
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        let (nick, account, certfp, oper) = {
+            let info = origin.info().read();
+            (info.nick().clone(), info.account().map(|v| v.to_string()),
+             info.certfp().map(|v| v.to_string()), info.is_oper())
+        };
+        let nick = nick.as_slice();
+        let account = match account {
+            Some(account) => account,
+            None => return Cert::notice(server, &origin, nick, "You are not logged in")
+        };
+        if server.accounts().is_none() {
+            return Cert::notice(server, &origin, nick, "Accounts are not available")
+        }
+        let fingerprint = match (self.subcmd.as_slice(), &self.fingerprint) {
+            ("LIST", _) => {
+                let fingerprints = server.accounts().unwrap().fingerprints(account.as_slice());
+                for fingerprint in fingerprints.iter() {
+                    Cert::notice(server, &origin, nick, fingerprint.as_slice())
+                }
+                return Cert::notice(server, &origin, nick, format!(
+                    "End of the certificate list of {}", account
+                ).as_slice())
+            },
+            ("ADD", &None) => match certfp {
+                Some(certfp) => certfp,
+                None => return Cert::notice(server, &origin, nick,
+                    "You are not using a TLS client certificate"
+                )
+            },
+            ("ADD", &Some(ref fingerprint)) | ("DEL", &Some(ref fingerprint)) => {
+                match normalize_fingerprint(fingerprint.as_slice()) {
+                    Some(ref fingerprint) if self.subcmd.as_slice() == "ADD" && !oper
+                                             && Some(fingerprint) != certfp.as_ref() => {
+                        return Cert::notice(server, &origin, nick,
+                            "You can only add the certificate you are connected with"
+                        )
+                    },
+                    Some(fingerprint) => fingerprint,
+                    None => return Cert::notice(server, &origin, nick, format!(
+                        "{} is not a SHA-256 fingerprint", fingerprint
+                    ).as_slice())
+                }
+            },
+            ("DEL", &None) => return Cert::notice(server, &origin, nick,
+                "Which fingerprint should be removed?"
+            ),
+            (subcmd, _) => return Cert::notice(server, &origin, nick, format!(
+                "Invalid CERT command {}, use ADD, DEL or LIST", subcmd
+            ).as_slice())
+        };
+        let adding = self.subcmd.as_slice() == "ADD";
+        let result = {
+            let accounts = server.accounts_mut().unwrap();
+            if adding {
+                accounts.add_fingerprint(account.as_slice(), fingerprint.as_slice())
+            } else {
+                accounts.remove_fingerprint(account.as_slice(), fingerprint.as_slice())
+            }
+        };
+        let text = match result {
+            Ok(true) if adding => format!("Added {} to {}", fingerprint, account),
+            Ok(true) => format!("Removed {} from {}", fingerprint, account),
+            Ok(false) if adding => format!("{} is already in use", fingerprint),
+            Ok(false) => format!("{} does not belong to {}", fingerprint, account),
+            Err(err) => {
+                error!("cannot save the accounts: {}", err);
+                "Cannot save the account".to_string()
+            }
+        };
+        Cert::notice(server, &origin, nick, text.as_slice())
+    }
+    fn raw_message(&self) -> &RawMessage {
+        &self.raw
+    }
+}
@@ -0,0 +1,257 @@
+use std::ascii::{AsciiExt};
+
+use cmd;
+use channel;
+use channel::registry::{Registration, AccessLevel};
+use msg::RawMessage;
+use util;
+
+use server::{Server};
+use con::{Peer};
+
+/// Handles the CHANNEL command
+///
+///    Command: CHANNEL
+/// Parameters: REGISTER <channel> | DROP <channel> |
+///             ACCESS <channel> [ LIST | ADD <account> <level> | DEL <account> ]
+///
+/// Manages registered channels. Channel operators register a channel with
+/// their account as founder, the founder manages the access list of
+/// registered accounts. The levels are `auto-op`, `auto-voice` and `ban`.
+/// Operators can drop any channel.
+pub struct Channel {
+    raw: RawMessage,
+    subcmd: String,
+    channel: String,
+    args: Vec<String>
+}
+
+impl Channel {
+    /// Registers the channel if the origin is an operator of it
+    fn register(&self, server: &mut Server, origin: &Peer, account: String) -> String {
+        let (tx, rx) = channel();
+        let id = origin.id();
+        match server.get_channel(self.channel.as_slice()) {
+            Some(channel) => channel.send(channel::Handle(proc(channel) {
+                let is_op = channel.member_with_id(id).map_or(false, |m| m.is_op());
+                let _ = tx.send_opt((is_op, channel.name().to_string(), channel.state()));
+            })),
+            None => return format!("{} does not exist", self.channel)
+        }
+        let (name, state) = match rx.recv_opt() {
+            Ok((true, name, state)) => (name, state),
+            _ => return format!("You are not an operator of {}", self.channel)
+        };
+        let result = server.channel_registry_mut().unwrap().register(Registration {
+            name: name.clone(),
+            founder: account.clone(),
+            state: state,
+            access: Vec::new()
+        });
+        match result {
+            Ok(true) => format!("Registered {} to {}", name, account),
+            Ok(false) => format!("{} is already registered", name),
+            Err(err) => {
+                error!("cannot save the registered channels: {}", err);
+                "Cannot save the channel".to_string()
+            }
+        }
+    }
+
+    /// Manages the access list, only the founder may do this
+    fn access(&self, server: &mut Server, origin: &Peer) -> String {
+        let key = server.key(self.channel.as_slice());
+        let registration = server.channel_registry().unwrap().get(&key).unwrap().clone();
+        let args: Vec<&str> = self.args.iter().map(|v| v.as_slice()).collect();
+        let subcmd = args.as_slice().get(0).map_or("LIST".to_string(), |v| v.to_ascii_upper());
+        let (account, level) = match (subcmd.as_slice(), args.as_slice()) {
+            ("LIST", _) => {
+                server.server_notice(origin, format!(
+                    "{} founder", registration.founder
+                ).as_slice());
+                for &(ref account, ref level) in registration.access.iter() {
+                    server.server_notice(origin, format!(
+                        "{} {}", account, level.name()
+                    ).as_slice())
+                }
+                return format!("End of the access list of {}", registration.name)
+            },
+            ("ADD", [_, account, level]) => match AccessLevel::from_name(level) {
+                Some(level) => (account, Some(level)),
+                None => return format!(
+                    "Unknown access level {}, use auto-op, auto-voice or ban", level
+                )
+            },
+            ("DEL", [_, account]) => (account, None),
+            ("ADD", _) => return "Which account and level?".to_string(),
+            ("DEL", _) => return "Which account?".to_string(),
+            (subcmd, _) => return format!(
+                "Invalid ACCESS command {}, use LIST, ADD or DEL", subcmd
+            )
+        };
+        if registration.is_founder(account, server.config().server.casemapping) {
+            return format!("{} is the founder of {}", account, registration.name)
+        }
+        // Entries are added with the name the account was registered with
+        let account = match level {
+            Some(_) => match server.accounts().and_then(|a| a.account_name(account)) {
+                Some(name) => name,
+                None => return format!("{} is not a registered account", account)
+            },
+            None => account.to_string()
+        };
+        let result = server.channel_registry_mut().unwrap().set_access(
+            &key, account.as_slice(), level.clone()
+        );
+        match (result, level) {
+            (Ok(true), Some(level)) => format!(
+                "{} has {} access to {}", account, level.name(), registration.name
+            ),
+            (Ok(true), None) => format!(
+                "Removed {} from the access list of {}", account, registration.name
+            ),
+            (Ok(false), _) => "Nothing changed".to_string(),
+            (Err(err), _) => {
+                error!("cannot save the registered channels: {}", err);
+                "Cannot save the channel".to_string()
+            }
+        }
+    }
+}
+
+impl super::MessageHandler for Channel {
+    fn from_message(message: RawMessage) -> Result<Box<Channel>, Option<RawMessage>> {
+        let params = message.params();
+        if params.len() < 2 {
+            return Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NEEDMOREPARAMS), &[
+                "*", message.command().to_string().as_slice(),
+                "not enought params given"
+            ], None)))
+        }
+        let channel = match util::verify_channel(params[1]) {
+            Some(channel) => channel.to_string(),
+            None => return Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NOSUCHCHANNEL), &[
+                "*", String::from_utf8_lossy(params[1]).as_slice(),
+                "Invalid channel name."
+            ], None)))
+        };
+        Ok(box Channel {
+            raw: message.clone(),
+            subcmd: String::from_utf8_lossy(params[0]).as_slice().to_ascii_upper(),
+            channel: channel,
+            args: params.slice_from(2).iter().map(
+                |v| String::from_utf8_lossy(*v).into_string()
+            ).collect()
+        })
+    }
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        let (account, oper) = {
+            let info = origin.info().read();
+            (info.account().map(|v| v.to_string()), info.is_oper())
+        };
+        let account = match account {
+            Some(account) => account,
+            None => return server.server_notice(&origin, "You are not logged in")
+        };
+        if server.channel_registry().is_none() {
+            return server.server_notice(&origin, "Channel registration is not available")
+        }
+        let key = server.key(self.channel.as_slice());
+        let casemapping = server.config().server.casemapping;
+        let founder = server.channel_registry().unwrap().get(&key).map(
+            |r| r.is_founder(account.as_slice(), casemapping)
+        );
+        let text = match (self.subcmd.as_slice(), founder) {
+            ("REGISTER", _) => self.register(server, &origin, account),
+            ("DROP", None) | ("ACCESS", None) => format!("{} is not registered", self.channel),
+            ("DROP", Some(founder)) if founder || oper => {
+                match server.channel_registry_mut().unwrap().unregister(&key) {
+                    Ok(_) => format!("Dropped {}", self.channel),
+                    Err(err) => {
+                        error!("cannot save the registered channels: {}", err);
+                        "Cannot save the channel".to_string()
+                    }
+                }
+            },
+            ("ACCESS", Some(true)) => self.access(server, &origin),
+            ("DROP", Some(_)) | ("ACCESS", Some(_)) => format!(
+                "You are not the founder of {}", self.channel
+            ),
+            (subcmd, _) => format!(
+                "Invalid CHANNEL command {}, use REGISTER, DROP or ACCESS", subcmd
+            )
+        };
+        server.server_notice(&origin, text.as_slice())
+    }
+    fn raw_message(&self) -> &RawMessage {
+        &self.raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::{MessageHandler, get_handler};
+	use account::{ScramCredentials};
+	use channel::{Channel};
+	use channel::registry::{Registration, AutoOp};
+	use config::{Config};
+	use con::{Peer, Outgoing, detached_connection};
+	use msg::RawMessage;
+	use server::{Server};
+	use std::io::{File, TempDir};
+
+	/// Sends a command and returns the last reply
+	fn send(server: &mut Server, peer: &Peer, out: &Outgoing, line: &str) -> String {
+		let handler = get_handler(RawMessage::parse(line.as_bytes()).unwrap()).ok().unwrap();
+		handler.invoke(server, peer.clone());
+		out.messages().last().unwrap().to_string()
+	}
+
+	/// Test that access entries hold the name the account was registered with
+	#[test]
+	fn test_access() {
+		let dir = TempDir::new("chatd").unwrap();
+		let accounts = dir.path().join("accounts.txt");
+		File::create(&accounts).unwrap();
+		let config = Config::from_str(format!(r#"
+[[listen]]
+address = "127.0.0.1"
+
+[channels]
+registry = "{}"
+
+[accounts]
+file = "{}"
+"#, dir.path().join("channels.txt").display(), accounts.display()).as_slice());
+		let mut server = Server::new(config.unwrap()).unwrap();
+		assert!(server.accounts_mut().unwrap().register(
+			"Bob[", ScramCredentials::new(b"secret")
+		).unwrap())
+		let state = Channel::new("#rust".to_string(), server.config()).state();
+		assert!(server.channel_registry_mut().unwrap().register(Registration {
+			name: "#rust".to_string(),
+			founder: "alice".to_string(),
+			state: state,
+			access: Vec::new()
+		}).unwrap())
+		let (con, out) = detached_connection(server.host());
+		let peer = con.peer();
+		{
+			let mut info = peer.info().write();
+			info.set_nick("alice".to_string());
+			info.set_account(Some("ALICE".to_string()));
+		}
+		assert!(send(&mut server, &peer, &out, "CHANNEL ACCESS #rust ADD carol auto-op")
+			.as_slice().contains("carol is not a registered account"))
+		assert!(send(&mut server, &peer, &out, "CHANNEL ACCESS #RUST ADD bob{ auto-op")
+			.as_slice().contains("Bob[ has auto-op access to #rust"))
+		let key = server.key("#rust");
+		assert_eq!(server.channel_registry().unwrap().get(&key).unwrap().access,
+			vec![("Bob[".to_string(), AutoOp)])
+		assert!(send(&mut server, &peer, &out, "CHANNEL ACCESS #rust ADD Alice auto-op")
+			.as_slice().contains("Alice is the founder of #rust"))
+		assert!(send(&mut server, &peer, &out, "CHANNEL ACCESS #rust DEL BOB[")
+			.as_slice().contains("Removed BOB[ from the access list of #rust"))
+		assert!(server.channel_registry().unwrap().get(&key).unwrap().access.is_empty())
+	}
+}
@@ -1,18 +1,21 @@
 use cmd;
 use channel;
-use channel::util::{InviteOnly, ChannelCreator, OperatorPrivilege, TopicProtect, MemberOnly, UserLimit};
+use channel::util::{InviteOnly, ChannelCreator, OperatorPrivilege, VoicePrivilege, UserLimit};
+use channel::registry::{AccessLevel, AutoOp, AutoVoice, Ban};
 use msg::RawMessage;
 use util;
 
 use server::{Server};
 use con::Peer;
 
-use std::collections::hash_map::{Vacant, Occupied};
-
 /// Handles the JOIN command.
 ///
 ///    Command: JOIN
 /// Parameters: <channel>{,<channel>} [<key>{,<key>}]
+///
+/// Registered channels get their stored topic, modes and masks back when
+/// they are created again. Their access list decides who is opped, voiced
+/// or kept out.
 pub struct Join {
     raw: RawMessage,
     targets: Vec<String>,
@@ -20,7 +23,17 @@ pub struct Join {
 }
 
 impl Join {
-    fn handle_join(channel: &mut channel::Channel, mut member: channel::Member, password: Option<Vec<u8>>) {
+    fn handle_join(channel: &mut channel::Channel, mut member: channel::Member, password: Option<Vec<u8>>,
+                   registered: bool, access: Option<AccessLevel>) {
+        if access == Some(Ban) {
+            // Account banned from the registered channel
+            channel.send_response(
+                member.proxy(), 
+                cmd::ERR_BANNEDFROMCHAN, 
+                &["Cannot join channel (access list)"]
+            );
+            return
+        }
         match channel.password() {
             &Some(ref chan_pass) => if !match password { 
                     Some(password) => &password == chan_pass,
@@ -67,10 +80,14 @@ impl Join {
             );
             return
         }
-        // Give op to first user
-        if channel.member_count() == 0 {
-            member.promote(ChannelCreator);
-            member.promote(OperatorPrivilege);
+        // Give op to first user, registered channels use their access list
+        match access {
+            Some(AutoOp) => member.promote(OperatorPrivilege),
+            Some(AutoVoice) => member.promote(VoicePrivilege),
+            _ => if !registered && channel.member_count() == 0 {
+                member.promote(ChannelCreator);
+                member.promote(OperatorPrivilege);
+            }
         }
         
         // Broadcast that a new member joined the channel and add him
@@ -80,14 +97,21 @@ impl Join {
             Some(member.nick())
         );
         let id = member.id().clone();
+        member.proxy().info().write().add_channel(channel.key());
         let _ = channel.add_member(member);
         channel.broadcast(msg);
         
         // Topic reply
         let member = channel.member_with_id(id).unwrap();
-        member.send_response(cmd::RPL_NOTOPIC, 
-            &[channel.name(), "No topic set."]
-        );
+        if channel.topic().is_empty() {
+            member.send_response(cmd::RPL_NOTOPIC, 
+                &[channel.name(), "No topic set."]
+            );
+        } else {
+            member.send_response(cmd::RPL_TOPIC, 
+                &[channel.name(), String::from_utf8_lossy(channel.topic()).as_slice()]
+            );
+        }
         // Send name list as per RFC
         super::lists::Names::handle_names(channel, member.proxy());
     }
@@ -132,23 +156,45 @@ impl super::MessageHandler for Join {
     }
     
     fn invoke(&self, server: &mut Server, origin: Peer) {
-        let host = server.host().to_string(); // clone due to #6393
+        let channel_length = server.config().limits.channel_length;
+        let account = origin.info().read().account().map(|v| v.to_string());
         for (channel, password) in self.targets.iter()
                                    .zip(self.passwords.iter()) {
+            if channel.len() > channel_length {
+                origin.send_response(cmd::ERR_NOSUCHCHANNEL,
+                    &[channel.as_slice(), "Channel name too long"],
+                    server.host()
+                );
+                continue
+            }
             let member = channel::Member::new(origin.clone());
-            let tx = server.tx().unwrap(); // save to unwrap, this should exist by now
             let password = password.clone();
-            match server.channels.entry(channel.to_string()) {
-                Occupied(entry) => entry.into_mut(),
-                Vacant(entry) => {
-                    let mut channel = channel::Channel::new(channel.to_string(), host.clone());
-                    channel.add_flag(TopicProtect);
-                    channel.add_flag(MemberOnly);
-                    entry.set(channel.listen(tx.clone()))
-                }
-            }.send(
+            let key = server.key(channel.as_slice());
+            let registration = server.channel_registry().and_then(
+                |registry| registry.get(&key).map(|v| v.clone())
+            );
+            let registered = registration.is_some();
+            let access = match (&registration, &account) {
+                (&Some(ref registration), &Some(ref account)) =>
+                    registration.access(account.as_slice(), server.config().server.casemapping),
+                _ => None
+            };
+            if !server.channels.contains_key(&key) {
+                let channel = match registration {
+                    Some(registration) => {
+                        let mut channel = channel::Channel::new(
+                            registration.name, server.config()
+                        );
+                        channel.restore(&registration.state);
+                        channel
+                    },
+                    None => channel::Channel::new(channel.to_string(), server.config())
+                };
+                server.channels.insert(key.clone(), channel::Proxy::new(channel));
+            }
+            server.channels.get(&key).unwrap().send(
                 channel::HandleMut(proc(channel) {
-                    Join::handle_join(channel, member, password)
+                    Join::handle_join(channel, member, password, registered, access)
                 })
             )
         }
@@ -157,4 +203,64 @@ impl super::MessageHandler for Join {
     fn raw_message(&self) -> &RawMessage {
         &self.raw
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Join;
+	use channel::{Channel, Member};
+	use channel::registry::{AutoOp, AutoVoice, Ban};
+	use config::{Config};
+	use con::{Connection, Outgoing, detached_connection};
+
+	fn connection(nick: &str) -> (Connection, Outgoing) {
+		let (con, out) = detached_connection("localhost");
+		con.peer().info().write().set_nick(nick.to_string());
+		(con, out)
+	}
+
+	/// Test joining a registered channel with the access levels
+	#[test]
+	fn test_registered_join() {
+		let config = Config::from_str("").unwrap();
+		let mut state = Channel::new("#rust".to_string(), &config).state();
+		state.topic = b"Welcome to #rust".to_vec();
+		state.modes = "nt".to_string();
+		let mut channel = Channel::new("#rust".to_string(), &config);
+		channel.restore(&state);
+		assert_eq!(channel.state().topic, state.topic)
+		// Registered channels do not op the first member
+		let (alice, out) = connection("alice");
+		Join::handle_join(&mut channel, Member::new(alice.peer()), None, true, None);
+		let member = channel.member_with_id(alice.peer().id()).unwrap();
+		assert!(!member.is_op() && !member.has_voice())
+		assert!(out.messages().iter().any(
+			|msg| msg.to_string().as_slice().contains(" 332 alice #rust :Welcome to #rust")
+		))
+		let (bob, _) = connection("bob");
+		Join::handle_join(&mut channel, Member::new(bob.peer()), None, true, Some(AutoOp));
+		assert!(channel.member_with_id(bob.peer().id()).unwrap().is_op())
+		let (carol, _) = connection("carol");
+		Join::handle_join(&mut channel, Member::new(carol.peer()), None, true, Some(AutoVoice));
+		let member = channel.member_with_id(carol.peer().id()).unwrap();
+		assert!(!member.is_op() && member.has_voice())
+		let (mallory, out) = connection("mallory");
+		Join::handle_join(&mut channel, Member::new(mallory.peer()), None, true, Some(Ban));
+		assert!(channel.member_with_id(mallory.peer().id()).is_none())
+		assert!(out.messages().last().unwrap().to_string().as_slice().contains(" 474 mallory "))
+		assert_eq!(channel.member_count(), 3)
+	}
+
+	/// Test that the first member of an unregistered channel is opped
+	#[test]
+	fn test_unregistered_join() {
+		let config = Config::from_str("").unwrap();
+		let mut channel = Channel::new("#rust".to_string(), &config);
+		let (alice, _) = connection("alice");
+		Join::handle_join(&mut channel, Member::new(alice.peer()), None, false, None);
+		assert!(channel.member_with_id(alice.peer().id()).unwrap().is_op())
+		let (bob, _) = connection("bob");
+		Join::handle_join(&mut channel, Member::new(bob.peer()), None, false, None);
+		assert!(!channel.member_with_id(bob.peer().id()).unwrap().is_op())
+	}
+}
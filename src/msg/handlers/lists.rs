@@ -1,3 +1,5 @@
+use std::uint;
+
 use cmd;
 use channel;
 use channel::{Channel};
@@ -66,10 +68,10 @@ impl Who {
 }
 impl super::MessageHandler for Who {
     fn from_message(message: RawMessage) -> Result<Box<Who>, Option<RawMessage>> {
-        let mask = message.params().as_slice().get(0).map_or("0".to_string(),
-            |&v| String::from_utf8_lossy(v).to_string());
-        let op_only = match message.params().as_slice().get(1) {
-            Some(&o) => o == b"o",
+        let mask = message.param(0).map_or("0".to_string(),
+            |v| String::from_utf8_lossy(v).to_string());
+        let op_only = match message.param(1) {
+            Some(o) => o == b"o",
             None => false
         };
         Ok(box Who {
@@ -77,7 +79,7 @@ impl super::MessageHandler for Who {
         })
     }
     fn invoke(&self, server: &mut Server, origin: Peer) {
-        match server.channels.get(&self.mask) {
+        match server.get_channel(self.mask.as_slice()) {
             Some(channel) => {
                 let this = (*self).clone();
                 channel.send(channel::Handle(proc(channel) {
@@ -101,13 +103,11 @@ impl Names {
     /// Sends the list of users to the client
     pub fn handle_names(channel: &Channel, proxy: &Peer) {
         // TODO check if channel is visible to user…
-        // TODO replace with generic list sending function
-        for member in channel.members() {
-            let mut tmp = String::from_str("= ");
-            tmp.push_str(channel.name());
+        let nicks: Vec<&str> = channel.members().map(|m| m.decorated_nick()).collect();
+        let room = proxy.response_room(&["=", channel.name()], channel.server_name());
+        for line in util::pack_words(nicks.as_slice(), room, uint::MAX).iter() {
             channel.send_response(proxy, cmd::RPL_NAMREPLY, &[
-                tmp.as_slice(),
-                member.decorated_nick()   
+                "=", channel.name(), line.as_slice().connect(" ").as_slice()
             ])
         }
         channel.send_response(proxy, cmd::RPL_ENDOFNAMES, 
@@ -135,7 +135,7 @@ impl super::MessageHandler for Names {
         for recv in self.receivers.iter() {
             match recv {
                 &util::ChannelName(ref name) => {
-                    match server.channels.get_mut(&name.to_string()) {
+                    match server.get_channel(name.as_slice()) {
                         Some(channel) => { 
                             let proxy = origin.clone();
                             channel.send(channel::Handle(proc(channel) {
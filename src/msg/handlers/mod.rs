@@ -15,6 +15,12 @@ mod lists;
 mod simple;
 mod ping_pong;
 mod cap;
+mod authenticate;
+mod cert;
+mod chanreg;
+mod nicks;
+mod register;
+mod oper;
 
 macro_rules! handle {
     {$(
@@ -42,6 +48,7 @@ pub fn get_handler(message: RawMessage) -> Result<Box<MessageHandler + Send>, Op
 handle!{
     PRIVMSG with self::msg::Msg;
     NOTICE with self::msg::Msg;
+    TAGMSG with self::msg::Msg;
     NAMES with self::lists::Names;
     WHO with self::lists::Who;
     MODE with self::mode::Mode;
@@ -54,6 +61,16 @@ handle!{
     PING with self::ping_pong::Ping;
     PONG with self::ping_pong::Pong;
     CAP with self::cap::Cap;
+    AUTHENTICATE with self::authenticate::Authenticate;
+    CERT with self::cert::Cert;
+    CHANNEL with self::chanreg::Channel;
+    NICKS with self::nicks::Nicks;
+    REGISTER with self::register::Register;
+    VERIFY with self::register::Verify;
+    FAIL with self::Reply;
+    OPER with self::oper::Oper;
+    MOTD with self::simple::Motd;
+    REHASH with self::oper::Rehash;
 }
 
 ///// Temporary dispatcher
@@ -128,8 +145,20 @@ impl MessageHandler for ExtensionHandler {
     fn from_message(message: RawMessage) -> Result<Box<ExtensionHandler>, Option<RawMessage>> {
         Ok(box ExtensionHandler { raw: message })
     }
-    fn invoke(&self, _: &mut Server, _: Peer) {
-        error!("Handling of message {} not implemented yet", self.raw.command().to_string())
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        self.unknown_command(server, &origin)
+    }
+    fn invoke_con(&self, server: &mut Server, con: Connection) {
+        self.unknown_command(server, &con.peer())
     }
     fn raw_message(&self) -> &RawMessage { &self.raw }
+}
+impl ExtensionHandler {
+    fn unknown_command(&self, server: &Server, origin: &Peer) {
+        debug!("unknown command {}", self.raw.command().to_string())
+        origin.send_response(cmd::ERR_UNKNOWNCOMMAND,
+            &[self.raw.command().to_string().as_slice(), "Unknown command"],
+            server.host()
+        )
+    }
 }
\ No newline at end of file
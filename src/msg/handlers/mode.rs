@@ -74,7 +74,7 @@ impl Mode {
                     },
                     OperatorPrivilege | VoicePrivilege => {
                         if let Some(name) = parameter {
-                            let nick = match channel.mut_member_with_nick(&String::from_utf8_lossy(name).to_string()) {
+                            let nick = match channel.mut_member_with_nick(String::from_utf8_lossy(name).as_slice()) {
                                 Some(member) => match action {
                                     Add => {
                                         member.promote(mode);
@@ -128,6 +128,13 @@ impl Mode {
                             let host_mask = util::HostMask::new(
                                 String::from_utf8_lossy(mask).to_string()
                             );
+                            if action == Add && channel.lists_full() {
+                                proxy.send_response(cmd::ERR_BANLISTFULL,
+                                    &[channel.name(), host_mask.as_str(), "Channel list is full"],
+                                    channel.server_name()
+                                );
+                                return
+                            }
                             match mode {
                                 BanMask => match action {
                                     Add => {channel.add_ban_mask(host_mask);},
@@ -223,7 +230,7 @@ impl super::MessageHandler for Mode {
         let ref raw = self.raw;
         match self.receiver {
             util::ChannelName(ref name) => {
-                match server.channels.get_mut(&name.to_string()) {
+                match server.get_channel(name.as_slice()) {
                     Some(channel) =>  {
                         let raw = raw.clone();
                         channel.send(channel::HandleMut(proc(channel) {
@@ -235,6 +242,7 @@ impl super::MessageHandler for Mode {
                             host.as_slice()
                     )
                 }
+                server.store_channel(name.as_slice())
             },
             _ => error!("user modes not supported yet")
         }
@@ -8,7 +8,7 @@ use util;
 use server::{Server};
 use con::{Peer, PeerId};
 
-/// handles PRIVMSG and NOTICE messages
+/// handles PRIVMSG, NOTICE and TAGMSG messages
 #[allow(dead_code)]
 pub struct Msg {
     raw: RawMessage,
@@ -49,14 +49,16 @@ impl Msg {
 impl super::MessageHandler for Msg {
     fn from_message(message: RawMessage) -> Result<Box<Msg>, Option<RawMessage>> {
         let params = message.params();
-        if params.len() > 1 {
+        // TAGMSG only carries tags and has no text
+        let min_params = if message.command() == cmd::TAGMSG { 1 } else { 2 };
+        if params.len() >= min_params {
             Ok(box Msg {
                 raw: message.clone(), 
                 receiver: params[0].as_slice()
                                    .split(|&v| v == b',' )
                                    .map(|v| util::verify_receiver(v))
                                    .collect(),
-                message: params[1].to_vec()
+                message: params.get(1).unwrap_or(b"").to_vec()
             })
         } else {
             if message.command() != cmd::NOTICE {
@@ -68,11 +70,14 @@ impl super::MessageHandler for Msg {
         }
     }
     fn invoke(&self, server: &mut Server, origin: Peer) {
+        if self.raw.command() == cmd::TAGMSG && !self.raw.has_tags() {
+            return
+        }
         let mut raw = self.raw.clone();
         raw.set_prefix(origin.info().read().nick().as_slice());
         for receiver in self.receiver.iter() {
             match *receiver {
-                util::ChannelName(ref name) => match server.channels.get_mut(&name.to_string()) {
+                util::ChannelName(ref name) => match server.get_channel(name.as_slice()) {
                     Some(channel) => {
                         let id = origin.id();
                         let message = raw.clone();
@@ -82,7 +87,7 @@ impl super::MessageHandler for Msg {
                     },
                     None => {}
                 },
-                util::NickName(ref nick) => match server.get_peer(&nick.to_string()) {
+                util::NickName(ref nick) => match server.get_peer(nick.as_slice()) {
                     Some(client) => {
                         client.send_msg(raw.clone());
                     },
@@ -0,0 +1,266 @@
+use std::ascii::{AsciiExt};
+
+use cmd;
+use msg::RawMessage;
+
+use server::{Server};
+use con::{Peer};
+
+/// Handles the NICKS command
+///
+///    Command: NICKS
+/// Parameters: GROUP | UNGROUP <nick> | LIST | RELEASE <nick> | REGAIN <nick>
+///
+/// Manages the nicks owned by the account of the user. GROUP adds the
+/// current nick to the account. RELEASE renames a session which uses an
+/// owned nick to a guest nick, REGAIN additionally takes the nick over.
+pub struct Nicks {
+    raw: RawMessage,
+    subcmd: String,
+    nick: Option<String>
+}
+
+impl Nicks {
+    /// Renames the peer using `nick` to a guest nick
+    ///
+    /// Returns false if nobody else uses the nick and an error if every
+    /// guest nick is taken.
+    fn release(server: &mut Server, origin: &Peer, nick: &str) -> Result<bool, ()> {
+        let holder = match server.nicks.get(&server.key(nick)) {
+            Some(id) if *id != origin.id() => server.users.get(id).map(|v| v.clone()),
+            _ => None
+        };
+        match holder {
+            Some(holder) => {
+                let guest = match server.guest_nick() {
+                    Some(guest) => guest,
+                    None => return Err(())
+                };
+                server.server_notice(&holder, format!(
+                    "{} was released by its owner, your nick was changed to {}", nick, guest
+                ).as_slice());
+                server.change_nick(&holder, guest);
+                server.check_nick_owner(&holder);
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+}
+
+impl super::MessageHandler for Nicks {
+    fn from_message(message: RawMessage) -> Result<Box<Nicks>, Option<RawMessage>> {
+        match message.param(0) {
+            Some(subcmd) => Ok(box Nicks {
+                raw: message.clone(),
+                subcmd: String::from_utf8_lossy(subcmd).as_slice().to_ascii_upper(),
+                nick: message.param(1).map(
+                    |v| String::from_utf8_lossy(v).into_string()
+                )
+            }),
+            None => Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NEEDMOREPARAMS), &[
+                "*", message.command().to_string().as_slice(),
+                "not enought params given"
+            ], None)))
+        }
+    }
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        let (current, account) = {
+            let info = origin.info().read();
+            (info.nick().clone(), info.account().map(|v| v.to_string()))
+        };
+        let account = match account {
+            Some(account) => account,
+            None => return server.server_notice(&origin, "You are not logged in")
+        };
+        if server.accounts().is_none() {
+            return server.server_notice(&origin, "Accounts are not available")
+        }
+        let text = match (self.subcmd.as_slice(), &self.nick) {
+            ("LIST", _) => {
+                let nicks = server.accounts().unwrap().nicks(account.as_slice());
+                for nick in nicks.iter() {
+                    server.server_notice(&origin, nick.as_slice())
+                }
+                format!("End of the nick list of {}", account)
+            },
+            ("GROUP", _) => {
+                let result = server.accounts_mut().unwrap().group_nick(
+                    account.as_slice(), current.as_slice()
+                );
+                match result {
+                    Ok(true) => format!("Grouped {} under {}", current, account),
+                    Ok(false) => format!("{} is already owned by an account", current),
+                    Err(err) => {
+                        error!("cannot save the accounts: {}", err);
+                        "Cannot save the account".to_string()
+                    }
+                }
+            },
+            ("UNGROUP", &Some(ref nick)) => {
+                let result = server.accounts_mut().unwrap().ungroup_nick(
+                    account.as_slice(), nick.as_slice()
+                );
+                match result {
+                    Ok(true) => format!("Removed {} from {}", nick, account),
+                    Ok(false) => format!("{} is not grouped under {}", nick, account),
+                    Err(err) => {
+                        error!("cannot save the accounts: {}", err);
+                        "Cannot save the account".to_string()
+                    }
+                }
+            },
+            ("RELEASE", &Some(ref nick)) | ("REGAIN", &Some(ref nick)) => {
+                let owner = server.accounts().unwrap().nick_owner(nick.as_slice());
+                match owner {
+                    Some(ref owner) if server.key(owner.as_slice())
+                        == server.key(account.as_slice()) => (),
+                    _ => return server.server_notice(&origin, format!(
+                        "{} does not belong to {}", nick, account
+                    ).as_slice())
+                }
+                if server.key(nick.as_slice()) == server.key(current.as_slice()) {
+                    return server.server_notice(&origin, format!(
+                        "You are already using {}", nick
+                    ).as_slice())
+                }
+                let regain = self.subcmd.as_slice() == "REGAIN";
+                if regain && !server.valid_nick(nick.as_slice()) {
+                    return origin.send_response(cmd::ERR_ERRONEUSNICKNAME,
+                        &[nick.as_slice(), "invalid nick name"], server.host()
+                    )
+                }
+                let released = match Nicks::release(server, &origin, nick.as_slice()) {
+                    Ok(released) => released,
+                    Err(()) => return server.server_notice(&origin, format!(
+                        "{} cannot be released right now, try again later", nick
+                    ).as_slice())
+                };
+                if regain {
+                    server.change_nick(&origin, nick.clone());
+                    server.check_nick_owner(&origin);
+                    return
+                } else if released {
+                    format!("Released {}", nick)
+                } else {
+                    format!("{} is not in use", nick)
+                }
+            },
+            ("UNGROUP", &None) | ("RELEASE", &None) | ("REGAIN", &None) =>
+                "Which nick?".to_string(),
+            (subcmd, _) => format!(
+                "Invalid NICKS command {}, use GROUP, UNGROUP, LIST, RELEASE or REGAIN", subcmd
+            )
+        };
+        server.server_notice(&origin, text.as_slice())
+    }
+    fn raw_message(&self) -> &RawMessage {
+        &self.raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::{MessageHandler, get_handler};
+	use account::{ScramCredentials};
+	use config::{Config};
+	use con::{Connection, Outgoing, detached_connection};
+	use msg::RawMessage;
+	use server::{Server};
+	use std::io::{File, TempDir};
+
+	/// Creates a server with the accounts alice and bob
+	fn server(dir: &TempDir) -> Server {
+		let accounts = dir.path().join("accounts.txt");
+		File::create(&accounts).unwrap();
+		let config = Config::from_str(format!(r#"
+[[listen]]
+address = "127.0.0.1"
+
+[accounts]
+file = "{}"
+"#, accounts.display()).as_slice());
+		let mut server = Server::new(config.unwrap()).unwrap();
+		for name in ["alice", "bob"].iter() {
+			assert!(server.accounts_mut().unwrap().register(
+				*name, ScramCredentials::new(b"secret")
+			).unwrap())
+		}
+		server
+	}
+
+	/// Adds a registered user
+	fn user(server: &mut Server, nick: &str, account: Option<&str>) -> (Connection, Outgoing) {
+		let (con, out) = detached_connection(server.host());
+		let peer = con.peer();
+		{
+			let mut info = peer.info().write();
+			info.set_nick(nick.to_string());
+			info.set_account(account.map(|v| v.to_string()));
+		}
+		server.add_user(peer);
+		(con, out)
+	}
+
+	/// Sends a command and returns the last reply
+	fn send(server: &mut Server, con: &Connection, out: &Outgoing, line: &str) -> String {
+		let handler = get_handler(RawMessage::parse(line.as_bytes()).unwrap()).ok().unwrap();
+		handler.invoke(server, con.peer());
+		out.messages().last().unwrap().to_string()
+	}
+
+	fn nick(con: &Connection) -> String {
+		let peer = con.peer();
+		let nick = peer.info().read().nick().clone();
+		nick
+	}
+
+	/// Test grouping the current nick under the account
+	#[test]
+	fn test_nicks_group() {
+		let dir = TempDir::new("chatd").unwrap();
+		let mut server = server(&dir);
+		let (con, out) = user(&mut server, "Ally", Some("alice"));
+		assert!(send(&mut server, &con, &out, "NICKS GROUP")
+			.as_slice().contains("Grouped Ally under alice"))
+		assert_eq!(server.accounts().unwrap().nick_owner("ALLY"), Some("alice".to_string()))
+		assert!(send(&mut server, &con, &out, "NICKS LIST")
+			.as_slice().contains("End of the nick list of alice"))
+		let (other, other_out) = user(&mut server, "bob", Some("alice"));
+		assert!(send(&mut server, &other, &other_out, "NICKS GROUP")
+			.as_slice().contains("bob is already owned by an account"))
+		let (anon, anon_out) = user(&mut server, "carol", None);
+		assert!(send(&mut server, &anon, &anon_out, "NICKS GROUP")
+			.as_slice().contains("You are not logged in"))
+		assert_eq!(server.accounts().unwrap().nick_owner("carol"), None)
+	}
+
+	/// Test releasing and regaining an owned nick
+	#[test]
+	fn test_nicks_release_regain() {
+		let dir = TempDir::new("chatd").unwrap();
+		let mut server = server(&dir);
+		assert!(server.accounts_mut().unwrap().group_nick("alice", "Ally").unwrap())
+		let (squatter, squatter_out) = user(&mut server, "ally", None);
+		let (con, out) = user(&mut server, "alice", Some("ALICE"));
+		assert!(send(&mut server, &con, &out, "NICKS RELEASE bob")
+			.as_slice().contains("bob does not belong to ALICE"))
+		assert!(send(&mut server, &con, &out, "NICKS RELEASE ALLY")
+			.as_slice().contains("Released ALLY"))
+		let guest = nick(&squatter);
+		assert!(guest.as_slice().starts_with("Guest"))
+		let notice = format!("ALLY was released by its owner, your nick was changed to {}", guest);
+		assert!(squatter_out.messages().iter().any(
+			|m| m.to_string().as_slice().contains(notice.as_slice())
+		))
+		assert!(send(&mut server, &con, &out, "NICKS RELEASE Ally")
+			.as_slice().contains("Ally is not in use"))
+		server.change_nick(&squatter.peer(), "Ally".to_string());
+		send(&mut server, &con, &out, "NICKS REGAIN ally");
+		assert_eq!(nick(&con).as_slice(), "ally")
+		assert!(nick(&squatter).as_slice().starts_with("Guest"))
+		assert!(server.get_peer("ALLY").map_or(false, |p| p.id() == con.peer().id()))
+		assert!(send(&mut server, &con, &out, "NICKS REGAIN Ally")
+			.as_slice().contains("You are already using Ally"))
+	}
+}
@@ -0,0 +1,122 @@
+use cmd;
+use msg::RawMessage;
+
+use server::{Server};
+use con::{Peer};
+use con::reg;
+use config::{RegistrationPolicy};
+
+/// Handles the OPER command
+///
+///    Command: OPER
+/// Parameters: <name> <password>
+pub struct Oper {
+    raw: RawMessage,
+    name: String,
+    password: String
+}
+
+impl super::MessageHandler for Oper {
+    fn from_message(message: RawMessage) -> Result<Box<Oper>, Option<RawMessage>> {
+        let params = message.params();
+        if params.len() >= 2 {
+            Ok(box Oper {
+                raw: message.clone(),
+                name: String::from_utf8_lossy(params[0]).to_string(),
+                password: String::from_utf8_lossy(params[1]).to_string()
+            })
+        } else {
+            Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NEEDMOREPARAMS), &[
+                "*", message.command().to_string().as_slice(),
+                "not enought params given"
+            ], None)))
+        }
+    }
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        let (mask, certfp) = {
+            let info = origin.info().read();
+            (info.real_hostmask().as_str().to_string(), info.certfp().map(|v| v.to_string()))
+        };
+        // Every failure gets the same reply so that the configured names
+        // and hosts are not revealed
+        let granted = server.config().opers.iter().find(|v| v.name == self.name).map_or(
+            false, |block| block.password == self.password
+                // The operator has to use the configured client certificate
+                && (block.fingerprint.is_none() || block.fingerprint == certfp)
+                && block.hosts.iter().any(|v| v.matches(mask.as_slice()))
+        );
+        if granted {
+            origin.info().write().set_oper(true);
+            origin.send_response(cmd::RPL_YOUREOPER,
+                &["You are now an IRC operator"], server.host()
+            )
+        } else {
+            info!("failed OPER attempt as {} from {}", self.name, mask)
+            origin.send_response(cmd::ERR_PASSWDMISMATCH,
+                &["Password incorrect"], server.host()
+            )
+        }
+    }
+    fn raw_message(&self) -> &RawMessage {
+        &self.raw
+    }
+}
+
+
+/// Handles the REHASH command
+///
+///    Command: REHASH
+/// Parameters: None
+///
+/// Reloads the message of the day and the accounts.
+pub struct Rehash {
+    raw: RawMessage
+}
+
+impl super::MessageHandler for Rehash {
+    fn from_message(message: RawMessage) -> Result<Box<Rehash>, Option<RawMessage>> {
+        Ok(box Rehash { raw: message })
+    }
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        if !origin.info().read().is_oper() {
+            origin.send_response(cmd::ERR_NOPRIVILEGES,
+                &["Permission Denied- You're not an IRC operator"], server.host()
+            );
+            return
+        }
+        let path = server.config().server.motd.as_ref().map_or(
+            "MOTD".to_string(), |v| v.display().to_string()
+        );
+        origin.send_response(cmd::RPL_REHASHING,
+            &[path.as_slice(), "Rehashing"], server.host()
+        );
+        if !server.reload_motd() {
+            origin.send_response(cmd::ERR_FILEERROR,
+                &["reading", path.as_slice(), "Cannot load the message of the day"],
+                server.host()
+            )
+        }
+        let had_accounts = server.accounts().is_some();
+        let accounts_path = server.config().accounts.file.as_ref().map(
+            |v| v.display().to_string()
+        );
+        if let Some(path) = accounts_path {
+            if !server.reload_accounts() {
+                origin.send_response(cmd::ERR_FILEERROR,
+                    &["reading", path.as_slice(), "Cannot load the accounts"],
+                    server.host()
+                )
+            }
+        }
+        if !had_accounts && server.accounts().is_some() {
+            let mut flags = vec![reg::SASL];
+            if server.config().accounts.registration != RegistrationPolicy::Closed {
+                flags.push(reg::AccountRegistration)
+            }
+            super::cap::notify(server, "NEW", flags.as_slice())
+        }
+    }
+    fn raw_message(&self) -> &RawMessage {
+        &self.raw
+    }
+}
\ No newline at end of file
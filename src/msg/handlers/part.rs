@@ -4,7 +4,7 @@ use msg::RawMessage;
 use util;
 
 use server::{Server};
-use con::Peer;
+use con::{Peer, Connection};
 
 
 /// Handles the quit/part event
@@ -14,9 +14,12 @@ pub fn do_quit_leave(channel: &mut channel::Channel, client: Peer,
     match nick {
         Some(nick) => {
             let msg = {
-                let payload = match reason {
-                    None => vec![channel.name().as_bytes()],
-                    Some(ref reason) => vec![channel.name().as_bytes(), reason.as_slice()],
+                let payload = match (command, &reason) {
+                    // QUIT does not name the channel
+                    (cmd::QUIT, &Some(ref reason)) => vec![reason.as_slice()],
+                    (cmd::QUIT, &None) => vec![],
+                    (_, &None) => vec![channel.name().as_bytes()],
+                    (_, &Some(ref reason)) => vec![channel.name().as_bytes(), reason.as_slice()],
                     };
                 RawMessage::new_raw(
                     command, payload.as_slice(), Some(nick.as_bytes())
@@ -24,6 +27,7 @@ pub fn do_quit_leave(channel: &mut channel::Channel, client: Peer,
             };
             channel.broadcast(msg);
             channel.remove_member(&client.id());
+            client.info().write().remove_channel(&channel.key());
         },
         // This error message makes only sense for the part command
         None if command == cmd::PART => channel.send_response(
@@ -58,7 +62,7 @@ impl super::MessageHandler for Part {
             Ok(box Part {
                 raw: message.clone(),
                 channels: channels,
-                reason: params.as_slice().get(1).map(|v| v.to_vec())
+                reason: params.get(1).map(|v| v.to_vec())
             })
         } else {
              Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NEEDMOREPARAMS), &[
@@ -70,7 +74,7 @@ impl super::MessageHandler for Part {
     fn invoke(&self, server: &mut Server, origin: Peer) {
         let host = server.host().to_string(); // clone due to #6393
         for channel_name in self.channels.iter() {
-            match server.channels.get_mut(channel_name) {
+            match server.get_channel(channel_name.as_slice()) {
                 Some(channel) => {
                     let reason = self.reason.clone();
                     let proxy = origin.clone();
@@ -99,22 +103,20 @@ pub struct Quit {
 }
 impl super::MessageHandler for Quit {
     fn from_message(message: RawMessage) -> Result<Box<Quit>, Option<RawMessage>> {
-        let reason = message.params().as_slice().get(0).map(
-            |&v| v.to_vec());
+        let reason = message.param(0).map(|v| v.to_vec());
         Ok(box Quit {
             raw: message, reason: reason
         })
     }
     fn invoke(&self, server: &mut Server, origin: Peer) {
-        server.close_connection(&origin);
-        for (_, channel) in server.channels.iter() {
-            // TODO make this more performant, cache channels in user?
-            let reason = self.reason.clone();
-            let proxy = origin.clone();
-            channel.send(channel::HandleMut(proc(channel) {
-                do_quit_leave(channel, proxy, cmd::QUIT, reason)
-            }))
-        }
+        let reason = match self.reason {
+            Some(ref reason) => format!("Quit: {}", String::from_utf8_lossy(reason.as_slice())),
+            None => "Quit".to_string()
+        };
+        server.disconnect(origin.id(), reason.as_slice())
+    }
+    fn invoke_con(&self, server: &mut Server, origin: Connection) {
+        self.invoke(server, origin.peer())
     }
     fn raw_message(&self) -> &RawMessage {
         &self.raw
@@ -1,29 +1,51 @@
+use cmd;
 use msg::RawMessage;
 use server::{Server};
-use con::Peer;
+use con::{Peer, Connection};
 
-#[allow(dead_code)] 
+/// Handles the PING command
+///
+///    Command: PING
+/// Parameters: <server1> [ <server2> ]
 pub struct Ping {
  raw: RawMessage,
- payload: Option<String>
+ payload: String
 }
 
 impl super::MessageHandler for Ping {
     fn from_message(message: RawMessage) -> Result<Box<Ping>, Option<RawMessage>> {
-       let payload = message.params().as_slice().get(0).map(
-           |&v| String::from_utf8_lossy(v).to_string());
-       Ok(box Ping {
-           raw: message, payload: payload
-       })
+       match message.param(0) {
+           Some(payload) => {
+               let payload = String::from_utf8_lossy(payload).to_string();
+               Ok(box Ping {
+                   raw: message, payload: payload
+               })
+           },
+           None => Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NOORIGIN), &[
+               "*", "No origin specified"
+           ], None)))
+       }
     }
-    fn invoke(&self, _: &mut Server, _: Peer) {
-        // ignore for now
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        origin.send_msg(RawMessage::new(cmd::PONG,
+            &[server.host(), self.payload.as_slice()], Some(server.host())
+        ))
+    }
+    fn invoke_con(&self, server: &mut Server, origin: Connection) {
+        self.invoke(server, origin.peer())
     }
     fn raw_message(&self) -> &RawMessage {
         &self.raw
     }
 }
 
+/// Handles the PONG command
+///
+///    Command: PONG
+/// Parameters: <server> [ <server2> ]
+///
+/// Every message resets the ping timeout of a peer, hence
+/// nothing is left to do here.
 #[allow(dead_code)] 
 pub struct Pong {
  raw: RawMessage,
@@ -32,14 +54,14 @@ pub struct Pong {
 
 impl super::MessageHandler for Pong {
     fn from_message(message: RawMessage) -> Result<Box<Pong>, Option<RawMessage>> { 
-       let payload = message.params().as_slice().get(0).map(
-           |&v| String::from_utf8_lossy(v).to_string());
+       let payload = message.param(0).map(
+           |v| String::from_utf8_lossy(v).to_string());
        Ok(box Pong {
            raw: message, payload: payload
        })
     }
     fn invoke(&self, _: &mut Server, _: Peer) {
-        // ignore for now
+        // handled by Server::serve_forever
     }
     fn raw_message(&self) -> &RawMessage {
         &self.raw
@@ -0,0 +1,375 @@
+use cmd;
+use msg::RawMessage;
+use account::{ScramCredentials};
+use config::{RegistrationPolicy};
+
+use server::{Server};
+use con::{Peer, Connection};
+
+use time;
+
+/// Minimum length of the password of a new account
+static MIN_PASSWORD_LENGTH: uint = 8;
+
+/// Sends a FAIL standard reply
+fn fail(server: &Server, peer: &Peer, command: &str, code: &str, account: &str, text: &str) {
+    peer.send_msg(RawMessage::new(cmd::FAIL, &[command, code, account, text], Some(server.host())))
+}
+
+/// Checks that an e-mail address looks like `local@domain`
+fn valid_email(email: &str) -> bool {
+    let parts: Vec<&str> = email.split('@').collect();
+    parts.len() == 2 && parts.iter().all(|part| !part.is_empty())
+        && !email.contains_char(' ') && !email.contains_char(',')
+}
+
+/// Handles the REGISTER command
+///
+///    Command: REGISTER
+/// Parameters: <account> {<email> | "*"} <password>
+///
+/// Creates an account, see
+/// http://ircv3.net/specs/extensions/account-registration. The account has
+/// to be named like the nick of the user, `*` stands for the nick. Only
+/// operators can register other names if registration is limited to them.
+pub struct Register {
+    raw: RawMessage,
+    account: String,
+    email: String,
+    password: Vec<u8>
+}
+
+impl Register {
+    fn handle(&self, server: &mut Server, peer: Peer, registered: bool) {
+        let policy = server.config().accounts.registration.clone();
+        let (nick, logged_in, oper) = {
+            let info = peer.info().read();
+            (info.nick().clone(), info.account().is_some(), info.is_oper())
+        };
+        let account = if self.account.as_slice() == "*" {
+            nick.clone()
+        } else {
+            self.account.clone()
+        };
+        let account = account.as_slice();
+        if policy == RegistrationPolicy::Closed || server.accounts().is_none() {
+            return fail(server, &peer, "REGISTER", "TEMPORARILY_UNAVAILABLE", account,
+                "Account registration is disabled"
+            )
+        }
+        if policy == RegistrationPolicy::OpersOnly {
+            if !registered {
+                return fail(server, &peer, "REGISTER", "COMPLETE_CONNECTION_REQUIRED", account,
+                    "Complete the connection before registering an account"
+                )
+            } else if !oper {
+                return peer.send_response(cmd::ERR_NOPRIVILEGES,
+                    &["Permission Denied- You're not an IRC operator"], server.host()
+                )
+            }
+        } else {
+            if logged_in {
+                return fail(server, &peer, "REGISTER", "ALREADY_AUTHENTICATED", account,
+                    "You are already logged in"
+                )
+            } else if nick.as_slice() == "*" {
+                return fail(server, &peer, "REGISTER", "NEED_NICK", account,
+                    "Choose a nick before registering an account"
+                )
+            } else if server.key(account) != server.key(nick.as_slice()) {
+                return fail(server, &peer, "REGISTER", "ACCOUNT_NAME_MUST_BE_NICK", account,
+                    "The account has to be named like your nick"
+                )
+            }
+        }
+        if !server.valid_nick(account) {
+            return fail(server, &peer, "REGISTER", "BAD_ACCOUNT_NAME", account,
+                "Invalid account name"
+            )
+        } else if server.accounts().unwrap().nick_owner(account).is_some() {
+            return fail(server, &peer, "REGISTER", "ACCOUNT_EXISTS", account,
+                "The account already exists"
+            )
+        } else if self.password.len() < MIN_PASSWORD_LENGTH {
+            return fail(server, &peer, "REGISTER", "WEAK_PASSWORD", account, format!(
+                "The password needs at least {} characters", MIN_PASSWORD_LENGTH
+            ).as_slice())
+        }
+        let credentials = ScramCredentials::new(self.password.as_slice());
+        if policy == RegistrationPolicy::Verify {
+            let email = self.email.as_slice();
+            if !valid_email(email) {
+                return fail(server, &peer, "REGISTER", "INVALID_EMAIL", account,
+                    "A valid e-mail address is required"
+                )
+            }
+            let now = time::get_time().sec;
+            let result = server.verifications_mut().unwrap().start(
+                account, email, credentials, peer.id(), now
+            );
+            match result {
+                Ok(()) => peer.send_msg(RawMessage::new(cmd::REGISTER, &[
+                    "VERIFICATION_REQUIRED", account,
+                    format!("A verification code was sent to {}", email).as_slice()
+                ], Some(server.host()))),
+                Err(err) => {
+                    error!("cannot send the verification code for {}: {}", account, err);
+                    fail(server, &peer, "REGISTER", "TEMPORARILY_UNAVAILABLE", account,
+                        "Cannot send the verification code"
+                    )
+                }
+            }
+            return
+        }
+        let result = server.accounts_mut().unwrap().register(account, credentials);
+        match result {
+            Ok(true) => {
+                if policy == RegistrationPolicy::Open {
+                    super::authenticate::log_in(server, &peer, account.to_string())
+                }
+                peer.send_msg(RawMessage::new(cmd::REGISTER, &[
+                    "SUCCESS", account, "Account created"
+                ], Some(server.host())))
+            },
+            Ok(false) => fail(server, &peer, "REGISTER", "ACCOUNT_EXISTS", account,
+                "The account already exists"
+            ),
+            Err(err) => {
+                error!("cannot save the account {}: {}", account, err);
+                fail(server, &peer, "REGISTER", "TEMPORARILY_UNAVAILABLE", account,
+                    "Cannot save the account"
+                )
+            }
+        }
+    }
+}
+
+impl super::MessageHandler for Register {
+    fn from_message(message: RawMessage) -> Result<Box<Register>, Option<RawMessage>> {
+        let params = message.params();
+        if params.len() < 3 {
+            return Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NEEDMOREPARAMS), &[
+                "*", message.command().to_string().as_slice(),
+                "not enought params given"
+            ], None)))
+        }
+        Ok(box Register {
+            raw: message.clone(),
+            account: String::from_utf8_lossy(params[0]).into_string(),
+            email: String::from_utf8_lossy(params[1]).into_string(),
+            password: params[2].to_vec()
+        })
+    }
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        self.handle(server, origin, true)
+    }
+    fn invoke_con(&self, server: &mut Server, origin: Connection) {
+        self.handle(server, origin.peer(), false)
+    }
+    fn raw_message(&self) -> &RawMessage {
+        &self.raw
+    }
+}
+
+/// Handles the VERIFY command
+///
+///    Command: VERIFY
+/// Parameters: <account> <code>
+///
+/// Creates an account registered with REGISTER once the code sent to its
+/// e-mail address is given back by the same connection. The user is logged
+/// in to the account.
+pub struct Verify {
+    raw: RawMessage,
+    account: String,
+    code: String
+}
+
+impl Verify {
+    fn handle(&self, server: &mut Server, peer: Peer) {
+        let account = self.account.as_slice();
+        if server.accounts().is_none() || server.verifications_mut().is_none() {
+            return fail(server, &peer, "VERIFY", "TEMPORARILY_UNAVAILABLE", account,
+                "Account verification is disabled"
+            )
+        }
+        if peer.info().read().account().is_some() {
+            return fail(server, &peer, "VERIFY", "ALREADY_AUTHENTICATED", account,
+                "You are already logged in"
+            )
+        }
+        let now = time::get_time().sec;
+        let (name, credentials) = match server.verifications_mut().unwrap().verify(
+            account, self.code.as_slice(), peer.id(), now
+        ) {
+            Some(verified) => verified,
+            None => return fail(server, &peer, "VERIFY", "INVALID_CODE", account,
+                "Invalid verification code"
+            )
+        };
+        let result = server.accounts_mut().unwrap().register(name.as_slice(), credentials);
+        match result {
+            Ok(true) => {
+                super::authenticate::log_in(server, &peer, name.clone());
+                peer.send_msg(RawMessage::new(cmd::VERIFY, &[
+                    "SUCCESS", name.as_slice(), "Account verified"
+                ], Some(server.host())))
+            },
+            Ok(false) => fail(server, &peer, "VERIFY", "ACCOUNT_EXISTS", account,
+                "The account already exists"
+            ),
+            Err(err) => {
+                error!("cannot save the account {}: {}", name, err);
+                fail(server, &peer, "VERIFY", "TEMPORARILY_UNAVAILABLE", account,
+                    "Cannot save the account"
+                )
+            }
+        }
+    }
+}
+
+impl super::MessageHandler for Verify {
+    fn from_message(message: RawMessage) -> Result<Box<Verify>, Option<RawMessage>> {
+        let params = message.params();
+        if params.len() < 2 {
+            return Err(Some(RawMessage::new(cmd::REPLY(cmd::ERR_NEEDMOREPARAMS), &[
+                "*", message.command().to_string().as_slice(),
+                "not enought params given"
+            ], None)))
+        }
+        Ok(box Verify {
+            raw: message.clone(),
+            account: String::from_utf8_lossy(params[0]).into_string(),
+            code: String::from_utf8_lossy(params[1]).into_string()
+        })
+    }
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        self.handle(server, origin)
+    }
+    fn invoke_con(&self, server: &mut Server, origin: Connection) {
+        self.handle(server, origin.peer())
+    }
+    fn raw_message(&self) -> &RawMessage {
+        &self.raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::{MessageHandler, get_handler};
+	use config::{Config};
+	use con::{Connection, Outgoing, detached_connection};
+	use msg::RawMessage;
+	use server::{Server};
+	use std::io::{File, TempDir};
+
+	/// Creates a server with an empty account file
+	fn server(dir: &TempDir, policy: &str) -> Server {
+		let accounts = dir.path().join("accounts.txt");
+		File::create(&accounts).unwrap();
+		let config = Config::from_str(format!(r#"
+[[listen]]
+address = "127.0.0.1"
+
+[accounts]
+file = "{}"
+registration = "{}"
+verification_file = "{}"
+"#, accounts.display(), policy, dir.path().join("codes.txt").display()).as_slice());
+		Server::new(config.unwrap()).unwrap()
+	}
+
+	fn connection(nick: &str) -> (Connection, Outgoing) {
+		let (con, out) = detached_connection("localhost");
+		if nick != "*" {
+			con.peer().info().write().set_nick(nick.to_string())
+		}
+		(con, out)
+	}
+
+	fn handler(line: &str) -> Box<MessageHandler + Send> {
+		get_handler(RawMessage::parse(line.as_bytes()).unwrap()).ok().unwrap()
+	}
+
+	/// Sends a command before the connection is registered and returns
+	/// the last reply
+	fn send(server: &mut Server, con: &Connection, out: &Outgoing, line: &str) -> String {
+		handler(line).invoke_con(server, con.clone());
+		out.messages().last().unwrap().to_string()
+	}
+
+	fn account(con: &Connection) -> Option<String> {
+		con.peer().info().read().account().map(|v| v.to_string())
+	}
+
+	/// Test the checks of open registration
+	#[test]
+	fn test_register_open() {
+		let dir = TempDir::new("chatd").unwrap();
+		let mut server = server(&dir, "open");
+		let (con, out) = connection("*");
+		assert!(send(&mut server, &con, &out, "REGISTER * * password1")
+			.as_slice().contains("FAIL REGISTER NEED_NICK"))
+		let (con, out) = connection("alice");
+		assert!(send(&mut server, &con, &out, "REGISTER bob * password1")
+			.as_slice().contains("FAIL REGISTER ACCOUNT_NAME_MUST_BE_NICK"))
+		assert!(send(&mut server, &con, &out, "REGISTER * * short")
+			.as_slice().contains("FAIL REGISTER WEAK_PASSWORD"))
+		assert!(send(&mut server, &con, &out, "REGISTER ALICE * password1")
+			.as_slice().contains("REGISTER SUCCESS ALICE"))
+		assert_eq!(account(&con), Some("ALICE".to_string()))
+		assert!(send(&mut server, &con, &out, "REGISTER * * password1")
+			.as_slice().contains("FAIL REGISTER ALREADY_AUTHENTICATED"))
+		let (con, out) = connection("Alice");
+		assert!(send(&mut server, &con, &out, "REGISTER * * password1")
+			.as_slice().contains("FAIL REGISTER ACCOUNT_EXISTS"))
+	}
+
+	/// Test closed registration and registration by operators
+	#[test]
+	fn test_register_restricted() {
+		let dir = TempDir::new("chatd").unwrap();
+		let mut server = server(&dir, "closed");
+		let (con, out) = connection("alice");
+		assert!(send(&mut server, &con, &out, "REGISTER * * password1")
+			.as_slice().contains("FAIL REGISTER TEMPORARILY_UNAVAILABLE"))
+		let dir = TempDir::new("chatd").unwrap();
+		let mut server = server(&dir, "opers");
+		assert!(send(&mut server, &con, &out, "REGISTER * * password1")
+			.as_slice().contains("FAIL REGISTER COMPLETE_CONNECTION_REQUIRED"))
+		let handler = handler("REGISTER bob * password1");
+		handler.invoke(&mut server, con.peer());
+		assert!(out.messages().last().unwrap().to_string().as_slice().contains(" 481 alice "))
+		con.peer().info().write().set_oper(true);
+		handler.invoke(&mut server, con.peer());
+		assert!(out.messages().last().unwrap().to_string().as_slice()
+			.contains("REGISTER SUCCESS bob"))
+		// Operators register accounts for others
+		assert_eq!(account(&con), None)
+	}
+
+	/// Test registration with verification
+	#[test]
+	fn test_register_verify() {
+		let dir = TempDir::new("chatd").unwrap();
+		let mut server = server(&dir, "verify");
+		let (con, out) = connection("alice");
+		assert!(send(&mut server, &con, &out, "REGISTER * nomail password1")
+			.as_slice().contains("FAIL REGISTER INVALID_EMAIL"))
+		assert!(send(&mut server, &con, &out, "REGISTER * alice@example.net password1")
+			.as_slice().contains("REGISTER VERIFICATION_REQUIRED alice"))
+		let codes = File::open(&dir.path().join("codes.txt")).read_to_string().unwrap();
+		let code = codes.as_slice().trim().split(' ').last().unwrap();
+		// Only the connection which registered can verify
+		let (other, other_out) = connection("mallory");
+		let line = format!("VERIFY alice {}", code);
+		assert!(send(&mut server, &other, &other_out, line.as_slice())
+			.as_slice().contains("FAIL VERIFY INVALID_CODE"))
+		assert!(send(&mut server, &con, &out, "VERIFY alice wrong")
+			.as_slice().contains("FAIL VERIFY INVALID_CODE"))
+		assert_eq!(account(&con), None)
+		assert!(send(&mut server, &con, &out, line.as_slice())
+			.as_slice().contains("VERIFY SUCCESS alice"))
+		assert_eq!(account(&con), Some("alice".to_string()))
+	}
+}
@@ -6,15 +6,31 @@ use server::{Server};
 use con::{Peer, Connection};
 use con;
 
-fn try_register(server: &mut Server, origin: Peer) {
-    if server.nicks.contains_key(origin.info().read().nick()) {
-        origin.send_response(cmd::ERR_ALREADYREGISTRED, 
-            &["somebody already registered with the same nickname"],
+/// Completes the registration once NICK and USER have been received
+///
+/// Nothing happens while the client is still negotiating capabilities.
+pub fn try_register(server: &mut Server, origin: Peer) {
+    let (nick, ready) = {
+        let info = origin.info().read();
+        (info.nick().clone(), 
+         info.registration_status() == con::reg::GotUser && !info.is_negotiating())
+    };
+    if !ready || !server.valid_nick(nick.as_slice()) {
+        return
+    }
+    // The nick may have been taken while the client negotiated capabilities,
+    // the client can pick another one
+    if server.nicks.contains_key(&server.key(nick.as_slice())) {
+        origin.send_response(cmd::ERR_NICKNAMEINUSE,
+            &[nick.as_slice(), "nickname in use"],
             server.host()
         )
-    } else if origin.info().read().registration_status() == con::reg::Registered {
+    } else {
+        *origin.info().write().mut_registration_status() = con::reg::Registered;
         server.send_welcome_msg(&origin);
-        server.add_user(origin);
+        server.send_motd(&origin);
+        server.add_user(origin.clone());
+        server.check_nick_owner(&origin)
     }
 }
 
@@ -22,6 +38,9 @@ fn try_register(server: &mut Server, origin: Peer) {
 ///
 ///    Command: NICK
 /// Parameters: <nickname> [ <hopcount> ]
+///
+/// Users of a nick owned by an account they are not logged in to are
+/// renamed to a guest nick after a grace period.
 pub struct Nick {
     raw: RawMessage,
     nick: String
@@ -48,8 +67,33 @@ impl super::MessageHandler for Nick {
             ], None)))
         }
     }
+    /// Changes the nick of a registered user
     fn invoke(&self, server: &mut Server, origin: Peer) {
-        if server.nicks.contains_key(&self.nick) {
+        let old_nick = origin.info().read().nick().clone();
+        if old_nick == self.nick {
+            return
+        }
+        let (old_key, key) = (server.key(old_nick.as_slice()), server.key(self.nick.as_slice()));
+        // Changing the case of the own nick is allowed
+        if old_key != key && server.nicks.contains_key(&key) {
+            origin.send_response(cmd::ERR_NICKNAMEINUSE,
+                &[self.nick.as_slice(), "nickname in use"],
+                server.host()
+            );
+        } else if !server.valid_nick(self.nick.as_slice()) {
+            origin.send_response(cmd::ERR_ERRONEUSNICKNAME,
+                &[self.nick.as_slice(), "invalid nick name"],
+                server.host()
+            );
+        } else {
+            server.change_nick(&origin, self.nick.clone());
+            server.check_nick_owner(&origin)
+        }
+    }
+    /// Sets the nick during the registration
+    fn invoke_con(&self, server: &mut Server, origin: Connection) {
+        let origin = origin.peer();
+        if server.nicks.contains_key(&server.key(self.nick.as_slice())) {
             origin.send_response(cmd::ERR_NICKNAMEINUSE,
                 &[self.nick.as_slice(), "nickname in use"],
                 server.host()
@@ -58,13 +102,14 @@ impl super::MessageHandler for Nick {
             if server.valid_nick(self.nick.as_slice()) {
                 origin.info().write().set_nick(self.nick.clone());
                 try_register(server, origin)
+            } else {
+                origin.send_response(cmd::ERR_ERRONEUSNICKNAME,
+                    &[self.nick.as_slice(), "invalid nick name"],
+                    server.host()
+                );
             }
-            
         }
     }
-    fn invoke_con(&self, server: &mut Server, origin: Connection) {
-        self.invoke(server, origin.peer())
-    }
     fn raw_message(&self) -> &RawMessage {
         &self.raw
     }
@@ -92,20 +137,21 @@ impl super::MessageHandler for User {
         }
         
     }
+    /// Registered users cannot change their user name
     fn invoke(&self, server: &mut Server, origin: Peer) {
+        origin.send_response(cmd::ERR_ALREADYREGISTRED, 
+            &["You may not reregister"], server.host()
+        )
+    }
+    fn invoke_con(&self, server: &mut Server, origin: Connection) {
+        let origin = origin.peer();
         {
             let mut info = origin.info().write();
             info.set_username(self.username.clone());
             info.set_realname(self.realname.clone());
-            *info.mut_registration_status() = con::reg::Registered
-        
+            *info.mut_registration_status() = con::reg::GotUser
         }
-        if server.valid_nick(origin.info().read().nick().as_slice()) {
-            try_register(server, origin)
-        }
-    }
-    fn invoke_con(&self, server: &mut Server, origin: Connection) {
-        self.invoke(server, origin.peer())
+        try_register(server, origin)
     }
     fn raw_message(&self) -> &RawMessage {
         &self.raw
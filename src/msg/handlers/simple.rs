@@ -15,7 +15,8 @@ pub struct Topic {
 }
 
 impl Topic {
-    fn set(channel: &mut channel::Channel, proxy: Peer, topic: Vec<u8>) {
+    fn set(channel: &mut channel::Channel, proxy: Peer, mut topic: Vec<u8>) {
+        topic.truncate(channel.topic_length());
         let set_topic = match channel.member_with_id(proxy.id()) {
             Some(member) => {
                 if channel.has_flag(TopicProtect) && !member.is_op() {
@@ -55,7 +56,7 @@ impl super::MessageHandler for Topic {
                     "Invalid channel name."
                 ], None)))
             };  
-            let topic = message.params().as_slice().get(1).unwrap_or(&b"").to_vec();
+            let topic = message.param(1).unwrap_or(b"").to_vec();
             Ok(box Topic {
                 raw: message,
                 channel: channel,
@@ -70,7 +71,7 @@ impl super::MessageHandler for Topic {
     }
     fn invoke(&self, server: &mut Server, origin: Peer) {
         let host = server.host().to_string(); // clone due to #6393
-        match server.channels.get_mut(&self.channel) {
+        match server.get_channel(self.channel.as_slice()) {
             Some(channel) => {
                 let this = (*self).clone();
                 channel.send(channel::HandleMut(proc(channel) {
@@ -82,6 +83,29 @@ impl super::MessageHandler for Topic {
                 host.as_slice()
             )   
         }
+        server.store_channel(self.channel.as_slice())
+    }
+    fn raw_message(&self) -> &RawMessage {
+        &self.raw
+    }
+}
+
+/// Handles the MOTD command
+///
+///    Command: MOTD
+/// Parameters: [ <target> ]
+///
+/// Other servers are not supported, the target is ignored.
+pub struct Motd {
+    raw: RawMessage
+}
+
+impl super::MessageHandler for Motd {
+    fn from_message(message: RawMessage) -> Result<Box<Motd>, Option<RawMessage>> {
+        Ok(box Motd { raw: message })
+    }
+    fn invoke(&self, server: &mut Server, origin: Peer) {
+        server.send_motd(&origin)
     }
     fn raw_message(&self) -> &RawMessage {
         &self.raw
@@ -3,4 +3,5 @@ pub use self::raw::RawMessage;
 pub use self::handlers::{MessageHandler, get_handler};
 
 pub mod raw;
+pub mod tags;
 pub mod handlers;
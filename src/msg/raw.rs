@@ -1,7 +1,20 @@
+use std::cmp;
+use std::fmt::{Show, Formatter, Error};
+use std::io::{IoResult};
 use std::mem;
+use std::slice::bytes::{copy_memory};
+use std::sync::{Arc};
 
 use cmd::*;
 
+use super::tags;
+use super::tags::{Tags, Tag};
+
+/// Maximum length of a message without the trailing CR-LF
+pub static MAX_LENGTH: uint = 510;
+/// Maximum number of parameters of a message
+pub static MAX_PARAMS: uint = 15;
+
 #[deriving(Show, Clone)]
 // Helper struct to efficently adress the different parts
 // of the message.
@@ -10,22 +23,252 @@ struct ASlice {
     end: uint
 }
 
+static EMPTY: ASlice = ASlice { start: 0, end: 0 };
+
 impl ASlice {
     #[inline] 
-    fn slice_vec<'a, T>(&self, vec: &'a Vec<T>) -> &'a[T] {
-        vec.slice(self.start, self.end)
+    fn slice<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        buf.slice(self.start, self.end)
     }
 }
 
-/// IRC message
-// TODO: do not use vecs for that, better [u8, ..510] and slices to that
-// or just safe offsets in the message parts fields…
-#[deriving(Show, Clone)]
-pub struct RawMessage {
-    raw_message: Vec<u8>,
+/// Parsed message shared by all clones of a `RawMessage`
+///
+/// The line is stored inline, the parts of the message are
+/// offsets into it. The escaped tag data is kept by `RawMessage` since
+/// it may be much longer than the line.
+struct Message {
+    buf: [u8, ..MAX_LENGTH],
+    len: uint,
     prefix: Option<ASlice>,
     command: ASlice, 
-    params: Vec<ASlice>
+    params: [ASlice, ..MAX_PARAMS],
+    num_params: uint
+}
+
+impl Message {
+    fn empty() -> Message {
+        Message {
+            buf: [0, ..MAX_LENGTH],
+            len: 0,
+            prefix: None,
+            command: EMPTY,
+            params: [EMPTY, ..MAX_PARAMS],
+            num_params: 0
+        }
+    }
+    
+    #[inline]
+    fn as_slice(&self) -> &[u8] {
+        self.buf.slice_to(self.len)
+    }
+    
+    /// Appends bytes and returns their position
+    ///
+    /// Bytes which do not fit into the message are dropped, a UTF-8 sequence
+    /// is never cut in the middle.
+    fn push(&mut self, bytes: &[u8]) -> ASlice {
+        let start = self.len;
+        let mut n = cmp::min(bytes.len(), MAX_LENGTH - start);
+        while n > 0 && n < bytes.len() && is_continuation(bytes[n]) {
+            n -= 1
+        }
+        let end = start + n;
+        copy_memory(self.buf.slice_mut(start, end), bytes.slice_to(n));
+        self.len = end;
+        ASlice { start: start, end: end }
+    }
+    
+    /// Appends the bytes for which `keep` returns true
+    ///
+    /// Like `push` a UTF-8 sequence which does not fit is dropped entirely.
+    fn push_filtered(&mut self, bytes: &[u8], keep: |u8| -> bool) -> ASlice {
+        let start = self.len;
+        for &c in bytes.iter() {
+            if self.len == MAX_LENGTH {
+                if is_continuation(c) {
+                    while self.len > start && is_continuation(self.buf[self.len - 1]) {
+                        self.len -= 1
+                    }
+                    if self.len > start {
+                        // Lead byte of the sequence
+                        self.len -= 1
+                    }
+                }
+                break
+            }
+            if keep(c) {
+                self.buf[self.len] = c;
+                self.len += 1
+            }
+        }
+        ASlice { start: start, end: self.len }
+    }
+    
+    /// Appends a middle parameter
+    ///
+    /// Spaces and leading colons are removed, an empty parameter is replaced by `*`.
+    fn push_middle(&mut self, param: &[u8]) -> ASlice {
+        let start = param.iter().position(
+            |&c| c != b':' && c != b' ' && !is_illegal(c)
+        ).unwrap_or(param.len());
+        if start == param.len() {
+            self.push(b"*")
+        } else {
+            self.push_filtered(param.slice_from(start), |c| c != b' ' && !is_illegal(c))
+        }
+    }
+    
+    /// Appends the trailing parameter, the colon is only added if needed
+    fn push_trailing(&mut self, param: &[u8]) -> ASlice {
+        let mut kept = param.iter().filter(|&&c| !is_illegal(c));
+        let needs_colon = match kept.next() {
+            None | Some(&b':') => true,
+            Some(&b' ') => true,
+            Some(_) => kept.any(|&c| c == b' ')
+        };
+        if needs_colon {
+            self.push(b":");
+        }
+        self.push_filtered(param, |c| !is_illegal(c))
+    }
+    
+    /// Adds a parameter, parameters beyond `MAX_PARAMS` are ignored
+    fn push_param(&mut self, param: ASlice) {
+        if self.num_params < MAX_PARAMS {
+            self.params[self.num_params] = param;
+            self.num_params += 1
+        }
+    }
+    
+    /// Creates a copy of the message with a new prefix
+    fn with_prefix(&self, prefix: &str) -> Message {
+        let mut message = Message::empty();
+        message.push(b":");
+        let new_prefix = message.push_filtered(prefix.as_bytes(), |c| !is_illegal(c));
+        // Everything after the old prefix is kept
+        let rest = match self.prefix {
+            Some(old_prefix) => old_prefix.end,
+            None => {
+                message.push(b" ");
+                0
+            }
+        };
+        let base = message.len;
+        message.push(self.as_slice().slice_from(rest));
+        let len = message.len;
+        let shift = |slice: ASlice| ASlice {
+            start: cmp::min(slice.start - rest + base, len),
+            end: cmp::min(slice.end - rest + base, len)
+        };
+        message.prefix = Some(new_prefix);
+        message.command = shift(self.command);
+        for param in self.params.slice_to(self.num_params).iter() {
+            message.push_param(shift(*param))
+        }
+        message
+    }
+}
+
+/// IRC message
+///
+/// Messages are immutable and reference counted. Cloning a message does not
+/// copy it, such that a single message can be sent to thousands of peers.
+/// The line is stored inline, messages are truncated to `MAX_LENGTH` bytes.
+///
+/// A message may carry IRCv3 tags. They are not part of `as_slice` but are
+/// written by `write_to`. The tags are shared apart from the line, such that
+/// the untagged version of a message does not copy the line.
+#[deriving(Clone)]
+pub struct RawMessage {
+    /// Escaped tag data, `None` if the message has no tags
+    tags: Option<Arc<Vec<u8>>>,
+    inner: Arc<Message>
+}
+
+/// Iterator over the parameters of a message
+///
+/// Also allows indexed access to the parameters.
+#[deriving(Clone)]
+pub struct Params<'a> {
+    message: &'a Message,
+    pos: uint,
+    end: uint
+}
+
+impl<'a> Params<'a> {
+    /// Number of remaining parameters
+    pub fn len(&self) -> uint {
+        self.end - self.pos
+    }
+    
+    /// Returns the parameter at `index`
+    pub fn get(&self, index: uint) -> Option<&'a [u8]> {
+        if index < self.len() {
+            Some(self.message.params[self.pos + index].slice(self.message.as_slice()))
+        } else {
+            None
+        }
+    }
+    
+    /// Skips the first `start` parameters
+    pub fn slice_from(&self, start: uint) -> Params<'a> {
+        Params {
+            message: self.message,
+            pos: cmp::min(self.pos + start, self.end),
+            end: self.end
+        }
+    }
+}
+
+impl<'a> Iterator<&'a [u8]> for Params<'a> {
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let param = self.get(0);
+        if param.is_some() {
+            self.pos += 1
+        }
+        param
+    }
+    
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.len(), Some(self.len()))
+    }
+}
+
+impl<'a> Index<uint, [u8]> for Params<'a> {
+    fn index<'b>(&'b self, index: &uint) -> &'b [u8] {
+        match self.get(*index) {
+            Some(param) => param,
+            None => panic!("parameter index {} out of bounds", *index)
+        }
+    }
+}
+
+/// Bytes which must not appear in a message since they would break the framing
+#[inline]
+fn is_illegal(c: u8) -> bool {
+    c == b'\r' || c == b'\n' || c == 0
+}
+
+/// Bytes inside of a UTF-8 sequence
+#[inline]
+fn is_continuation(c: u8) -> bool {
+    c & 0xC0 == 0x80
+}
+
+/// Checks whether `key` is a valid tag key
+///
+/// Keys may have a '+' client prefix and a vendor prefix delimited by '/'.
+fn is_valid_key(key: &[u8]) -> bool {
+    let name = if tags::is_client_tag(key) { key.slice_from(1) } else { key };
+    let name = match name.iter().rposition(|&c| c == b'/') {
+        Some(pos) => name.slice_from(pos + 1),
+        None => name
+    };
+    !name.is_empty() && key.iter().enumerate().all(|(i, &c)| 
+        (c >= b'a' && c <= b'z') || (c >= b'A' && c <= b'Z') || (c >= b'0' && c <= b'9')
+        || c == b'-' || c == b'.' || c == b'/' || (i == 0 && c == b'+')
+    )
 }
 
 /// Searches a slice for the first occurence of needle
@@ -46,159 +289,275 @@ impl RawMessage {
     }
                        
     /// Creates a new message
+    ///
+    /// The message is truncated if it is longer than `MAX_LENGTH`. CR, LF and
+    /// NUL are removed from all parts of the message. Middle parameters cannot
+    /// contain spaces or start with a colon, those are removed as well. The last
+    /// parameter is only prefixed with a colon if necessary.
     pub fn new_raw(command: Command, 
                    params: &[&[u8]], 
                    prefix: Option<&[u8]>) -> RawMessage {
-        let mut raw_message = Vec::with_capacity(20);
-        let msg_prefix = prefix.map(|p| {
-            raw_message.push(b':');
-            raw_message.push_all(p);
-            raw_message.push(b' ');
-            ASlice { start: 1, end: p.len() + 1 }
-        });
-        let cmd = command.to_bytes();
-        let cmd_bytes = cmd.as_slice();
-        let msg_command = ASlice{ start: raw_message.len(),
-                                    end: raw_message.len() + cmd_bytes.len() };
-        raw_message.push_all(cmd_bytes);
-        let mut start = msg_command.end;
+        let mut message = Message::empty();
+        if let Some(prefix) = prefix {
+            message.push(b":");
+            let slice = message.push_filtered(prefix, |c| c != b' ' && !is_illegal(c));
+            message.push(b" ");
+            message.prefix = Some(slice)
+        }
+        message.command = message.push_filtered(
+            command.to_bytes().as_slice(), |c| c != b' ' && !is_illegal(c)
+        );
         let num_params = params.len();
-        let msg_params = params.iter().enumerate().map( |(i, &param)| {
-            let bytes = param;
-            raw_message.push(b' ');
-            let offset = if i + 1 == num_params {// && bytes.iter().position(|&v| v == b' ').is_some() {
-                raw_message.push(b':');
-                1
-            } else { 0 };
-            raw_message.push_all(bytes);
-            let end = start + 1 + bytes.len() + offset;
-            let slice = ASlice { start: start + 1 + offset, end: end };
-            start = end;
-            slice
-        }).collect();
-        RawMessage {
-            raw_message: raw_message,
-            prefix: msg_prefix,
-            command: msg_command,
-            params: msg_params
-        }
-    }
-    
-    /// Parses a message. Extracts the prefix, command and the params
-    pub fn parse(mut message: &[u8]) -> Result<RawMessage, &'static str> {
+        for (i, &param) in params.iter().enumerate() {
+            message.push(b" ");
+            let slice = if i + 1 == num_params {
+                message.push_trailing(param)
+            } else {
+                message.push_middle(param)
+            };
+            message.push_param(slice)
+        }
+        RawMessage { tags: None, inner: Arc::new(message) }
+    }
+    
+    /// Parses a message. Extracts the tags, prefix, command and the params
+    ///
+    /// The tag section may not be longer than `tags::MAX_TAGS_LENGTH`, the
+    /// rest of the message not longer than `MAX_LENGTH`.
+    pub fn parse(line: &[u8]) -> Result<RawMessage, &'static str> {
+        let mut message = Message::empty();
+        let mut tags = None;
+        let line = if line.starts_with(b"@") {
+            let tags_end = match line.position_elem(&b' ') { 
+                Some(v) => v, 
+                None => return Err("RawMessage does not contain a command.") 
+            };
+            if tags_end + 1 > tags::MAX_TAGS_LENGTH {
+                return Err("Tags are too long.")
+            }
+            if tags_end > 1 {
+                tags = Some(Arc::new(line.slice(1, tags_end).to_vec()))
+            }
+            line.slice_from(tags_end + 1)
+        } else {
+            line
+        };
+        if line.len() > MAX_LENGTH {
+            return Err("RawMessage is too long.")
+        }
+        message.push(line);
         // Check for message prefix (starts with : and ends with space)
-        let raw_message = message.to_vec();
-        let prefix = if message.starts_with(&[b':']) {
-            let prefix_end = match message.position_elem(&b' ') { 
+        let mut rest = line;
+        let prefix = if rest.starts_with(&[b':']) {
+            let prefix_end = match rest.position_elem(&b' ') { 
                 Some(v) => v, 
                 None => return Err("RawMessage does not contain a command.") 
             };
-            message = message.slice_from(prefix_end + 1);
+            rest = rest.slice_from(prefix_end + 1);
             Some(ASlice{ start: 1, end: prefix_end })
         } else {
             None
         };
         let cmd_start = prefix.map(|v| v.end + 1).unwrap_or(0);
-        let trailing = match position(message, " :".as_bytes()) {
+        let trailing = match position(rest, " :".as_bytes()) {
             Some(trailing_pos) => {
-                message = message.slice_to(trailing_pos);
+                rest = rest.slice_to(trailing_pos);
                 Some(ASlice { 
                     start: cmd_start + trailing_pos + 2, 
-                    end: raw_message.len() })
+                    end: line.len() })
             },
             None => None
         };
-        let mut middle_iter = message.split(|&x| x == b' ');
+        let mut middle_iter = rest.split(|&x| x == b' ');
         let command = match middle_iter.next() {
             Some(cmd) =>
                 ASlice { start: cmd_start, 
                            end: cmd_start + cmd.len() },
             None => return Err("RawMessage does not contain a command.") 
         };
+        message.prefix = prefix;
+        message.command = command;
         let mut start = command.end + 1;
-        let mut params: Vec<ASlice> = middle_iter.map(|p| {
-            let slice = ASlice { start: start, end: start + p.len() };
-            start = slice.end + 1;
-            slice
-        }).collect();
-        if trailing.is_some() {
-            params.push(trailing.unwrap())
-        }
-        Ok(RawMessage {
-            raw_message: raw_message,
-            prefix: prefix,
-            command: command,
-            params: params
-        })
+        for param in middle_iter {
+            message.push_param(ASlice { start: start, end: start + param.len() });
+            start += param.len() + 1;
+        }
+        if let Some(trailing) = trailing {
+            message.push_param(trailing)
+        }
+        Ok(RawMessage { tags: tags, inner: Arc::new(message) })
+    }
+    
+    /// Iterates over the tags of the message
+    pub fn tags<'a>(&'a self) -> Tags<'a> {
+        match self.tags {
+            Some(ref tags) => Tags::new(tags.as_slice()),
+            None => Tags::new(&[])
+        }
+    }
+    
+    /// Returns the tag with the given key
+    pub fn tag<'a>(&'a self, key: &[u8]) -> Option<Tag<'a>> {
+        self.tags().find(|tag| tag.key == key)
+    }
+    
+    /// Checks whether the message carries any tags
+    pub fn has_tags(&self) -> bool {
+        self.tags.is_some()
+    }
+    
+    /// Length of the escaped tag data without the leading '@' and the
+    /// trailing space
+    pub fn tags_len(&self) -> uint {
+        self.tags.as_ref().map_or(0, |tags| tags.len())
+    }
+    
+    /// Number of bytes used by the tags which are not client-only,
+    /// including their separators
+    fn server_tags_len(&self) -> uint {
+        self.tags().filter(|tag| !tags::is_client_tag(tag.key)).fold(0, |len, tag|
+            len + tag.key.len() + tag.raw_value().len() + 2
+        )
+    }
+    
+    /// Adds a server tag, the value is escaped
+    ///
+    /// Returns false if the key is invalid or the tag does not fit into
+    /// the space the spec reserves for server tags.
+    /// Other clones of the message are not affected.
+    pub fn add_tag(&mut self, key: &str, value: Option<&[u8]>) -> bool {
+        let key = key.as_bytes();
+        if !is_valid_key(key) {
+            return false
+        }
+        let mut tag = key.to_vec();
+        if let Some(value) = value {
+            tag.push(b'=');
+            tags::escape(value, &mut tag)
+        }
+        if self.server_tags_len() + tag.len() > tags::MAX_TAG_DATA_LENGTH 
+           || self.tags_len() + tag.len() + 1 > tags::MAX_TAGS_LENGTH - 2 {
+            return false
+        }
+        let mut tags = match self.tags {
+            Some(ref tags) => {
+                let mut tags = (**tags).clone();
+                tags.push(b';');
+                tags
+            },
+            None => Vec::new()
+        };
+        tags.push_all(tag.as_slice());
+        self.tags = Some(Arc::new(tags));
+        true
+    }
+    
+    /// Removes all tags except the valid client-only tags
+    ///
+    /// This is applied to messages received from clients, such that only
+    /// the tags the server is allowed to relay remain.
+    pub fn retain_client_tags(&mut self) {
+        if !self.has_tags() {
+            return
+        }
+        let mut kept = Vec::new();
+        for tag in self.tags() {
+            if tags::is_client_tag(tag.key) && is_valid_key(tag.key) {
+                if !kept.is_empty() {
+                    kept.push(b';')
+                }
+                kept.push_all(tag.key);
+                if !tag.raw_value().is_empty() {
+                    kept.push(b'=');
+                    kept.push_all(tag.raw_value())
+                }
+            }
+        }
+        self.tags = if kept.is_empty() { None } else { Some(Arc::new(kept)) }
+    }
+    
+    /// Returns the message without tags
+    ///
+    /// The line is shared with this message.
+    pub fn without_tags(&self) -> RawMessage {
+        RawMessage { tags: None, inner: self.inner.clone() }
     }
     
     /// Returns the message prefix
     /// It might contain non-utf8 chars and thus only bytes are returned.
     pub fn prefix<'a>(&'a self) -> Option<&'a[u8]> {
-        self.prefix.map(|p| p.slice_vec(&self.raw_message))
+        self.inner.prefix.map(|p| p.slice(self.inner.as_slice()))
     }
     
     /// Sets the message prefix
     /// For all purposes of this library &str should be fine since only
     /// ASCII chars will be used for the prefix.
+    ///
+    /// Other clones of the message are not affected.
     pub fn set_prefix(&mut self, prefix: &str) {
-        let bytes = prefix.as_bytes();
-        let offset = match self.prefix {
-            Some(ref mut old_prefix) => {
-                let mut temp = b":".to_vec();
-                temp.push_all(bytes);
-                temp.push_all(self.raw_message.slice_from(old_prefix.end));
-                self.raw_message = temp;
-                let offset = prefix.len() - old_prefix.end + 1;
-                old_prefix.end += offset;
-                offset
-            },
-            None => {
-                let mut temp = b":".to_vec();
-                temp.push_all(bytes);
-                temp.push_all(b" ");
-                temp.push_all(self.raw_message.as_slice());
-                self.raw_message = temp;
-                prefix.len() + 2
-            }
-        };
-        self.command.start += offset;
-        self.command.end += offset;
-        for param in self.params.iter_mut() {
-            param.start += offset;
-            param.end += offset;
-        }
+        self.inner = Arc::new(self.inner.with_prefix(prefix))
     }
 
     /// Returns the message command
     pub fn command<'a>(&'a self) -> Command<'a> {
-        Command::from_bytes(self.command.slice_vec(&self.raw_message))
+        Command::from_bytes(self.inner.command.slice(self.inner.as_slice()))
     }
     
     /// Returns the parameters of the command
     /// *Note* since the IRC protocol does not define any encoding
     /// raw bytes are returned.
-    pub fn params<'a>(&'a self) -> Vec<&'a[u8]> {
-        self.params.iter().map(
-            |slice| slice.slice_vec(&self.raw_message)
-        ).collect()
+    pub fn params<'a>(&'a self) -> Params<'a> {
+        Params {
+            message: &*self.inner,
+            pos: 0,
+            end: self.inner.num_params
+        }
+    }
+    
+    /// Returns the parameter at `index`
+    pub fn param<'a>(&'a self, index: uint) -> Option<&'a [u8]> {
+        self.params().get(index)
     }
 
-    /// Returns the raw message
+    /// Returns the raw message without the tags
     pub fn as_slice<'a>(&'a self) -> &'a[u8] {
-        self.raw_message.as_slice()
+        self.inner.as_slice()
+    }
+    
+    /// Number of bytes `write_to` writes
+    pub fn wire_len(&self) -> uint {
+        let tags = if self.has_tags() { self.tags_len() + 2 } else { 0 };
+        tags + self.as_slice().len() + 2
+    }
+    
+    /// Writes the message including the tags terminated by CR-LF
+    pub fn write_to<W: Writer>(&self, writer: &mut W) -> IoResult<()> {
+        if let Some(ref tags) = self.tags {
+            try!(writer.write(b"@"));
+            try!(writer.write(tags.as_slice()));
+            try!(writer.write(b" "));
+        }
+        try!(writer.write(self.as_slice()));
+        writer.write(b"\r\n")
     }
     
     /// Returns the message as an string for debugging/logging
     pub fn to_string(&self) -> String {
-        String::from_utf8_lossy(self.raw_message.as_slice()).into_string()
+        String::from_utf8_lossy(self.as_slice()).into_string()
+    }
+}
+
+impl Show for RawMessage {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{}", self.to_string())
     }
 }
 
 #[cfg(test)]
 mod tests {
 	use super::{RawMessage};
-	use cmd::{JOIN};
+	use cmd::{JOIN, PRIVMSG, TOPIC};
+	use std::rand::{XorShiftRng, SeedableRng, Rng};
 	/// Test the nickname validation function
 	#[test]
 	fn test_message_parser() {
@@ -225,6 +584,143 @@ mod tests {
         assert_eq!(m.prefix().unwrap(), b"prefix")
         assert!(match m.command() {JOIN => true, _ => false})
         assert_eq!(m.params()[0], b"#channel")
-        assert_eq!(m.as_slice(), b":prefix JOIN :#channel")
+        assert_eq!(m.as_slice(), b":prefix JOIN #channel")
+	}
+	/// Test the parameter accessors
+	#[test]
+	fn test_params() {
+        let m = RawMessage::parse(b"PRIVMSG #a,#b :hello world").unwrap();
+        let mut params = m.params();
+        assert_eq!(params.len(), 2)
+        assert_eq!(params[1], b"hello world")
+        assert_eq!(params.next(), Some(b"#a,#b"))
+        assert_eq!(params.len(), 1)
+        assert_eq!(m.param(1), Some(b"hello world"))
+        assert_eq!(m.param(2), None)
+        assert_eq!(m.params().slice_from(1).collect::<Vec<&[u8]>>(), vec![b"hello world"])
+	}
+	/// Test serialization and the length limit
+	#[test]
+	fn test_write_to() {
+        let m = RawMessage::new(JOIN, &["#channel"], Some("prefix"));
+        let mut buf = Vec::new();
+        m.write_to(&mut buf).unwrap();
+        assert_eq!(buf.as_slice(), b":prefix JOIN #channel\r\n")
+        let long = String::from_char(600, 'a');
+        let m = RawMessage::new(PRIVMSG, &["#channel", long.as_slice()], Some("prefix"));
+        assert_eq!(m.as_slice().len(), 510)
+        assert_eq!(m.params()[1].len(), 510 - ":prefix PRIVMSG #channel ".len())
+        assert!(RawMessage::parse(long.as_bytes()).is_err())
+	}
+	/// Test that truncation does not split UTF-8 sequences
+	#[test]
+	fn test_utf8_truncation() {
+        let long = String::from_char(300, 'ä');
+        let m = RawMessage::new(PRIVMSG, &["#channel", long.as_slice()], Some("prefix"));
+        assert_eq!(m.params()[1].len(), 484)
+        assert!(String::from_utf8(m.params()[1].to_vec()).is_ok())
+        let long = String::from_char(200, '€');
+        let m = RawMessage::new(PRIVMSG, &["#channel", long.as_slice()], Some("prefix"));
+        assert_eq!(m.params()[1].len(), 483)
+        assert!(String::from_utf8(m.params()[1].to_vec()).is_ok())
+	}
+	/// Test the trailing colon and the removal of illegal bytes
+	#[test]
+	fn test_serializer() {
+        let m = RawMessage::new(PRIVMSG, &["#channel", "hello world"], None);
+        assert_eq!(m.as_slice(), b"PRIVMSG #channel :hello world")
+        let m = RawMessage::new(PRIVMSG, &["#channel", ":)"], None);
+        assert_eq!(m.as_slice(), b"PRIVMSG #channel ::)")
+        let m = RawMessage::new(TOPIC, &["#channel", ""], None);
+        assert_eq!(m.as_slice(), b"TOPIC #channel :")
+        let m = RawMessage::new(PRIVMSG, &["#chan\r\nQUIT", "hi\r\nQUIT :bye\0"], Some("nick"));
+        assert_eq!(m.as_slice(), b":nick PRIVMSG #chanQUIT :hiQUIT :bye")
+        let m = RawMessage::new(PRIVMSG, &[":a b", "", "c"], None);
+        assert_eq!(m.as_slice(), b"PRIVMSG ab * c")
 	}
-}
\ No newline at end of file
+	
+	/// Random parameter from an alphabet which includes the special bytes
+	fn random_param<R: Rng>(rng: &mut R) -> Vec<u8> {
+        static ALPHABET: &'static [u8] = b"ab:# \r\n\0\xff";
+        let len = rng.gen_range(0u, 12);
+        Vec::from_fn(len, |_| ALPHABET[rng.gen_range(0, ALPHABET.len())])
+	}
+	/// Serializing and parsing a message returns the same message
+	#[test]
+	fn test_roundtrip() {
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        for _ in range(0u, 1000) {
+            let params = Vec::from_fn(rng.gen_range(0u, 6), |_| random_param(&mut rng));
+            let slices: Vec<&[u8]> = params.iter().map(|p| p.as_slice()).collect();
+            let m = RawMessage::new_raw(PRIVMSG, slices.as_slice(), Some(b"nick!user@host"));
+            assert!(!m.as_slice().iter().any(|&c| c == b'\r' || c == b'\n' || c == 0))
+            let parsed = RawMessage::parse(m.as_slice()).unwrap();
+            assert_eq!(parsed.as_slice(), m.as_slice())
+            assert_eq!(parsed.prefix(), m.prefix())
+            assert_eq!(parsed.command(), m.command())
+            assert_eq!(parsed.params().len(), params.len())
+            assert_eq!(parsed.params().collect::<Vec<&[u8]>>(), m.params().collect::<Vec<&[u8]>>())
+            // Parameters without special bytes are not changed
+            for (param, original) in parsed.params().zip(params.iter()) {
+                if !original.iter().any(|&c| c == b' ' || c == b':' || c == b'\r' 
+                                            || c == b'\n' || c == 0) && original.len() > 0 {
+                    assert_eq!(param, original.as_slice())
+                }
+            }
+            // The trailing parameter only loses the illegal bytes
+            if let Some(last) = params.last() {
+                let cleaned: Vec<u8> = last.iter().map(|&c| c).filter(
+                    |&c| c != b'\r' && c != b'\n' && c != 0
+                ).collect();
+                assert_eq!(parsed.params().last(), Some(cleaned.as_slice()))
+            }
+        }
+	}
+	/// Test that clones share the message
+	#[test]
+	fn test_shared_clone() {
+        let m = RawMessage::new(JOIN, &["#channel"], Some("prefix"));
+        let mut n = m.clone();
+        assert_eq!(m.as_slice().as_ptr(), n.as_slice().as_ptr())
+        n.set_prefix("other");
+        assert_eq!(m.as_slice(), b":prefix JOIN #channel")
+        assert_eq!(n.as_slice(), b":other JOIN #channel")
+	}
+	/// Test parsing and serialization of tags
+	#[test]
+	fn test_tags() {
+        let mut m = RawMessage::parse(b"@+draft/reply=a\\sb;time=1 :nick TAGMSG #chan").unwrap();
+        assert_eq!(m.as_slice(), b":nick TAGMSG #chan")
+        assert_eq!(m.tags().count(), 2)
+        assert_eq!(m.tag(b"+draft/reply").unwrap().value().as_slice(), b"a b")
+        m.set_prefix("other");
+        assert!(m.tag(b"time").is_some())
+        m.retain_client_tags();
+        assert!(m.tag(b"time").is_none())
+        assert!(m.add_tag("msgid", Some(b"x;y")))
+        assert!(!m.add_tag("in valid", None))
+        let mut buf = Vec::new();
+        m.write_to(&mut buf).unwrap();
+        assert_eq!(buf.as_slice(), b"@+draft/reply=a\\sb;msgid=x\\:y :other TAGMSG #chan\r\n")
+        assert_eq!(m.wire_len(), buf.len())
+        let n = m.without_tags();
+        assert!(!n.has_tags())
+        assert!(m.has_tags())
+        // The line is not copied for clients without message-tags
+        assert_eq!(m.as_slice().as_ptr(), n.as_slice().as_ptr())
+	}
+	/// Test the limits of the tag section
+	#[test]
+	fn test_tag_limits() {
+        let mut line = b"@a=".to_vec();
+        line.push_all(Vec::from_elem(8187, b'x').as_slice());
+        line.push_all(b" PING x");
+        assert!(RawMessage::parse(line.as_slice()).is_ok())
+        line.insert(1, b'b');
+        assert!(RawMessage::parse(line.as_slice()).is_err())
+        let mut m = RawMessage::new(PRIVMSG, &["#channel", "hi"], None);
+        let value = Vec::from_elem(4000, b'v');
+        assert!(m.add_tag("a", Some(value.as_slice())))
+        assert!(!m.add_tag("b", Some(value.as_slice())))
+	}
+}
@@ -0,0 +1,137 @@
+//! IRCv3 message tags, see http://ircv3.net/specs/extensions/message-tags
+
+/// Maximum length of the tag data a client may send or the server may add,
+/// the leading '@' and the trailing space are not counted
+pub static MAX_TAG_DATA_LENGTH: uint = 4094;
+/// Maximum length of the tag section including the leading '@' and the
+/// trailing space
+pub static MAX_TAGS_LENGTH: uint = 8191;
+
+/// Checks whether a tag is client-only
+///
+/// Client-only tags are prefixed with '+', they are relayed to other
+/// clients but not interpreted by the server.
+pub fn is_client_tag(key: &[u8]) -> bool {
+    key.starts_with(b"+")
+}
+
+/// Escapes a tag value and appends it to `out`
+pub fn escape(value: &[u8], out: &mut Vec<u8>) {
+    for &c in value.iter() {
+        match c {
+            b';' => out.push_all(b"\\:"),
+            b' ' => out.push_all(b"\\s"),
+            b'\\' => out.push_all(b"\\\\"),
+            b'\r' => out.push_all(b"\\r"),
+            b'\n' => out.push_all(b"\\n"),
+            c => out.push(c)
+        }
+    }
+}
+
+/// Unescapes a tag value
+///
+/// Unknown escape sequences are replaced by the escaped char, a trailing
+/// backslash is dropped.
+pub fn unescape(value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut iter = value.iter();
+    loop {
+        match iter.next() {
+            Some(&b'\\') => match iter.next() {
+                Some(&b':') => out.push(b';'),
+                Some(&b's') => out.push(b' '),
+                Some(&b'r') => out.push(b'\r'),
+                Some(&b'n') => out.push(b'\n'),
+                Some(&c) => out.push(c),
+                None => break
+            },
+            Some(&c) => out.push(c),
+            None => break
+        }
+    }
+    out
+}
+
+/// A single tag
+#[deriving(Show, Clone)]
+pub struct Tag<'a> {
+    /// Key of the tag including the client prefix and the vendor
+    pub key: &'a [u8],
+    raw_value: &'a [u8]
+}
+
+impl<'a> Tag<'a> {
+    /// Returns the unescaped value, tags without a value have an empty value
+    pub fn value(&self) -> Vec<u8> {
+        unescape(self.raw_value)
+    }
+
+    /// Returns the escaped value
+    pub fn raw_value(&self) -> &'a [u8] {
+        self.raw_value
+    }
+}
+
+/// Iterator over the tags of a message
+#[deriving(Clone)]
+pub struct Tags<'a> {
+    rest: &'a [u8]
+}
+
+impl<'a> Tags<'a> {
+    /// Iterates over the tag data of a message (without '@' and the trailing space)
+    pub fn new(data: &'a [u8]) -> Tags<'a> {
+        Tags { rest: data }
+    }
+}
+
+impl<'a> Iterator<Tag<'a>> for Tags<'a> {
+    fn next(&mut self) -> Option<Tag<'a>> {
+        loop {
+            if self.rest.is_empty() {
+                return None
+            }
+            let (tag, rest) = match self.rest.position_elem(&b';') {
+                Some(end) => (self.rest.slice_to(end), self.rest.slice_from(end + 1)),
+                None => (self.rest, b"")
+            };
+            self.rest = rest;
+            if tag.is_empty() {
+                continue
+            }
+            return Some(match tag.position_elem(&b'=') {
+                Some(pos) => Tag { key: tag.slice_to(pos), raw_value: tag.slice_from(pos + 1) },
+                None => Tag { key: tag, raw_value: b"" }
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{escape, unescape, Tags, is_client_tag};
+	/// Test escaping of values
+	#[test]
+	fn test_escape() {
+		let mut out = Vec::new();
+		escape(b"a; b\\c\r\n", &mut out);
+		assert_eq!(out.as_slice(), b"a\\:\\sb\\\\c\\r\\n")
+		assert_eq!(unescape(out.as_slice()).as_slice(), b"a; b\\c\r\n")
+		assert_eq!(unescape(b"\\x\\").as_slice(), b"x")
+	}
+	/// Test the tag iterator
+	#[test]
+	fn test_tags() {
+		let tags: Vec<(Vec<u8>, Vec<u8>)> = Tags::new(b"+example.com/a=1\\s2;;b;c=").map(
+			|tag| (tag.key.to_vec(), tag.value())
+		).collect();
+		assert_eq!(tags, vec![
+			(b"+example.com/a".to_vec(), b"1 2".to_vec()),
+			(b"b".to_vec(), b"".to_vec()),
+			(b"c".to_vec(), b"".to_vec())
+		])
+		assert!(is_client_tag(b"+typing"))
+		assert!(!is_client_tag(b"time"))
+	}
+}
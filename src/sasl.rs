@@ -0,0 +1,516 @@
+//! SASL authentication, see http://ircv3.net/specs/extensions/sasl-3.1
+//!
+//! The client sends its responses base64 encoded in chunks of at most
+//! 400 bytes. A chunk of exactly 400 bytes is followed by another chunk,
+//! `+` denotes an empty chunk.
+
+use std::rand::{task_rng, Rng};
+
+use serialize::base64::{ToBase64, FromBase64, STANDARD};
+
+use account::{AccountBackend, ScramCredentials, sha256, hmac_sha256, constant_time_eq};
+use util::{CaseMapping};
+
+pub use self::ResponseError::*;
+pub use self::Step::*;
+use self::Mechanism::*;
+use self::ScramState::*;
+
+/// Mechanisms supported by the server
+pub static MECHANISMS: &'static [&'static str] = &["PLAIN", "EXTERNAL", "SCRAM-SHA-256"];
+/// Maximum length of a chunk of an AUTHENTICATE message
+pub static CHUNK_LENGTH: uint = 400;
+/// Number of failed authentications after which a connection may not try
+/// again, each attempt may cost thousands of PBKDF2 rounds
+pub static MAX_FAILURES: uint = 3;
+/// Maximum length of an encoded response
+static MAX_RESPONSE_LENGTH: uint = 8192;
+
+/// Error while receiving a response
+#[deriving(Show, PartialEq)]
+pub enum ResponseError {
+    /// The response exceeds `MAX_RESPONSE_LENGTH`
+    TooLong,
+    /// The response is not valid base64
+    Invalid
+}
+
+/// Outcome of a step of the authentication
+pub enum Step {
+    /// The server sends a challenge and waits for the next response
+    Challenge(Vec<u8>),
+    /// The client is authenticated as the account
+    Success(String),
+    /// The authentication failed
+    Failure
+}
+
+#[deriving(Clone)]
+enum Mechanism {
+    Plain,
+    /// Holds the fingerprint of the client certificate
+    External(Option<String>),
+    Scram(ScramState)
+}
+
+/// Progress of a SCRAM-SHA-256 authentication
+#[deriving(Clone)]
+enum ScramState {
+    /// Waiting for the client-first-message, holds the server nonce and the
+    /// secret the salts of unknown accounts are derived from
+    First(String, Vec<u8>),
+    /// Waiting for the client-final-message
+    Final(Exchange),
+    /// The proof was correct, waiting for the empty response to the
+    /// server signature
+    Verified(String)
+}
+
+/// Messages of a SCRAM exchange needed to verify the client proof
+#[deriving(Clone)]
+struct Exchange {
+    /// `None` if the account does not exist or has no SCRAM credentials
+    account: Option<String>,
+    credentials: ScramCredentials,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    /// Client nonce followed by the server nonce
+    nonce: String
+}
+
+/// Authentication in progress
+#[deriving(Clone)]
+pub struct Session {
+    mechanism: Mechanism,
+    /// Case mapping account names are compared with
+    casemapping: CaseMapping,
+    /// Encoded chunks received so far
+    buffer: Vec<u8>
+}
+
+impl Session {
+    /// Starts an authentication, returns `None` if the mechanism is unknown
+    ///
+    /// `fingerprint` is the fingerprint of the TLS client certificate,
+    /// `secret` a random key of the server instance.
+    pub fn new(mechanism: &str, fingerprint: Option<&str>, secret: &[u8],
+               casemapping: CaseMapping) -> Option<Session> {
+        let mechanism = match mechanism {
+            "PLAIN" => Plain,
+            "EXTERNAL" => External(fingerprint.map(|v| v.to_string())),
+            "SCRAM-SHA-256" => Scram(First(random_nonce(), secret.to_vec())),
+            _ => return None
+        };
+        Some(Session {
+            mechanism: mechanism,
+            casemapping: casemapping,
+            buffer: Vec::new()
+        })
+    }
+
+    /// Appends a chunk received from the client
+    ///
+    /// Returns the decoded response once it is complete.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>, ResponseError> {
+        if chunk != b"+" {
+            self.buffer.push_all(chunk)
+        }
+        if self.buffer.len() > MAX_RESPONSE_LENGTH {
+            return Err(TooLong)
+        }
+        if chunk.len() == CHUNK_LENGTH {
+            return Ok(None)
+        }
+        let response = match self.buffer.as_slice().from_base64() {
+            Ok(response) => response,
+            Err(_) => return Err(Invalid)
+        };
+        self.buffer.clear();
+        Ok(Some(response))
+    }
+
+    /// Processes a complete response
+    pub fn step(&mut self, response: &[u8], accounts: &AccountBackend) -> Step {
+        match self.mechanism {
+            Plain => plain(response, accounts),
+            External(ref fingerprint) => external(
+                response, fingerprint, accounts, self.casemapping
+            ),
+            Scram(ref mut state) => scram(state, response, accounts, self.casemapping)
+        }
+    }
+}
+
+/// PLAIN mechanism, the response is `authzid\0authcid\0password`
+///
+/// The authorization identity has to be empty or the same as the
+/// authentication identity.
+fn plain(response: &[u8], accounts: &AccountBackend) -> Step {
+    let fields: Vec<&[u8]> = response.split(|&c| c == 0).collect();
+    if fields.len() != 3 {
+        return Failure
+    }
+    let (authzid, authcid, password) = (fields[0], fields[1], fields[2]);
+    if !authzid.is_empty() && authzid != authcid {
+        return Failure
+    }
+    match String::from_utf8(authcid.to_vec()) {
+        Ok(name) => match accounts.check_password(name.as_slice(), password) {
+            Some(account) => Success(account),
+            None => Failure
+        },
+        Err(_) => Failure
+    }
+}
+
+/// EXTERNAL mechanism, the account is identified by the client certificate
+///
+/// The response is the optional authorization identity, which has to be
+/// the account of the certificate.
+fn external(response: &[u8], fingerprint: &Option<String>, accounts: &AccountBackend,
+            casemapping: CaseMapping) -> Step {
+    let account = match *fingerprint {
+        Some(ref fingerprint) => match accounts.find_fingerprint(fingerprint.as_slice()) {
+            Some(account) => account,
+            None => return Failure
+        },
+        None => return Failure
+    };
+    if response.is_empty() {
+        return Success(account)
+    }
+    match String::from_utf8(response.to_vec()) {
+        Ok(ref authzid) if casemapping.key(authzid.as_slice())
+                           == casemapping.key(account.as_slice()) => Success(account),
+        _ => Failure
+    }
+}
+
+/// SCRAM-SHA-256 mechanism, see RFC 5802 and RFC 7677
+///
+/// Channel binding is not supported.
+fn scram(state: &mut ScramState, response: &[u8], accounts: &AccountBackend,
+         casemapping: CaseMapping) -> Step {
+    let next = match *state {
+        First(ref nonce, ref secret) => scram_first(
+            nonce.as_slice(), secret.as_slice(), response, accounts, casemapping
+        ),
+        Final(ref exchange) => scram_final(exchange, response),
+        Verified(ref account) => return if response.is_empty() {
+            Success(account.clone())
+        } else {
+            Failure
+        }
+    };
+    match next {
+        Some((challenge, next)) => {
+            *state = next;
+            Challenge(challenge.into_bytes())
+        },
+        None => Failure
+    }
+}
+
+/// Handles the client-first-message `gs2-header client-first-bare`
+///
+/// Returns the server-first-message. Unknown accounts get a made up salt
+/// and fail only at the proof, such that account names cannot be probed
+/// (RFC 5802, section 5.1).
+fn scram_first(server_nonce: &str, secret: &[u8], response: &[u8], accounts: &AccountBackend,
+               casemapping: CaseMapping) -> Option<(String, ScramState)> {
+    let message = match String::from_utf8(response.to_vec()) {
+        Ok(message) => message,
+        Err(_) => return None
+    };
+    let message = message.as_slice();
+    let fields: Vec<&str> = message.split(',').collect();
+    if fields.len() < 4 || (fields[0] != "n" && fields[0] != "y") {
+        return None
+    }
+    let gs2_length = fields[0].len() + fields[1].len() + 2;
+    let (name, client_nonce) = match (attribute(fields[2], "n="), attribute(fields[3], "r=")) {
+        (Some(name), Some(nonce)) if !nonce.is_empty() => match decode_saslname(name) {
+            Some(name) => (name, nonce),
+            None => return None
+        },
+        _ => return None
+    };
+    let key = casemapping.key(name.as_slice());
+    if !fields[1].is_empty() {
+        match attribute(fields[1], "a=").and_then(|v| decode_saslname(v)) {
+            Some(ref authzid) if casemapping.key(authzid.as_slice()) == key => (),
+            _ => return None
+        }
+    }
+    let (account, credentials) = match accounts.scram_credentials(name.as_slice()) {
+        Some((account, credentials)) => (Some(account), credentials),
+        None => (None, ScramCredentials::fake(&key, secret))
+    };
+    let nonce = client_nonce.to_string() + server_nonce;
+    let server_first = format!("r={},s={},i={}",
+        nonce, credentials.salt.to_base64(STANDARD), credentials.iterations
+    );
+    Some((server_first.clone(), Final(Exchange {
+        account: account,
+        credentials: credentials,
+        gs2_header: message.slice_to(gs2_length).to_string(),
+        client_first_bare: message.slice_from(gs2_length).to_string(),
+        server_first: server_first,
+        nonce: nonce
+    })))
+}
+
+/// Handles the client-final-message `c=channel-binding,r=nonce,p=proof`
+///
+/// Returns the server-final-message with the server signature.
+fn scram_final(exchange: &Exchange, response: &[u8]) -> Option<(String, ScramState)> {
+    let message = match String::from_utf8(response.to_vec()) {
+        Ok(message) => message,
+        Err(_) => return None
+    };
+    let message = message.as_slice();
+    let fields: Vec<&str> = message.split(',').collect();
+    if fields.len() < 3 {
+        return None
+    }
+    let last = fields[fields.len() - 1];
+    let proof = match attribute(last, "p=").map(|v| v.from_base64()) {
+        Some(Ok(proof)) => proof,
+        _ => return None
+    };
+    match attribute(fields[0], "c=").map(|v| v.from_base64()) {
+        Some(Ok(ref binding)) if binding.as_slice() == exchange.gs2_header.as_bytes() => (),
+        _ => return None
+    }
+    if attribute(fields[1], "r=") != Some(exchange.nonce.as_slice()) {
+        return None
+    }
+    let without_proof = message.slice_to(message.len() - last.len() - 1);
+    let auth_message = format!("{},{},{}",
+        exchange.client_first_bare, exchange.server_first, without_proof
+    );
+    let credentials = &exchange.credentials;
+    let signature = hmac_sha256(credentials.stored_key.as_slice(), auth_message.as_bytes());
+    if proof.len() != signature.len() {
+        return None
+    }
+    let client_key: Vec<u8> = proof.iter().zip(signature.iter()).map(|(&a, &b)| a ^ b).collect();
+    if !constant_time_eq(sha256(client_key.as_slice()).as_slice(),
+                         credentials.stored_key.as_slice()) {
+        return None
+    }
+    let account = match exchange.account {
+        Some(ref account) => account.clone(),
+        None => return None
+    };
+    let server_signature = hmac_sha256(credentials.server_key.as_slice(), auth_message.as_bytes());
+    Some((format!("v={}", server_signature.to_base64(STANDARD)),
+          Verified(account)))
+}
+
+/// Returns the value of a SCRAM attribute if it has the prefix `key`
+fn attribute<'a>(field: &'a str, key: &str) -> Option<&'a str> {
+    if field.starts_with(key) {
+        Some(field.slice_from(key.len()))
+    } else {
+        None
+    }
+}
+
+/// Decodes `=2C` and `=3D` in a SCRAM user name
+fn decode_saslname(name: &str) -> Option<String> {
+    let mut parts = name.split('=');
+    let mut decoded = parts.next().unwrap_or("").to_string();
+    for part in parts {
+        if part.starts_with("2C") {
+            decoded.push(',')
+        } else if part.starts_with("3D") {
+            decoded.push('=')
+        } else {
+            return None
+        }
+        decoded.push_str(part.slice_from(2))
+    }
+    Some(decoded)
+}
+
+fn random_nonce() -> String {
+    let mut nonce = [0u8, ..18];
+    task_rng().fill_bytes(&mut nonce);
+    nonce.to_base64(STANDARD)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Session, Step, Success, Failure, Challenge, TooLong, CHUNK_LENGTH};
+	use super::{Scram, First, decode_saslname};
+	use account::{AccountBackend, ScramCredentials};
+	use serialize::base64::{ToBase64, FromBase64, STANDARD};
+	use util::{Rfc1459};
+	use std::io::{IoResult};
+
+	struct Backend;
+	impl AccountBackend for Backend {
+		fn check_password(&self, name: &str, password: &[u8]) -> Option<String> {
+			if name == "alice" && password == b"secret" { Some("Alice".to_string()) } else { None }
+		}
+		fn scram_credentials(&self, name: &str) -> Option<(String, ScramCredentials)> {
+			if name == "user" {
+				let salt = "W22ZaJ0SNY7soEsUEjb6gQ==".from_base64().unwrap();
+				Some(("User".to_string(), ScramCredentials::derive(b"pencil", salt.as_slice(), 4096)))
+			} else {
+				None
+			}
+		}
+		fn account_name(&self, name: &str) -> Option<String> {
+			if name == "alice" || name == "user" { Some(name.to_string()) } else { None }
+		}
+		fn register(&mut self, _: &str, _: ScramCredentials) -> IoResult<bool> { Ok(false) }
+		fn nick_owner(&self, _: &str) -> Option<String> { None }
+		fn nicks(&self, _: &str) -> Vec<String> { Vec::new() }
+		fn group_nick(&mut self, _: &str, _: &str) -> IoResult<bool> { Ok(false) }
+		fn ungroup_nick(&mut self, _: &str, _: &str) -> IoResult<bool> { Ok(false) }
+		fn find_fingerprint(&self, fingerprint: &str) -> Option<String> {
+			match fingerprint {
+				"ab" => Some("Alice".to_string()),
+				"ef" => Some("Alice{".to_string()),
+				_ => None
+			}
+		}
+		fn fingerprints(&self, _: &str) -> Vec<String> { vec!["ab".to_string()] }
+		fn add_fingerprint(&mut self, _: &str, _: &str) -> IoResult<bool> { Ok(false) }
+		fn remove_fingerprint(&mut self, _: &str, _: &str) -> IoResult<bool> { Ok(false) }
+	}
+
+	fn authenticate(mechanism: &str, fingerprint: Option<&str>, response: &[u8]) -> bool {
+		let mut session = Session::new(mechanism, fingerprint, b"secret", Rfc1459).unwrap();
+		let encoded = response.to_base64(STANDARD);
+		let response = session.feed(encoded.as_bytes()).unwrap().unwrap();
+		match session.step(response.as_slice(), &Backend) {
+			Success(account) => account.as_slice() == "Alice",
+			Failure => false,
+			_ => panic!("{} does not send challenges", mechanism)
+		}
+	}
+
+	/// Test the PLAIN mechanism
+	#[test]
+	fn test_plain() {
+		assert!(authenticate("PLAIN", None, b"\0alice\0secret"))
+		assert!(authenticate("PLAIN", None, b"alice\0alice\0secret"))
+		assert!(!authenticate("PLAIN", None, b"bob\0alice\0secret"))
+		assert!(!authenticate("PLAIN", None, b"\0alice\0wrong"))
+		assert!(!authenticate("PLAIN", None, b"alice\0secret"))
+		assert!(Session::new("LOGIN", None, b"secret", Rfc1459).is_none())
+	}
+
+	/// Test the EXTERNAL mechanism
+	#[test]
+	fn test_external() {
+		assert!(authenticate("EXTERNAL", Some("ab"), b""))
+		assert!(authenticate("EXTERNAL", Some("ab"), b"alice"))
+		assert!(!authenticate("EXTERNAL", Some("ab"), b"bob"))
+		assert!(!authenticate("EXTERNAL", Some("cd"), b""))
+		assert!(!authenticate("EXTERNAL", None, b""))
+		// The authorization identity is compared with the case mapping
+		let mut session = Session::new("EXTERNAL", Some("ef"), b"secret", Rfc1459).unwrap();
+		assert!(match session.step(b"ALICE[", &Backend) { Success(_) => true, _ => false })
+	}
+
+	/// Test the reassembly of chunks
+	#[test]
+	fn test_chunks() {
+		let mut session = Session::new("PLAIN", None, b"secret", Rfc1459).unwrap();
+		let response = Vec::from_elem(300, b'a').to_base64(STANDARD);
+		assert_eq!(response.len(), CHUNK_LENGTH)
+		assert_eq!(session.feed(response.as_bytes()), Ok(None))
+		assert_eq!(session.feed(b"+"), Ok(Some(Vec::from_elem(300, b'a'))))
+		let chunk = Vec::from_elem(CHUNK_LENGTH, b'A');
+		for _ in range(0u, 20) {
+			assert_eq!(session.feed(chunk.as_slice()), Ok(None))
+		}
+		assert_eq!(session.feed(chunk.as_slice()), Err(TooLong))
+	}
+
+	fn step(session: &mut Session, response: &str) -> Step {
+		let encoded = response.as_bytes().to_base64(STANDARD);
+		let response = session.feed(encoded.as_bytes()).unwrap().unwrap();
+		session.step(response.as_slice(), &Backend)
+	}
+
+	fn challenge(step: Step) -> String {
+		match step {
+			Challenge(challenge) => String::from_utf8(challenge).unwrap(),
+			_ => panic!("challenge expected")
+		}
+	}
+
+	fn scram_session() -> Session {
+		Session {
+			mechanism: Scram(First(
+				"%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_string(), b"secret".to_vec()
+			)),
+			casemapping: Rfc1459,
+			buffer: Vec::new()
+		}
+	}
+
+	static CLIENT_FINAL: &'static str = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+
+	/// Test the SCRAM-SHA-256 mechanism with the example of RFC 7677
+	#[test]
+	fn test_scram() {
+		let mut session = scram_session();
+		assert_eq!(challenge(step(&mut session, "n,,n=user,r=rOprNGfwEbeRWgbNEkqO")).as_slice(),
+			"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096")
+		let proof = format!("{},p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=", CLIENT_FINAL);
+		assert_eq!(challenge(step(&mut session, proof.as_slice())).as_slice(),
+			"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+		match step(&mut session, "") {
+			Success(account) => assert_eq!(account.as_slice(), "User"),
+			_ => panic!("authentication should succeed")
+		}
+		// Wrong proof
+		let mut session = scram_session();
+		challenge(step(&mut session, "n,,n=user,r=rOprNGfwEbeRWgbNEkqO"));
+		let proof = format!("{},p=AHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=", CLIENT_FINAL);
+		assert!(match step(&mut session, proof.as_slice()) { Failure => true, _ => false })
+		// Channel binding and mismatching authzid
+		for first in ["p=tls-unique,,n=user,r=abc", "n,a=bob,n=user,r=abc"].iter() {
+			let mut session = scram_session();
+			assert!(match step(&mut session, *first) { Failure => true, _ => false })
+		}
+		assert!(Session::new("SCRAM-SHA-256", None, b"secret", Rfc1459).is_some())
+	}
+
+	/// Test that unknown accounts fail only at the proof
+	#[test]
+	fn test_scram_unknown_account() {
+		let mut session = scram_session();
+		let first = challenge(step(&mut session, "n,,n=bob,r=abc"));
+		let mut session = scram_session();
+		assert_eq!(challenge(step(&mut session, "n,,n=bob,r=abc")), first)
+		let mut session = scram_session();
+		assert!(challenge(step(&mut session, "n,,n=carol,r=abc")) != first)
+		let nonce = first.as_slice().split(',').next().unwrap();
+		let proof = format!("c=biws,{},p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=", nonce);
+		let mut session = scram_session();
+		challenge(step(&mut session, "n,,n=bob,r=abc"));
+		assert!(match step(&mut session, proof.as_slice()) { Failure => true, _ => false })
+		// Names which are equal under the case mapping get the same salt
+		let mut session = scram_session();
+		let first = challenge(step(&mut session, "n,,n=bob{,r=abc"));
+		let mut session = scram_session();
+		assert_eq!(challenge(step(&mut session, "n,a=BOB[,n=bob{,r=abc")), first)
+		let mut session = scram_session();
+		assert_eq!(challenge(step(&mut session, "n,,n=BOB[,r=abc")), first)
+	}
+
+	/// Test the decoding of SCRAM user names
+	#[test]
+	fn test_saslname() {
+		assert_eq!(decode_saslname("a=2Cb=3Dc"), Some("a,b=c".to_string()))
+		assert_eq!(decode_saslname("a=b"), None)
+	}
+}
@@ -1,28 +1,64 @@
 
-use std::io::{TcpListener};
-use std::io::{Listener, Acceptor};
+use std::io::{Timer};
 use std::io::{IoResult};
 use std::io::net;
+use std::io::net::ip::{SocketAddr};
 use std::io;
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration};
+use std::rand::{task_rng, Rng};
+use std::cmp;
+use std::num;
 
-use msg::{MessageHandler};
+use msg::{MessageHandler, RawMessage};
 
 use cmd;
-use con::{Peer, PeerId, Connection};
+use con::{Peer, PeerId, Connection, TlsContext, Reactor, SENDQ_EXCEEDED};
 use channel;
+use channel::util::{CHANNEL_MODES};
+use channel::registry::{Registry};
+use config::{Config, RegistrationPolicy};
+use motd::{Motd};
+use account::{AccountBackend, FileBackend};
+use verification::{Verifications, FileVerifier};
+use util;
+use util::{NameKey};
+
+use time;
+
+/// Version string of the server
+pub static VERSION: &'static str = concat!("chätd-", env!("CARGO_PKG_VERSION"));
+
+/// Maximum number of tokens per RPL_ISUPPORT line
+static ISUPPORT_TOKENS_PER_LINE: uint = 13;
+
+/// Interval in seconds in which timeouts are checked
+static TICK_INTERVAL: i64 = 5;
+
+/// Number of random guest nicks tried before they are counted up
+static GUEST_NICK_ATTEMPTS: uint = 20;
 
 pub use self::Event::*;
 
 pub struct Server {
+    config: Config,
     host: String,
-    ip: String,
-    port: u16, 
+    created: String,
+    motd: Option<Motd>,
+    accounts: Option<Box<AccountBackend + Send>>,
+    verifications: Option<Verifications>,
+    channel_registry: Option<Registry>,
+    /// Random key of this server instance
+    secret: Vec<u8>,
+    /// Time until which peers using a nick owned by another account have
+    /// to log in
+    nick_deadlines: HashMap<PeerId, i64>,
+    addresses: Vec<(SocketAddr, Option<TlsContext>)>,
     tx: Option<Sender<Event>>,
     connections: HashMap<PeerId, Connection>,
     pub users: HashMap<PeerId, Peer>,
-    pub nicks: HashMap<String, PeerId>,
-    pub channels: HashMap<String, channel::Proxy>
+    pub nicks: HashMap<NameKey, PeerId>,
+    pub channels: HashMap<NameKey, channel::Proxy>
 }
 
 /// Enumeration of the events the server can receive
@@ -31,43 +67,88 @@ pub enum Event {
     MessageReceived(PeerId, Box<MessageHandler + Send>),
     /// Connection to a peer established
     Connected(Connection),
-    /// The task of Channel(name) failed
-    ChannelLost(String),
+    /// Connection to a peer was closed or failed
+    Disconnected(PeerId, String),
+    /// Sent periodically to check for timeouts
+    Tick,
 }
 
 /// Convenience function to run the server
-pub fn run_server(host: &str) -> IoResult<Server> {
-    let server = try!(Server::new(host));
+pub fn run_server(config: Config) -> IoResult<Server> {
+    let server = try!(Server::new(config));
     server.serve_forever()
 }
 
 /// Irc server
 impl Server {
     /// Creates a new IRC server instance.
-    pub fn new(host: &str) -> IoResult<Server> {
-        let addresses = try!(net::get_host_addresses(host));
-        debug!("addresses found: {}", addresses)
-        // Listen only on ipv4 for now…
-        let ip = match addresses.iter().filter(
-            |&v| match v { &net::ip::Ipv4Addr(_, _, _, _) => true, _ => false }
-        ).nth(0) {
-            Some(ip) => ip,
-            None => return Err(io::IoError {
+    ///
+    /// Resolves the addresses of all listen blocks, every address a host name
+    /// resolves to gets its own listener.
+    pub fn new(config: Config) -> IoResult<Server> {
+        let mut addresses: Vec<(SocketAddr, Option<TlsContext>)> = Vec::new();
+        for listen in config.listen.iter() {
+            let tls = match listen.tls {
+                Some(ref tls) => Some(try!(TlsContext::new(&tls.certificate, &tls.key))),
+                None => None
+            };
+            let ips = try!(net::get_host_addresses(listen.address.as_slice()));
+            debug!("addresses found for {}: {}", listen.address, ips)
+            for &ip in ips.iter() {
+                let address = SocketAddr { ip: ip, port: listen.port };
+                if !addresses.iter().any(|&(ref v, _)| *v == address) {
+                    addresses.push((address, tls.clone()))
+                }
+            }
+        }
+        if addresses.len() == 0 {
+            return Err(io::IoError {
                 kind: io::OtherIoError,
                 desc: "cannot get host address",
                 detail: None
             })
+        }
+        let verifications = match (&config.accounts.registration,
+                                   &config.accounts.verification_file) {
+            (&RegistrationPolicy::Verify, &Some(ref path)) =>
+                Some(Verifications::new(
+                    box FileVerifier::new(path.clone()), config.server.casemapping
+                )),
+            _ => None
         };
-        Ok(Server {
-            host: host.to_string(),
-            ip: format!("{}", ip),
-            port: 6667,
+        let channel_registry = match config.channels.registry {
+            Some(ref path) => match Registry::load(path, config.server.casemapping) {
+                Ok(registry) => {
+                    info!("loaded registered channels from {}", path.display());
+                    Some(registry)
+                },
+                Err(err) => {
+                    error!("cannot load registered channels from {}: {}", path.display(), err);
+                    return Err(err)
+                }
+            },
+            None => None
+        };
+        let mut server = Server {
+            host: config.server.name.clone(),
+            created: time::now_utc().rfc822().to_string(),
+            motd: None,
+            accounts: None,
+            verifications: verifications,
+            channel_registry: channel_registry,
+            secret: Vec::from_fn(32, |_| task_rng().gen()),
+            nick_deadlines: HashMap::new(),
+            config: config,
+            addresses: addresses,
             tx: None,
             connections: HashMap::new(),
             users: HashMap::new(),
             nicks: HashMap::new(),
             channels: HashMap::new()
-        })
+        };
+        server.reload_motd();
+        server.reload_accounts();
+        Ok(server)
     }
     
     /// Starts the main loop and listens on the specified host and port.
@@ -76,6 +157,8 @@ impl Server {
         for event in try!(self.start_listening()).iter() {
             match event {
                 MessageReceived(client_id, handler) => {
+                    let now = time::get_time().sec;
+                    self.connections.get_mut(&client_id).map(|c| c.touch(now));
                     let client = match self.users.get(&client_id) {
                         Some(client) => Some(client.clone()),
                         None => None
@@ -106,43 +189,212 @@ impl Server {
                     }
                     self.connections.insert(id, con); 
                 },
-                ChannelLost(name) => {
-                    // TODO kick all users from this channel
-                    // can be implemented when channel names are cached on all users
-                    self.channels.remove(&name);
-                }
+                Disconnected(id, reason) => self.disconnect(id, reason.as_slice()),
+                Tick => self.check_timeouts()
             }
         }
         Ok(self)
     }
     
+    /// Pings idle peers and disconnects peers which did not answer in time
+    fn check_timeouts(&mut self) {
+        let now = time::get_time().sec;
+        let interval = self.config.limits.ping_interval as i64;
+        let timeout = self.config.limits.ping_timeout as i64;
+        let mut timed_out = Vec::new();
+        for (id, con) in self.connections.iter_mut() {
+            match con.ping_sent() {
+                Some(sent) => if now - sent >= timeout {
+                    timed_out.push(*id)
+                },
+                None => if now - con.last_activity() >= interval {
+                    con.peer().send_msg(RawMessage::new(
+                        cmd::PING, &[self.host.as_slice()], Some(self.host.as_slice())
+                    ));
+                    con.set_ping_sent(now)
+                }
+            }
+        }
+        for id in timed_out.into_iter() {
+            self.disconnect(id, format!("Ping timeout: {} seconds", timeout).as_slice())
+        }
+        self.check_sendqs();
+        self.enforce_nicks(now);
+        self.flush_channels()
+    }
+    
+    /// Warns the operators about peers whose send queue is filling up
+    fn check_sendqs(&self) {
+        for con in self.connections.values() {
+            let sendq = con.sendq();
+            if sendq.reached_high_water() {
+                let peer = con.peer();
+                let (nick, hostname) = {
+                    let info = peer.info().read();
+                    (info.nick().clone(), info.hostname().clone())
+                };
+                self.notify_opers(format!(
+                    "Client {} ({}) is not reading: SendQ at {}/{} bytes",
+                    nick, hostname, sendq.len(), sendq.max()
+                ).as_slice())
+            }
+        }
+    }
+    
+    /// Renames peers which did not log in to the account owning their nick
+    /// in time
+    fn enforce_nicks(&mut self, now: i64) {
+        let expired: Vec<PeerId> = self.nick_deadlines.iter()
+            .filter(|&(_, &deadline)| deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired.iter() {
+            self.nick_deadlines.remove(id);
+            let peer = match self.users.get(id) {
+                Some(peer) => peer.clone(),
+                None => continue
+            };
+            if self.foreign_nick_owner(&peer).is_some() {
+                match self.guest_nick() {
+                    Some(nick) => {
+                        self.server_notice(&peer, format!(
+                            "You did not log in in time, your nick was changed to {}", nick
+                        ).as_slice());
+                        self.change_nick(&peer, nick)
+                    },
+                    // Tried again on the next tick
+                    None => { self.nick_deadlines.insert(*id, now); }
+                }
+            }
+        }
+    }
+    
+    /// Returns the account which owns the nick of the peer if the peer is
+    /// not logged in to it
+    pub fn foreign_nick_owner(&self, peer: &Peer) -> Option<String> {
+        let accounts = match self.accounts {
+            Some(ref accounts) => accounts,
+            None => return None
+        };
+        let info = peer.info().read();
+        match accounts.nick_owner(info.nick().as_slice()) {
+            Some(owner) => match info.account() {
+                Some(account) if self.key(account) == self.key(owner.as_slice()) => None,
+                _ => Some(owner)
+            },
+            None => None
+        }
+    }
+    
+    /// Gives a peer which uses a nick owned by another account time to log in
+    ///
+    /// The peer is renamed to a guest nick if it does not log in within the
+    /// grace period.
+    pub fn check_nick_owner(&mut self, peer: &Peer) {
+        match self.foreign_nick_owner(peer) {
+            Some(owner) => {
+                let grace = self.config.accounts.nick_grace_period;
+                self.server_notice(peer, format!(
+                    "This nick is owned by the account {}, log in within {} seconds \
+                     or your nick will be changed", owner, grace
+                ).as_slice());
+                self.nick_deadlines.insert(peer.id(), time::get_time().sec + grace as i64);
+            },
+            None => { self.nick_deadlines.remove(&peer.id()); }
+        }
+    }
+    
+    /// Finds an unused nick like Guest12345 which fits the nick length
+    ///
+    /// A few random numbers are tried before the numbers are counted up.
+    /// Nicks owned by an account are skipped. Returns `None` if every guest
+    /// nick is taken.
+    pub fn guest_nick(&self) -> Option<String> {
+        let length = self.config.limits.nick_length;
+        if length < 2 {
+            return None
+        }
+        // Short nick lengths cut the prefix, at least one digit is left
+        let prefix = "Guest".slice_to(cmp::min(5, length - 1));
+        let digits = cmp::min(5, length - prefix.len());
+        let count = num::pow(10u, digits);
+        let guest = |n: uint| {
+            let number = n.to_string();
+            format!("{}{}{}", prefix, String::from_char(digits - number.len(), '0'), number)
+        };
+        let free = |nick: &str| {
+            !self.nicks.contains_key(&self.key(nick))
+            && self.accounts().map_or(true, |a| a.nick_owner(nick).is_none())
+        };
+        let mut rng = task_rng();
+        for _ in range(0, GUEST_NICK_ATTEMPTS) {
+            let nick = guest(rng.gen_range(0, count));
+            if free(nick.as_slice()) {
+                return Some(nick)
+            }
+        }
+        range(0, count).map(|n| guest(n)).find(|nick| free(nick.as_slice()))
+    }
+    
+    /// Changes the nick of a registered user and tells its neighbours
+    pub fn change_nick(&mut self, peer: &Peer, nick: String) {
+        let old_nick = peer.info().read().nick().clone();
+        let (old_key, key) = (self.key(old_nick.as_slice()), self.key(nick.as_slice()));
+        peer.info().write().set_nick(nick.clone());
+        self.nicks.remove(&old_key);
+        self.nicks.insert(key, peer.id());
+        fn rename(channel: &mut channel::Channel, peer: &Peer) {
+            let nick = peer.info().read().nick().clone();
+            channel.rename_member(&peer.id(), nick);
+        }
+        let message = RawMessage::new(cmd::NICK, &[nick.as_slice()], Some(old_nick.as_slice()));
+        self.send_to_neighbours(peer, message, rename)
+    }
+    
+    /// Sends a notice from the server to a peer
+    pub fn server_notice(&self, peer: &Peer, text: &str) {
+        let nick = peer.info().read().nick().clone();
+        peer.send_msg(RawMessage::new(cmd::NOTICE,
+            &[nick.as_slice(), text], Some(self.host.as_slice())
+        ))
+    }
+    
+    /// Binds all listeners, they all feed the same event channel.
+    ///
+    /// Plain text and TLS connections are served by a single event loop.
     fn start_listening(&mut self) -> IoResult<Receiver<(Event)>>  {
-        let listener = TcpListener::bind(format!("{}:{}", self.ip, self.port).as_slice());
-        info!("started listening on {}:{} ({})", self.ip, self.port, self.host);
-        let acceptor = try!(listener.listen());
         let (tx, rx) = channel();
         self.tx = Some(tx.clone());
-        let host = self.host.clone();
+        let timer_tx = tx.clone();
         spawn(proc() {
-            let mut a = acceptor; // https://github.com/rust-lang/rust/issues/11958
-            for maybe_stream in a.incoming() {
-                match maybe_stream {
-                    Err(err) => { error!("{}", err) }
-                    Ok(stream) => {
-                        match Connection::listen(host.clone(), stream, tx.clone()) {
-                            Ok(()) => {},
-                            Err(err) => error!("{}", err)
-                        }
-                    }
+            let mut timer = Timer::new().unwrap();
+            let ticks = timer.periodic(Duration::seconds(TICK_INTERVAL));
+            for _ in ticks.iter() {
+                if timer_tx.send_opt(Tick).is_err() {
+                    break
                 }
             }
         });
+        let reactor = try!(Reactor::new(
+            self.addresses.as_slice(), self.host.clone(), self.config.flood.clone(),
+            self.config.limits.sendq, tx.clone()
+        ));
+        for &(address, ref tls) in self.addresses.iter() {
+            if tls.is_some() {
+                info!("started listening on {} (TLS) ({})", address, self.host);
+            } else {
+                info!("started listening on {} ({})", address, self.host);
+            }
+        }
+        spawn(proc() {
+            reactor.run()
+        });
         Ok(rx)
     }
     
     /// Checks if the nickname is valid
     pub fn valid_nick(&self, nick: &str) -> bool {
-        nick.len() > 1
+        nick.len() > 1 && util::valid_nick_len(nick, self.config.limits.nick_length)
     }
     
     /// Getter for hostname
@@ -150,9 +402,96 @@ impl Server {
         self.host.as_slice()
     }
     
+    /// Getter for the configuration
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+    
+    /// Returns the key of a nick or channel name
+    pub fn key(&self, name: &str) -> NameKey {
+        self.config.server.casemapping.key(name)
+    }
+    
     /// Finds a peer
-    pub fn get_peer(&self, nick: &String) -> Option<&Peer> {
-        self.nicks.get(nick).and_then(|id| self.users.get(id))
+    pub fn get_peer(&self, nick: &str) -> Option<&Peer> {
+        self.nicks.get(&self.key(nick)).and_then(|id| self.users.get(id))
+    }
+    
+    /// Finds the connection of a peer
+    pub fn connection_mut(&mut self, id: PeerId) -> Option<&mut Connection> {
+        self.connections.get_mut(&id)
+    }
+    
+    /// Random key of this server instance
+    ///
+    /// Used to derive values which have to look the same on every request
+    /// without revealing anything, like the salts of unknown accounts.
+    pub fn secret(&self) -> &[u8] {
+        self.secret.as_slice()
+    }
+    
+    /// Account backend, `None` if accounts are not configured
+    pub fn accounts(&self) -> Option<&AccountBackend> {
+        self.accounts.as_ref().map(|v| &**v as &AccountBackend)
+    }
+    
+    /// Mutable account backend, `None` if accounts are not configured
+    pub fn accounts_mut(&mut self) -> Option<&mut AccountBackend> {
+        self.accounts.as_mut().map(|v| &mut **v as &mut AccountBackend)
+    }
+    
+    /// Registrations waiting for verification, `None` if registration
+    /// does not require verification
+    pub fn verifications_mut(&mut self) -> Option<&mut Verifications> {
+        self.verifications.as_mut()
+    }
+    
+    /// Registered channels, `None` if channel registration is not configured
+    pub fn channel_registry(&self) -> Option<&Registry> {
+        self.channel_registry.as_ref()
+    }
+    
+    /// Mutable registered channels, `None` if channel registration is not
+    /// configured
+    pub fn channel_registry_mut(&mut self) -> Option<&mut Registry> {
+        self.channel_registry.as_mut()
+    }
+    
+    /// Stores topic, modes and masks of a channel if it is registered
+    ///
+    /// The registry is written on the next tick.
+    pub fn store_channel(&mut self, name: &str) {
+        let key = self.key(name);
+        match self.channel_registry {
+            Some(ref registry) if registry.get(&key).is_some() => (),
+            _ => return
+        }
+        let (tx, rx) = channel();
+        match self.channels.get(&key) {
+            Some(channel) => channel.send(channel::Handle(proc(channel) {
+                let _ = tx.send_opt(channel.state());
+            })),
+            None => return
+        }
+        let state = match rx.recv_opt() {
+            Ok(state) => state,
+            Err(_) => return
+        };
+        self.channel_registry.as_mut().unwrap().set_state(&key, state)
+    }
+
+    /// Writes the registered channels if a channel state changed
+    fn flush_channels(&mut self) {
+        if let Some(ref mut registry) = self.channel_registry {
+            if let Err(err) = registry.flush() {
+                error!("cannot save the registered channels: {}", err)
+            }
+        }
+    }
+    
+    /// Finds a channel
+    pub fn get_channel(&self, name: &str) -> Option<&channel::Proxy> {
+        self.channels.get(&self.key(name))
     }
     
     /// Getter for hostname
@@ -160,22 +499,331 @@ impl Server {
         self.tx.clone()
     }
     
+    /// Closes the connection and forgets about the peer
     pub fn close_connection(&mut self, client: &Peer) {
-        //self.nicks.remove(client.nick());
         let id = &client.id();
+        let nick = self.key(client.info().read().nick().as_slice());
+        if self.nicks.get(&nick) == Some(id) {
+            self.nicks.remove(&nick);
+        }
         self.connections.get_mut(id).map(|c| c.close());
+        self.nick_deadlines.remove(id);
         self.users.remove(id);
         self.connections.remove(id);
-        error!("TODO: clean up nicknames in Server::close_connection")
+    }
+    
+    /// Disconnects a peer and tells its channels that it quit
+    ///
+    /// The peer is sent an ERROR with the reason before the connection is
+    /// closed. Does nothing if the peer is already disconnected.
+    pub fn disconnect(&mut self, id: PeerId, reason: &str) {
+        let (peer, queued, max) = match self.connections.get(&id) {
+            Some(con) => (con.peer(), con.sendq().len(), con.sendq().max()),
+            None => return
+        };
+        info!("disconnecting {}: {}", id, reason)
+        let (nick, hostname) = {
+            let info = peer.info().read();
+            (info.nick().clone(), info.hostname().clone())
+        };
+        peer.send_msg(RawMessage::new(cmd::ERROR, &[
+            format!("Closing Link: {} ({})", hostname, reason).as_slice()
+        ], None));
+        if reason == SENDQ_EXCEEDED {
+            self.notify_opers(format!(
+                "Client {} ({}) exited: {} ({}/{} bytes)",
+                nick, hostname, reason, queued, max
+            ).as_slice())
+        }
+        let registered = self.users.contains_key(&id);
+        self.close_connection(&peer);
+        if registered {
+            fn leave(channel: &mut channel::Channel, peer: &Peer) {
+                channel.remove_member(&peer.id());
+            }
+            let message = RawMessage::new(cmd::QUIT, &[reason], Some(nick.as_slice()));
+            self.send_to_neighbours(&peer, message, leave)
+        }
+    }
+    
+    /// Sends a server notice to all operators
+    pub fn notify_opers(&self, text: &str) {
+        let text = format!("*** Notice -- {}", text);
+        for user in self.users.values() {
+            let info = user.info().read();
+            if info.is_oper() {
+                user.send_msg(RawMessage::new(cmd::NOTICE, 
+                    &[info.nick().as_slice(), text.as_slice()], Some(self.host.as_slice())
+                ))
+            }
+        }
+    }
+    
+    /// Sends a message exactly once to every peer who shares a channel with `peer`
+    ///
+    /// The peer itself receives the message as well. `update` is executed on
+    /// each channel of the peer before the members are collected.
+    pub fn send_to_neighbours(&self, peer: &Peer, message: RawMessage,
+                              update: fn(&mut channel::Channel, &Peer)) {
+        let (tx, rx) = channel();
+        let channels = peer.info().read().channels().clone();
+        for name in channels.iter() {
+            match self.channels.get(name) {
+                Some(channel) => {
+                    let tx = tx.clone();
+                    let proxy = peer.clone();
+                    channel.send(channel::HandleMut(proc(channel) {
+                        update(channel, &proxy);
+                        let members: Vec<Peer> = channel.members().map(
+                            |m| m.proxy().clone()
+                        ).collect();
+                        let _ = tx.send_opt(members);
+                    }))
+                },
+                None => {}
+            }
+        }
+        drop(tx);
+        let mut seen = HashSet::new();
+        seen.insert(peer.id());
+        peer.send_msg(message.clone());
+        // Ends as soon as all channels replied
+        for members in rx.iter() {
+            for member in members.into_iter() {
+                if seen.insert(member.id()) {
+                    member.send_msg(message.clone())
+                }
+            }
+        }
     }
     
     pub fn add_user(&mut self, client: Peer) {
-        self.nicks.insert(client.info().read().nick().to_string(), client.id());
+        let nick = self.key(client.info().read().nick().as_slice());
+        self.nicks.insert(nick, client.id());
         self.users.insert(client.id(), client);
     }
     
-    /// Sends a welcome message to a newly registered client
+    /// (Re-)loads the message of the day
+    ///
+    /// Returns false if the file could not be loaded, the old message is kept
+    /// in this case.
+    pub fn reload_motd(&mut self) -> bool {
+        let path = match self.config.server.motd {
+            Some(ref path) => path.clone(),
+            None => return false
+        };
+        match Motd::load(&path, self.host.as_slice(), self.config.limits.nick_length) {
+            Ok(motd) => {
+                info!("loaded message of the day from {}", path.display());
+                self.motd = Some(motd);
+                true
+            },
+            Err(err) => {
+                error!("cannot load message of the day from {}: {}", path.display(), err);
+                false
+            }
+        }
+    }
+    
+    /// (Re-)loads the accounts
+    ///
+    /// Returns false if the file could not be loaded, the old accounts are
+    /// kept in this case.
+    pub fn reload_accounts(&mut self) -> bool {
+        let path = match self.config.accounts.file {
+            Some(ref path) => path.clone(),
+            None => return false
+        };
+        match FileBackend::load(&path, self.config.server.casemapping) {
+            Ok(backend) => {
+                info!("loaded accounts from {}", path.display());
+                self.accounts = Some(box backend as Box<AccountBackend + Send>);
+                true
+            },
+            Err(err) => {
+                error!("cannot load accounts from {}: {}", path.display(), err);
+                false
+            }
+        }
+    }
+    
+    /// Sends the message of the day to a client
+    pub fn send_motd(&self, client: &Peer) {
+        let host = self.host.as_slice();
+        match self.motd {
+            Some(ref motd) => {
+                client.send_response(cmd::RPL_MOTDSTART, &[format!(
+                    "- {} Message of the day - ", host
+                ).as_slice()], host);
+                for line in motd.lines().iter() {
+                    client.send_response(cmd::RPL_MOTD, &[format!(
+                        "- {}", line
+                    ).as_slice()], host);
+                }
+                client.send_response(cmd::RPL_ENDOFMOTD, &["End of MOTD command"], host)
+            },
+            None => client.send_response(cmd::ERR_NOMOTD, &["MOTD File is missing"], host)
+        }
+    }
+    
+    /// Returns the RPL_ISUPPORT tokens
+    ///
+    /// The tokens are derived from the modes and validation rules the
+    /// server actually enforces.
+    pub fn isupport(&self) -> Vec<String> {
+        let limits = &self.config.limits;
+        vec![
+            format!("CHANTYPES={}", String::from_chars(util::CHANNEL_PREFIXES)),
+            format!("PREFIX={}", channel::util::isupport_prefix()),
+            format!("CHANMODES={}", channel::util::isupport_chanmodes()),
+            format!("NICKLEN={}", limits.nick_length),
+            format!("CHANNELLEN={}", limits.channel_length),
+            format!("TOPICLEN={}", limits.topic_length),
+            format!("MAXLIST=beI:{}", limits.list_entries),
+            "EXCEPTS=e".to_string(),
+            "INVEX=I".to_string(),
+            format!("CASEMAPPING={}", self.config.server.casemapping.name()),
+            format!("NETWORK={}", self.config.server.network),
+        ]
+    }
+    
+    /// Sends the welcome burst (001 - 005) to a newly registered client
     pub fn send_welcome_msg(&self, client: &Peer) {
-        client.send_response(cmd::RPL_WELCOME, &["Welcome the {} IRC network"], self.host.as_slice())
+        let host = self.host.as_slice();
+        let mask = client.info().read().real_hostmask().as_str().to_string();
+        client.send_response(cmd::RPL_WELCOME, &[format!(
+            "Welcome to the {} IRC network {}", self.config.server.network, mask
+        ).as_slice()], host);
+        client.send_response(cmd::RPL_YOURHOST, &[format!(
+            "Your host is {}, running version {}", host, VERSION
+        ).as_slice()], host);
+        client.send_response(cmd::RPL_CREATED, &[format!(
+            "This server was created {}", self.created
+        ).as_slice()], host);
+        let channel_modes: String = CHANNEL_MODES.iter().map(|m| *m as u8 as char).collect();
+        let param_modes: String = CHANNEL_MODES.iter().filter(|m| m.has_parameter())
+                                               .map(|m| *m as u8 as char).collect();
+        client.send_response(cmd::RPL_MYINFO, &[
+            host, VERSION, "o", channel_modes.as_slice(), param_modes.as_slice()
+        ], host);
+        let tokens = self.isupport();
+        let tokens: Vec<&str> = tokens.iter().map(|v| v.as_slice()).collect();
+        let trailing = "are supported by this server";
+        // The tokens take the place of the trailing parameter and the other
+        // way round, the length is the same
+        let room = client.response_room(&[trailing], host);
+        for line in util::pack_words(tokens.as_slice(), room, ISUPPORT_TOKENS_PER_LINE).iter() {
+            let params = line.clone() + [trailing].as_slice();
+            client.send_response(cmd::RPL_ISUPPORT, params.as_slice(), host)
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use account::{ScramCredentials};
+	use config::{Config};
+	use con::{Connection, Outgoing, detached_connection};
+	use msg::{MessageHandler, RawMessage, get_handler};
+	use std::io::{File, TempDir};
+	use time;
+	use super::{Server};
+
+	/// Creates a server whose account alice owns the nick Ally
+	fn server(dir: &TempDir, nick_length: uint) -> Server {
+		let accounts = dir.path().join("accounts.txt");
+		File::create(&accounts).unwrap();
+		let config = Config::from_str(format!(r#"
+[[listen]]
+address = "127.0.0.1"
+
+[limits]
+nick_length = {}
+
+[accounts]
+file = "{}"
+"#, nick_length, accounts.display()).as_slice());
+		let mut server = Server::new(config.unwrap()).unwrap();
+		{
+			let accounts = server.accounts_mut().unwrap();
+			assert!(accounts.register("alice", ScramCredentials::new(b"secret")).unwrap())
+			assert!(accounts.group_nick("alice", "Ally").unwrap())
+		}
+		server
+	}
+
+	/// Adds a registered user which is not logged in
+	fn user(server: &mut Server, nick: &str) -> (Connection, Outgoing) {
+		let (con, out) = detached_connection(server.host());
+		let peer = con.peer();
+		peer.info().write().set_nick(nick.to_string());
+		server.add_user(peer);
+		(con, out)
+	}
+
+	/// Sends a command as a registered user and returns the replies
+	fn send(server: &mut Server, con: &Connection, out: &Outgoing, line: &str) -> Vec<String> {
+		let handler = get_handler(RawMessage::parse(line.as_bytes()).unwrap()).ok().unwrap();
+		handler.invoke(server, con.peer());
+		out.messages().iter().map(|m| m.to_string()).collect()
+	}
+
+	fn nick(con: &Connection) -> String {
+		let peer = con.peer();
+		let nick = peer.info().read().nick().clone();
+		nick
+	}
+
+	/// Test the grace period for owned nicks and the guest nick afterwards
+	#[test]
+	fn test_enforce_nicks() {
+		let dir = TempDir::new("chatd").unwrap();
+		let mut server = server(&dir, 9);
+		let (con, out) = user(&mut server, "bob");
+		let replies = send(&mut server, &con, &out, "NICK ally");
+		assert!(replies.last().unwrap().as_slice().contains(
+			"This nick is owned by the account alice, log in within 60 seconds"
+		))
+		server.enforce_nicks(time::get_time().sec);
+		assert_eq!(nick(&con).as_slice(), "ally")
+		server.enforce_nicks(time::get_time().sec + 60);
+		let guest = nick(&con);
+		assert!(guest.as_slice().starts_with("Guest"))
+		assert_eq!(guest.len(), 9)
+		let notice = format!("your nick was changed to {}", guest);
+		assert!(out.messages().iter().any(
+			|m| m.to_string().as_slice().contains(notice.as_slice())
+		))
+		assert!(server.get_peer(guest.as_slice()).map_or(false, |p| p.id() == con.peer().id()))
+		assert!(server.get_peer("ally").is_none())
+	}
+
+	/// Test that a peer which logs in during the grace period keeps its nick
+	#[test]
+	fn test_enforce_nicks_late_login() {
+		let dir = TempDir::new("chatd").unwrap();
+		let mut server = server(&dir, 9);
+		let (con, out) = user(&mut server, "bob");
+		send(&mut server, &con, &out, "NICK Alice");
+		// Like a successful SASL authentication after the notice
+		con.peer().info().write().set_account(Some("ALICE".to_string()));
+		server.enforce_nicks(time::get_time().sec + 60);
+		assert_eq!(nick(&con).as_slice(), "Alice")
+		assert!(out.messages().is_empty())
+	}
+
+	/// Test that guest nicks fit short nick lengths and skip taken nicks
+	#[test]
+	fn test_guest_nick() {
+		let dir = TempDir::new("chatd").unwrap();
+		let mut server = server(&dir, 2);
+		// G0 to G9 are the only guest nicks
+		assert!(server.accounts_mut().unwrap().group_nick("alice", "G0").unwrap())
+		let _users: Vec<(Connection, Outgoing)> = range(1u, 9).map(
+			|i| user(&mut server, format!("G{}", i).as_slice())
+		).collect();
+		assert_eq!(server.guest_nick(), Some("G9".to_string()))
+		let _last = user(&mut server, "G9");
+		assert_eq!(server.guest_nick(), None)
+	}
+}
@@ -1,9 +1,21 @@
 use collections::str::{from_utf8};
 
 pub use self::Receiver::*;
+pub use self::CaseMapping::*;
+
+/// Maximum nick name length as of RFC 2812
+pub static RFC_NICK_LENGTH: uint = 9;
+
+/// Upper bound for the configurable nick name length
+pub static MAX_NICK_LENGTH: uint = 32;
 
 /// Checks if the nickname is valid
 pub fn valid_nick(nick: &str) -> bool {
+    valid_nick_len(nick, RFC_NICK_LENGTH)
+}
+
+/// Checks if the nickname is valid and at most `max_len` chars long
+pub fn valid_nick_len(nick: &str, max_len: uint) -> bool {
     // <nick>       ::= <letter> { <letter> | <number> | <special> }
     //<special>    ::= '-' | '[' | ']' | '\' | '`' | '^' | '{' | '}'
     // 
@@ -11,7 +23,7 @@ pub fn valid_nick(nick: &str) -> bool {
     // nickname   =  ( letter / special ) *8( letter / digit / special / "-" )
     // special    =  %x5B-60 / %x7B-7D
     for (i, char) in nick.chars().enumerate() {
-        if i == 9 {
+        if i == max_len {
             return false
         }
         match char {
@@ -26,17 +38,23 @@ pub fn valid_nick(nick: &str) -> bool {
 }
 
 /// Validates the raw nickname and converts it into a string. 
+///
+/// The length is only checked against `MAX_NICK_LENGTH`, the configured
+/// limit has to be checked by the server.
 pub fn verify_nick<'a>(nick: &'a [u8]) -> Option<&'a str> {
     match from_utf8(nick) {
         None => None,
-        Some(nick) => if valid_nick(nick) { Some(nick) } else { None }
+        Some(nick) => if valid_nick_len(nick, MAX_NICK_LENGTH) { Some(nick) } else { None }
     }
 }
 
+/// Characters a channel name can start with
+pub static CHANNEL_PREFIXES: &'static [char] = &['#', '&'];
+
 pub fn valid_channel(channel: &str) -> bool {
     for (i, char) in channel.chars().enumerate() {
         match char {
-            '#' | '&' if i == 0 => {},
+            c if i == 0 && CHANNEL_PREFIXES.contains(&c) => {},
             _ if i == 0 => { return false },
             ' ' | '\x07' | ',' => { return false }
             _ => {}
@@ -70,13 +88,82 @@ pub fn verify_receiver<'a>(recv: &'a [u8]) -> Receiver {
         Some(name) => 
             if valid_channel(name) {
                 ChannelName(name.to_string())
-            } else if valid_nick(name) {
+            } else if valid_nick_len(name, MAX_NICK_LENGTH) {
                 NickName(name.to_string())
             } else { InvalidReceiver(recv.to_vec()) }
     }
 }
 
 
+/// Case mapping which determines which nick and channel names are equal
+///
+/// See http://tools.ietf.org/html/draft-hardy-irc-isupport-00#section-4.1
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum CaseMapping {
+    /// Only A-Z are mapped to a-z
+    Ascii,
+    /// Additionally []\~ are mapped to {}|^
+    Rfc1459,
+    /// Like rfc1459 but ~ and ^ are different
+    StrictRfc1459
+}
+
+impl CaseMapping {
+    /// Parses the name used by the CASEMAPPING token of RPL_ISUPPORT
+    pub fn from_name(name: &str) -> Option<CaseMapping> {
+        match name {
+            "ascii" => Some(Ascii),
+            "rfc1459" => Some(Rfc1459),
+            "strict-rfc1459" => Some(StrictRfc1459),
+            _ => None
+        }
+    }
+
+    /// Name of the mapping as used by the CASEMAPPING token
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Ascii => "ascii",
+            Rfc1459 => "rfc1459",
+            StrictRfc1459 => "strict-rfc1459"
+        }
+    }
+
+    /// Converts a char to lower case
+    pub fn to_lower(&self, c: char) -> char {
+        match (*self, c) {
+            (_, 'A'...'Z') => ((c as u8) + 32) as char,
+            (Rfc1459, '[') | (StrictRfc1459, '[') => '{',
+            (Rfc1459, ']') | (StrictRfc1459, ']') => '}',
+            (Rfc1459, '\\') | (StrictRfc1459, '\\') => '|',
+            (Rfc1459, '~') => '^',
+            _ => c
+        }
+    }
+
+    /// Creates a key which is equal for all names that are equal
+    /// under this mapping.
+    pub fn key(&self, name: &str) -> NameKey {
+        NameKey {
+            folded: name.chars().map(|c| self.to_lower(c)).collect()
+        }
+    }
+}
+
+/// Case folded nick or channel name
+///
+/// Has to be used for all maps which are indexed by names.
+#[deriving(Hash, PartialEq, Eq, Clone, Show)]
+pub struct NameKey {
+    folded: String
+}
+
+impl NameKey {
+    /// Returns the folded name
+    pub fn as_str(&self) -> &str {
+        self.folded.as_slice()
+    }
+}
+
 #[deriving(Hash, PartialEq, Eq, Clone)]
 /// A host mask in the form "*!*@*.*"
 pub struct HostMask {
@@ -141,9 +228,32 @@ impl HostMask {
     }
 }
 
+/// Groups words into lines of at most `room` bytes and `max` words
+///
+/// The words of a line are separated by single spaces. Words longer than
+/// `room` get a line of their own.
+pub fn pack_words<'a>(words: &[&'a str], room: uint, max: uint) -> Vec<Vec<&'a str>> {
+    let mut lines = Vec::new();
+    let mut line = Vec::new();
+    let mut len = 0;
+    for &word in words.iter() {
+        if !line.is_empty() && (len + 1 + word.len() > room || line.len() == max) {
+            lines.push(line);
+            line = Vec::new();
+        }
+        len = if line.is_empty() { word.len() } else { len + 1 + word.len() };
+        line.push(word)
+    }
+    if !line.is_empty() {
+        lines.push(line)
+    }
+    lines
+}
+
 #[cfg(test)]
 mod tests {
-	use super::{valid_nick, valid_channel, HostMask};
+	use super::{valid_nick, valid_channel, pack_words, HostMask};
+	use super::{Ascii, Rfc1459, StrictRfc1459};
 	#[test]
 	/// Test the nickname validation function
 	fn test_nickname_validation() {
@@ -174,4 +284,28 @@ mod tests {
 		assert!(!HostMask::new("*!bar@*.com".to_string()).matches("foo!baz@example.com"))
 	}
     
+    #[test]
+    /// Test the case mappings
+	fn test_casemapping() {
+		assert_eq!(Ascii.key("#Rust"), Ascii.key("#rust"))
+		assert!(Ascii.key("Foo[]") != Ascii.key("foo{}"))
+		assert_eq!(Rfc1459.key("Foo[]\\~"), Rfc1459.key("foo{}|^"))
+		assert_eq!(StrictRfc1459.key("Foo[]\\"), StrictRfc1459.key("foo{}|"))
+		assert!(StrictRfc1459.key("a~") != StrictRfc1459.key("a^"))
+		assert!(Rfc1459.key("Foo") != Rfc1459.key("Foo_"))
+		assert_eq!(Rfc1459.key("ÄÖ").as_str(), "ÄÖ")
+	}
+    
+    #[test]
+    /// Test the splitting of long lists
+	fn test_pack_words() {
+		let words = ["aa", "bbb", "c", "dddddddd", "e"];
+		assert_eq!(pack_words(&words, 8, 10), vec![
+			vec!["aa", "bbb", "c"], vec!["dddddddd"], vec!["e"]
+		])
+		assert_eq!(pack_words(&words, 100, 2), vec![
+			vec!["aa", "bbb"], vec!["c", "dddddddd"], vec!["e"]
+		])
+		assert!(pack_words(&[], 8, 10).is_empty())
+	}
 }
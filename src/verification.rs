@@ -0,0 +1,195 @@
+//! Verification of new accounts
+//!
+//! If the registration policy requires verification, REGISTER sends a code
+//! to the e-mail address of the new account and creates the account only
+//! after the code was given back with VERIFY. Until then the registration
+//! is only kept in memory. Only the connection which sent REGISTER can
+//! verify the account.
+
+use std::collections::{HashMap};
+use std::io::{File, Append, Write, IoResult};
+use std::rand::{task_rng, Rng};
+
+use serialize::hex::{ToHex};
+
+use account::{ScramCredentials, constant_time_eq};
+use con::{PeerId};
+use util::{CaseMapping, NameKey};
+
+/// Seconds a verification code is valid
+static CODE_LIFETIME: i64 = 86400;
+/// Wrong codes after which a registration is dropped
+static MAX_ATTEMPTS: uint = 3;
+/// Random bytes of a verification code
+static CODE_LENGTH: uint = 6;
+
+/// Delivers verification codes to the users
+pub trait Verifier {
+    /// Sends the code for `account` to `email`
+    fn send_code(&mut self, account: &str, email: &str, code: &str) -> IoResult<()>;
+}
+
+/// Appends the codes to a file instead of sending them
+///
+/// Every line holds the account, the e-mail address and the code.
+pub struct FileVerifier {
+    path: Path
+}
+
+impl FileVerifier {
+    pub fn new(path: Path) -> FileVerifier {
+        FileVerifier { path: path }
+    }
+}
+
+impl Verifier for FileVerifier {
+    fn send_code(&mut self, account: &str, email: &str, code: &str) -> IoResult<()> {
+        let mut file = try!(File::open_mode(&self.path, Append, Write));
+        try!(file.write_line(format!("{} {} {}", account, email, code).as_slice()));
+        file.fsync()
+    }
+}
+
+/// Registration waiting for its code
+struct Pending {
+    name: String,
+    credentials: ScramCredentials,
+    code: String,
+    /// Connection which started the registration
+    issuer: PeerId,
+    created: i64,
+    attempts: uint
+}
+
+/// Registrations waiting for verification
+pub struct Verifications {
+    verifier: Box<Verifier + Send>,
+    casemapping: CaseMapping,
+    pending: HashMap<NameKey, Pending>
+}
+
+impl Verifications {
+    pub fn new(verifier: Box<Verifier + Send>, casemapping: CaseMapping) -> Verifications {
+        Verifications {
+            verifier: verifier,
+            casemapping: casemapping,
+            pending: HashMap::new()
+        }
+    }
+
+    /// Sends a new code for `name` to `email`
+    ///
+    /// An earlier registration of the same name is replaced. `issuer` is
+    /// the connection which has to give the code back.
+    pub fn start(&mut self, name: &str, email: &str, credentials: ScramCredentials,
+                 issuer: PeerId, now: i64) -> IoResult<()> {
+        self.pending.retain(|_, pending| now - pending.created < CODE_LIFETIME);
+        let mut code = [0u8, ..CODE_LENGTH];
+        task_rng().fill_bytes(&mut code);
+        let code = code.to_hex();
+        try!(self.verifier.send_code(name, email, code.as_slice()));
+        self.pending.insert(self.casemapping.key(name), Pending {
+            name: name.to_string(),
+            credentials: credentials,
+            code: code,
+            issuer: issuer,
+            created: now,
+            attempts: 0
+        });
+        Ok(())
+    }
+
+    /// Checks the code of a registration
+    ///
+    /// Returns the name and the credentials of the account if the code is
+    /// correct, the account can then be created. Codes from other
+    /// connections than the issuer are rejected without counting as an
+    /// attempt, such that they cannot drop the registration.
+    pub fn verify(&mut self, name: &str, code: &str, issuer: PeerId,
+                  now: i64) -> Option<(String, ScramCredentials)> {
+        let key = self.casemapping.key(name);
+        let (valid, keep) = match self.pending.get_mut(&key) {
+            Some(pending) if pending.issuer != issuer => return None,
+            Some(pending) => {
+                pending.attempts += 1;
+                let alive = now - pending.created < CODE_LIFETIME;
+                (alive && constant_time_eq(pending.code.as_bytes(), code.as_bytes()),
+                 alive && pending.attempts < MAX_ATTEMPTS)
+            },
+            None => return None
+        };
+        if valid {
+            self.pending.remove(&key).map(|pending| (pending.name, pending.credentials))
+        } else {
+            if !keep {
+                self.pending.remove(&key);
+            }
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Verifications, FileVerifier, MAX_ATTEMPTS, CODE_LIFETIME};
+	use account::{ScramCredentials};
+	use con::{PeerId, detached_connection};
+	use std::io::{File, TempDir};
+	use util::{Rfc1459};
+
+	fn peer_id() -> PeerId {
+		let (con, _) = detached_connection("localhost");
+		con.peer().id()
+	}
+
+	fn start(dir: &TempDir, issuer: PeerId, now: i64) -> (Verifications, String) {
+		let path = dir.path().join("codes.txt");
+		let mut verifications = Verifications::new(box FileVerifier::new(path.clone()), Rfc1459);
+		let credentials = ScramCredentials::derive(b"secret", b"salt", 1);
+		verifications.start("Alice", "alice@example.net", credentials, issuer, now).unwrap();
+		let line = File::open(&path).read_to_string().unwrap();
+		let code = line.as_slice().trim().split(' ').last().unwrap().to_string();
+		assert!(line.as_slice().starts_with("Alice alice@example.net "))
+		(verifications, code)
+	}
+
+	/// Test that codes are written to the file and accepted once
+	#[test]
+	fn test_verify() {
+		let dir = TempDir::new("chatd").unwrap();
+		let id = peer_id();
+		let (mut verifications, code) = start(&dir, id, 0);
+		assert!(verifications.verify("alice", "wrong", id, 1).is_none())
+		let (name, _) = verifications.verify("alice", code.as_slice(), id, 1).unwrap();
+		assert_eq!(name.as_slice(), "Alice")
+		assert!(verifications.verify("alice", code.as_slice(), id, 1).is_none())
+	}
+
+	/// Test that codes expire and are dropped after too many attempts
+	#[test]
+	fn test_expiry() {
+		let dir = TempDir::new("chatd").unwrap();
+		let id = peer_id();
+		let (mut verifications, code) = start(&dir, id, 0);
+		assert!(verifications.verify("alice", code.as_slice(), id, CODE_LIFETIME).is_none())
+		let dir = TempDir::new("chatd").unwrap();
+		let (mut verifications, code) = start(&dir, id, 0);
+		for _ in range(0, MAX_ATTEMPTS) {
+			assert!(verifications.verify("alice", "wrong", id, 1).is_none())
+		}
+		assert!(verifications.verify("alice", code.as_slice(), id, 1).is_none())
+	}
+
+	/// Test that other connections can neither verify nor drop a registration
+	#[test]
+	fn test_other_connection() {
+		let dir = TempDir::new("chatd").unwrap();
+		let (id, other) = (peer_id(), peer_id());
+		let (mut verifications, code) = start(&dir, id, 0);
+		for _ in range(0, MAX_ATTEMPTS) {
+			assert!(verifications.verify("alice", "wrong", other, 1).is_none())
+		}
+		assert!(verifications.verify("alice", code.as_slice(), other, 1).is_none())
+		assert!(verifications.verify("alice", code.as_slice(), id, 1).is_some())
+	}
+}